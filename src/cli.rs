@@ -0,0 +1,428 @@
+use chip8rs::frontend::audio::AudioConfig;
+use chip8rs::hardware::cpus::quirks::Quirks;
+use chip8rs::hardware::trace::TraceFilter;
+
+const DEFAULT_HEADLESS_FRAMES: u128 = 600;
+const DEFAULT_REWIND_SECONDS: u32 = 10;
+
+pub const USAGE: &str = "\
+Usage: chip8rs [OPTIONS] <ROM>
+       chip8rs disasm [--start <ADDR>] <ROM>
+       chip8rs asm [--start <ADDR>] [-o <FILE>] <SOURCE>
+       chip8rs trace <FILE>
+
+Options:
+  --speed <IPS>         instructions per second (default 500)
+  --scale <N>           window pixels per Chip-8 pixel (default 15)
+  --palette <FG>,<BG>[,<P2>,<BOTH>]
+                        foreground and background as hex RGB, e.g. 33ff66,001100, then the
+                        XO-CHIP colours of the second plane and of both planes
+  --grid                draw a grid between pixels
+  --vsync               pace frames with the display's refresh instead of the clock, for 60Hz displays
+  --keymap <FILE>       key map config (default keymap.cfg when present)
+  --start <ADDR>        load and start the program at ADDR (default 0x200)
+                        ROMs ending in .asm or .8o (Octo) are compiled first
+  --quirks <PROFILE>[,<QUIRK>=<VALUE>...]
+                        interpreter to behave like: vip, chip48, schip or xochip, with quirks
+                        overridden by name (default the ROM's .quirks file, or vip)
+  --headless            run without a window and print the final screen
+  --debug               start paused in the terminal debugger
+  --gdb <PORT>          wait for gdb on localhost PORT and run under its control
+  --rewind <SECONDS>    history kept for rewinding and stepping back (default 10, 0 turns it off),
+                        not kept in headless mode
+  --frames <N>          frames to run in headless mode (default 600)
+  --wav <FILE>          headless mode: write the beeper to a WAV file
+  --tone <HZ>           beeper frequency, 20 to 20000 (default 440)
+  --waveform <WAVE>     beeper waveform: square, triangle, sawtooth or sine (default square)
+  --volume <0-1>        beeper volume (default 0.25)
+  --trace <FILE>        record every instruction, as text when FILE ends in .txt, binary otherwise
+  --trace-range <ADDR>[-<END>]
+                        only trace instructions in the address range
+  --trace-class <CLASS>,...
+                        only trace flow, skip, load, alu, memory, display, input or timer instructions
+  -h, --help            show this help
+
+Commands:
+  disasm                print a disassembly of the ROM
+  asm                   assemble SOURCE into a ROM, written next to it as .ch8 unless -o is given,
+                        .8o files are compiled as Octo
+  trace                 print a binary trace as text";
+
+pub type Rgb = (u8, u8, u8);
+
+pub enum Command {
+    Run(Options),
+    Disassemble { rom: String, start_address: Option<u16> },
+    Assemble { source: String, output: Option<String>, start_address: Option<u16> },
+    Trace { file: String },
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub rom: String,
+    pub speed: Option<u32>,
+    pub scale: Option<u32>,
+    /// Foreground and background, then optionally the second plane and both planes.
+    pub palette: Option<Vec<Rgb>>,
+    pub grid: bool,
+    pub vsync: bool,
+    pub keymap: Option<String>,
+    pub start_address: Option<u16>,
+    pub quirks: Option<Quirks>,
+    pub headless: bool,
+    pub debug: bool,
+    pub gdb: Option<u16>,
+    pub rewind: u32,
+    pub frames: u128,
+    pub wav: Option<String>,
+    /// Beeper tone, waveform and volume.
+    pub audio: AudioConfig,
+    pub trace: Option<String>,
+    pub trace_filter: TraceFilter,
+}
+
+impl Options {
+    fn new(rom: String) -> Self {
+        Options {
+            rom,
+            speed: None,
+            scale: None,
+            palette: None,
+            grid: false,
+            vsync: false,
+            keymap: None,
+            start_address: None,
+            quirks: None,
+            headless: false,
+            debug: false,
+            gdb: None,
+            rewind: DEFAULT_REWIND_SECONDS,
+            frames: DEFAULT_HEADLESS_FRAMES,
+            wav: None,
+            audio: AudioConfig::new(),
+            trace: None,
+            trace_filter: TraceFilter::new(),
+        }
+    }
+}
+
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter().peekable();
+    if args.peek().map(String::as_str) == Some("disasm") {
+        args.next();
+        return parse_disassemble_args(args);
+    }
+    if args.peek().map(String::as_str) == Some("asm") {
+        args.next();
+        return parse_assemble_args(args);
+    }
+    if args.peek().map(String::as_str) == Some("trace") {
+        args.next();
+        return parse_trace_args(args);
+    }
+    let mut options = Options::new(String::new());
+    let mut rom = None;
+    let mut frames_given = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--speed" => options.speed = Some(parse_positive(&arg, &value()?)?),
+            "--scale" => options.scale = Some(parse_positive(&arg, &value()?)?),
+            "--palette" => options.palette = Some(parse_palette(&value()?)?),
+            "--grid" => options.grid = true,
+            "--vsync" => options.vsync = true,
+            "--keymap" => options.keymap = Some(value()?),
+            "--start" => options.start_address = Some(parse_address(&value()?)?),
+            "--quirks" => options.quirks = Some(value()?.parse()?),
+            "--headless" => options.headless = true,
+            "--debug" => options.debug = true,
+            "--gdb" => options.gdb = Some(parse_port(&value()?)?),
+            "--rewind" => {
+                let text = value()?;
+                options.rewind = parse_number(&text).ok_or_else(|| format!("--rewind expects a number of seconds, got '{}'", text))?;
+            },
+            "--frames" => {
+                options.frames = parse_positive(&arg, &value()?)? as u128;
+                frames_given = true;
+            },
+            "--wav" => options.wav = Some(value()?),
+            "--tone" => options.audio.frequency = parse_decimal(&arg, &value()?, 20.0, 20_000.0)?,
+            "--waveform" => options.audio.waveform = value()?.parse()?,
+            "--volume" => options.audio.volume = parse_decimal(&arg, &value()?, 0.0, 1.0)?,
+            "--trace" => options.trace = Some(value()?),
+            "--trace-range" => {
+                let (start, end) = parse_range(&value()?)?;
+                options.trace_filter.range = Some(start..=end);
+            },
+            "--trace-class" => {
+                options.trace_filter.classes = value()?.split(',').map(str::parse).collect::<Result<_, _>>()?;
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_some() => return Err(format!("unexpected argument {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+
+    // each of these would be dropped without a word
+    if options.debug && options.gdb.is_some() {
+        return Err(String::from("--debug and --gdb cannot be combined"));
+    }
+    if options.headless && (options.debug || options.gdb.is_some()) {
+        return Err(String::from("--headless cannot be combined with --debug or --gdb"));
+    }
+    if options.wav.is_some() && !options.headless {
+        return Err(String::from("--wav needs --headless"));
+    }
+    if frames_given && !options.headless {
+        return Err(String::from("--frames needs --headless"));
+    }
+    options.rom = rom.ok_or("no ROM given")?;
+    Ok(Command::Run(options))
+}
+
+fn parse_disassemble_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut rom = None;
+    let mut start_address = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--start" => {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                start_address = Some(parse_address(&value)?);
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_some() => return Err(format!("unexpected argument {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+    Ok(Command::Disassemble {
+        rom: rom.ok_or("no ROM given")?,
+        start_address,
+    })
+}
+
+fn parse_assemble_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut source = None;
+    let mut output = None;
+    let mut start_address = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" | "--output" => output = Some(value()?),
+            "--start" => start_address = Some(parse_address(&value()?)?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if source.is_some() => return Err(format!("unexpected argument {}", arg)),
+            _ => source = Some(arg),
+        }
+    }
+    Ok(Command::Assemble {
+        source: source.ok_or("no source file given")?,
+        output,
+        start_address,
+    })
+}
+
+fn parse_trace_args(args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut file = None;
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if file.is_some() => return Err(format!("unexpected argument {}", arg)),
+            _ => file = Some(arg),
+        }
+    }
+    Ok(Command::Trace { file: file.ok_or("no trace file given")? })
+}
+
+/// Decimal, or hexadecimal with a 0x prefix.
+pub fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_positive(option: &str, text: &str) -> Result<u32, String> {
+    match parse_number(text) {
+        Some(number) if number > 0 => Ok(number),
+        _ => Err(format!("{} expects a positive number, got '{}'", option, text)),
+    }
+}
+
+/// A number from `min` to `max`, fractions allowed.
+fn parse_decimal(option: &str, text: &str, min: f32, max: f32) -> Result<f32, String> {
+    match text.parse::<f32>() {
+        Ok(number) if (min..=max).contains(&number) => Ok(number),
+        _ => Err(format!("{} expects a number from {} to {}, got '{}'", option, min, max, text)),
+    }
+}
+
+fn parse_port(text: &str) -> Result<u16, String> {
+    match parse_number(text) {
+        Some(port) if (1..=0xFFFF).contains(&port) => Ok(port as u16),
+        _ => Err(format!("'{}' is not a port number", text)),
+    }
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    match parse_number(text) {
+        Some(address) if address <= 0xFFFF => Ok(address as u16),
+        _ => Err(format!("'{}' is not an address", text)),
+    }
+}
+
+/// `ADDR` or `ADDR-END`, both ends included.
+fn parse_range(text: &str) -> Result<(u16, u16), String> {
+    let (start, end) = match text.split_once('-') {
+        Some((start, end)) => (parse_address(start.trim())?, parse_address(end.trim())?),
+        None => (parse_address(text)?, parse_address(text)?),
+    };
+    match start <= end {
+        true => Ok((start, end)),
+        false => Err(format!("range '{}' ends before it starts", text)),
+    }
+}
+
+fn parse_palette(text: &str) -> Result<Vec<Rgb>, String> {
+    let colours: Vec<&str> = text.split(',').collect();
+    if colours.len() != 2 && colours.len() != 4 {
+        return Err(format!("palette '{}' should be two or four colours, e.g. ffffff,000000", text));
+    }
+    colours.into_iter().map(parse_colour).collect()
+}
+
+fn parse_colour(text: &str) -> Result<Rgb, String> {
+    let hex = text.trim().trim_start_matches('#');
+    match (hex.len(), u32::from_str_radix(hex, 16)) {
+        (6, Ok(rgb)) => Ok(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)),
+        _ => Err(format!("'{}' is not a hex RGB colour", text)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8rs::frontend::audio::Waveform;
+    use chip8rs::hardware::trace::OpcodeClass;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse_options(args: &[&str]) -> Options {
+        match parse(args) {
+            Ok(Command::Run(options)) => options,
+            _ => panic!("expected run options for {:?}", args),
+        }
+    }
+
+    #[test]
+    fn test_parse_rom_only() {
+        assert_eq!(parse_options(&["data/PONG"]), Options::new("data/PONG".to_string()));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = parse_options(&[
+            "--speed", "700", "--scale", "10", "--palette", "33ff66,#001100", "--grid", "--vsync",
+            "--start", "0x600", "--quirks", "schip,clip=off", "--headless", "--rewind", "0", "--frames", "120", "--wav", "out.wav",
+            "--trace", "pong.trace", "--trace-range", "0x200-0x2FF", "--trace-class", "flow,display", "data/PONG",
+        ]);
+        assert_eq!(options.speed, Some(700));
+        assert_eq!(options.scale, Some(10));
+        assert_eq!(options.palette, Some(vec![(0x33, 0xFF, 0x66), (0x00, 0x11, 0x00)]));
+        assert!(options.grid);
+        assert!(options.vsync);
+        assert_eq!(options.start_address, Some(0x600));
+        assert_eq!(options.quirks, Some(Quirks { clip_sprites: false, ..Quirks::schip() }));
+        assert!(options.headless);
+        assert_eq!(options.rewind, 0);
+        assert_eq!(options.frames, 120);
+        assert_eq!(options.wav, Some("out.wav".to_string()));
+        assert_eq!(options.trace, Some("pong.trace".to_string()));
+        assert_eq!(options.trace_filter, TraceFilter::new().with_range(0x200..=0x2FF).with_classes(vec![OpcodeClass::Flow, OpcodeClass::Display]));
+    }
+
+    #[test]
+    fn test_parse_debuggers() {
+        assert!(parse_options(&["--debug", "rom"]).debug);
+        assert_eq!(parse_options(&["--gdb", "2159", "rom"]).gdb, Some(2159));
+    }
+
+    #[test]
+    fn test_parse_conflicts() {
+        let error = |args: &[&str]| parse(args).err().unwrap_or_default();
+        assert_eq!(error(&["--debug", "--gdb", "2159", "rom"]), "--debug and --gdb cannot be combined");
+        assert_eq!(error(&["--headless", "--debug", "rom"]), "--headless cannot be combined with --debug or --gdb");
+        assert_eq!(error(&["--wav", "out.wav", "rom"]), "--wav needs --headless");
+        assert_eq!(error(&["--frames", "120", "rom"]), "--frames needs --headless");
+    }
+
+    #[test]
+    fn test_parse_audio() {
+        let options = parse_options(&["--tone", "880", "--waveform", "triangle", "--volume", "0.5", "rom"]);
+        assert_eq!(options.audio, AudioConfig { frequency: 880.0, waveform: Waveform::Triangle, volume: 0.5 });
+        assert!(parse(&["--tone", "10", "rom"]).is_err());
+        assert!(parse(&["--tone", "high", "rom"]).is_err());
+        assert!(parse(&["--waveform", "noise", "rom"]).is_err());
+        assert!(parse(&["--volume", "1.5", "rom"]).is_err());
+        assert!(parse(&["--volume", "-0.1", "rom"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--speed"]).is_err());
+        assert!(parse(&["--speed", "0", "rom"]).is_err());
+        assert!(parse(&["--palette", "fff,000", "rom"]).is_err());
+        assert!(parse(&["--palette", "ffffff,000000,888888", "rom"]).is_err());
+        assert!(parse(&["--bogus", "rom"]).is_err());
+        assert!(parse(&["--gdb", "70000", "rom"]).is_err());
+        assert!(parse(&["--trace-range", "0x300-0x200", "rom"]).is_err());
+        assert!(parse(&["--trace-class", "flow,jumps", "rom"]).is_err());
+        assert!(parse(&["--quirks", "cosmac", "rom"]).is_err());
+        assert!(parse(&["rom", "other"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_disassemble() {
+        match parse(&["disasm", "--start", "0x600", "data/PONG"]) {
+            Ok(Command::Disassemble { rom, start_address }) => {
+                assert_eq!(rom, "data/PONG");
+                assert_eq!(start_address, Some(0x600));
+            },
+            _ => panic!("expected disasm command"),
+        }
+        assert!(parse(&["disasm"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_assemble() {
+        match parse(&["asm", "-o", "pong.ch8", "pong.asm"]) {
+            Ok(Command::Assemble { source, output, start_address }) => {
+                assert_eq!(source, "pong.asm");
+                assert_eq!(output, Some("pong.ch8".to_string()));
+                assert_eq!(start_address, None);
+            },
+            _ => panic!("expected asm command"),
+        }
+        assert!(parse(&["asm", "-o"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_trace() {
+        assert!(matches!(parse(&["trace", "pong.trace"]), Ok(Command::Trace { file }) if file == "pong.trace"));
+        assert!(parse(&["trace"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_help() {
+        assert!(matches!(parse(&["rom", "--help"]), Ok(Command::Help)));
+    }
+}
@@ -0,0 +1,363 @@
+//! Audio
+//! From: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#2.5
+//! The sound timer is active whenever the sound timer register (ST) is non-zero. As long as ST's value is greater than zero,
+//! the Chip-8 buzzer will sound. The sound produced by the Chip-8 interpreter has only one tone.
+//! XO-CHIP programs can load a pattern of 128 one bit samples instead, played in a loop at the rate
+//! their pitch register gives, see `hardware::sound`.
+
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::hardware::sound::{Sound, PATTERN_BITS, PATTERN_SIZE};
+
+pub const SAMPLE_RATE: u32 = 44_100;
+// attack and release ramp, long enough to avoid clicks and short enough to keep beeps crisp
+const ENVELOPE_SECONDS: f32 = 0.005;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
+
+impl Waveform {
+    /// Sample of the waveform at `phase` (0.0 - 1.0), between -1.0 and 1.0.
+    fn sample(&self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Sine => (phase * std::f32::consts::TAU).sin(),
+        }
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "square" => Ok(Waveform::Square),
+            "triangle" => Ok(Waveform::Triangle),
+            "sawtooth" | "saw" => Ok(Waveform::Sawtooth),
+            "sine" => Ok(Waveform::Sine),
+            _ => Err(format!("'{}' is not a waveform, expected square, triangle, sawtooth or sine", text)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioConfig {
+    pub frequency: f32,
+    pub waveform: Waveform,
+    pub volume: f32,
+}
+
+impl AudioConfig {
+    pub fn new() -> Self {
+        AudioConfig {
+            frequency: 440.0,
+            waveform: Waveform::Square,
+            volume: 0.25,
+        }
+    }
+}
+
+/// Beeper
+/// Tone generator shared by all outputs. Starting and stopping fade the tone in and out over a
+/// few milliseconds and the phase carries over between buffers, so there are no clicks. Once the
+/// program has loaded an XO-CHIP pattern it plays that instead of the configured waveform.
+pub struct Beeper {
+    config: AudioConfig,
+    sample_rate: u32,
+    phase: f32,
+    /// Position in the XO-CHIP pattern, in samples of the pattern.
+    position: f32,
+    level: f32,
+    active: bool,
+    sound: Sound,
+}
+
+impl Beeper {
+    pub fn new(config: AudioConfig, sample_rate: u32) -> Self {
+        Beeper {
+            config,
+            sample_rate,
+            phase: 0.0,
+            position: 0.0,
+            level: 0.0,
+            active: false,
+            sound: Sound::new(),
+        }
+    }
+
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+
+    pub fn set_sound(&mut self, sound: Sound) {
+        self.sound = sound;
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        let ramp = 1.0 / (ENVELOPE_SECONDS * self.sample_rate as f32);
+        let step = self.config.frequency / self.sample_rate as f32;
+        let pattern_step = self.sound.playback_rate() / self.sample_rate as f32;
+        for sample in out.iter_mut() {
+            self.level = if self.active {
+                (self.level + ramp).min(1.0)
+            } else {
+                (self.level - ramp).max(0.0)
+            };
+            if self.level == 0.0 {
+                // silent, restart the next beep at the beginning of a cycle
+                self.phase = 0.0;
+                self.position = 0.0;
+                *sample = 0.0;
+                continue;
+            }
+            let tone = match &self.sound.pattern {
+                Some(pattern) => {
+                    let tone = pattern_level(pattern, self.position, pattern_step);
+                    self.position = (self.position + pattern_step) % PATTERN_BITS as f32;
+                    tone
+                },
+                None => {
+                    let tone = self.config.waveform.sample(self.phase);
+                    self.phase = (self.phase + step).fract();
+                    tone
+                },
+            };
+            *sample = tone * self.level * self.config.volume;
+        }
+    }
+}
+
+/// The pattern between `start` and `start + width` samples, each sample counted for the part of it
+/// that falls inside, -1.0 for a clear bit and 1.0 for a set one. Averaging over what one output
+/// sample covers resamples to the host rate without the aliasing of picking the nearest bit.
+fn pattern_level(pattern: &[u8; PATTERN_SIZE], start: f32, width: f32) -> f32 {
+    let end = start + width;
+    let mut position = start;
+    let mut total = 0.0;
+    while position < end {
+        let next = (position.floor() + 1.0).min(end);
+        let level = if Sound::bit(pattern, position as usize) { 1.0 } else { -1.0 };
+        total += (next - position) * level;
+        position = next;
+    }
+    total / width
+}
+
+/// Where the beeper ends up, the frontend calls `frame` once per emulated frame.
+pub trait AudioOutput {
+    fn frame(&mut self, beeping: bool, sound: &Sound);
+}
+
+pub struct NullAudio;
+
+impl AudioOutput for NullAudio {
+    fn frame(&mut self, _beeping: bool, _sound: &Sound) {}
+}
+
+/// Renders the beeper in emulated time, one frame worth of samples per call, for headless runs.
+pub struct WavCapture {
+    beeper: Beeper,
+    samples: Vec<f32>,
+}
+
+impl WavCapture {
+    pub fn new(config: AudioConfig) -> Self {
+        WavCapture {
+            beeper: Beeper::new(config, SAMPLE_RATE),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut file = std::fs::File::create(path)
+            .map_err(|e| format!("Unable to create {}: {}", path, e))?;
+        self.write_wav(&mut file)
+            .map_err(|e| format!("Unable to write {}: {}", path, e))
+    }
+
+    /// 16 bit mono PCM.
+    pub fn write_wav(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let data_size = self.samples.len() as u32 * 2;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(36 + data_size).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // mono
+        out.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        out.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?; // byte rate
+        out.write_all(&2u16.to_le_bytes())?; // block align
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&data_size.to_le_bytes())?;
+        for sample in &self.samples {
+            let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            out.write_all(&pcm.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl AudioOutput for WavCapture {
+    fn frame(&mut self, beeping: bool, sound: &Sound) {
+        let frame_samples = (SAMPLE_RATE / crate::hardware::clock::TIMER_FREQUENCY as u32) as usize;
+        let start = self.samples.len();
+        self.samples.resize(start + frame_samples, 0.0);
+        self.beeper.set_active(beeping);
+        self.beeper.set_sound(*sound);
+        self.beeper.fill(&mut self.samples[start..]);
+    }
+}
+
+#[cfg(feature = "gui")]
+pub use sdl::SdlAudio;
+
+#[cfg(feature = "gui")]
+mod sdl {
+    use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+    use sdl2::AudioSubsystem;
+
+    use super::{AudioConfig, AudioOutput, Beeper, Sound, SAMPLE_RATE};
+
+    struct BeeperCallback {
+        beeper: Beeper,
+    }
+
+    impl AudioCallback for BeeperCallback {
+        type Channel = f32;
+
+        fn callback(&mut self, out: &mut [f32]) {
+            self.beeper.fill(out);
+        }
+    }
+
+    /// Plays the beeper on the default output device, SDL pulls samples from its own thread.
+    pub struct SdlAudio {
+        device: AudioDevice<BeeperCallback>,
+    }
+
+    impl SdlAudio {
+        pub fn new(audio_subsystem: &AudioSubsystem, config: AudioConfig) -> Result<Self, String> {
+            let spec = AudioSpecDesired {
+                freq: Some(SAMPLE_RATE as i32),
+                channels: Some(1),
+                samples: None,
+            };
+            let device = audio_subsystem.open_playback(None, &spec, |spec| BeeperCallback {
+                beeper: Beeper::new(config, spec.freq as u32),
+            })?;
+            device.resume();
+            Ok(SdlAudio { device })
+        }
+    }
+
+    impl AudioOutput for SdlAudio {
+        fn frame(&mut self, beeping: bool, sound: &Sound) {
+            let mut callback = self.device.lock();
+            callback.beeper.set_active(beeping);
+            callback.beeper.set_sound(*sound);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beeper_is_silent_when_inactive() {
+        let mut beeper = Beeper::new(AudioConfig::new(), SAMPLE_RATE);
+        let mut out = [1.0; 64];
+        beeper.fill(&mut out);
+        assert!(out.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn test_beeper_ramps_in_and_out() {
+        let config = AudioConfig::new();
+        let mut beeper = Beeper::new(config, SAMPLE_RATE);
+        let ramp = (ENVELOPE_SECONDS * SAMPLE_RATE as f32) as usize;
+        let mut out = vec![0.0; ramp * 2];
+
+        beeper.set_active(true);
+        beeper.fill(&mut out);
+        // no jump to full volume on the first sample
+        assert!(out[0].abs() < 0.01);
+        assert_eq!(out[ramp + 1].abs(), config.volume);
+
+        beeper.set_active(false);
+        beeper.fill(&mut out);
+        assert!(out[0].abs() <= config.volume);
+        assert!(out[0].abs() > 0.0);
+        assert_eq!(out[ramp + 1], 0.0);
+    }
+
+    #[test]
+    fn test_waveforms_stay_in_range() {
+        for waveform in [Waveform::Square, Waveform::Triangle, Waveform::Sawtooth, Waveform::Sine] {
+            for step in 0..100 {
+                let sample = waveform.sample(step as f32 / 100.0);
+                assert!((-1.0..=1.0).contains(&sample));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_waveform() {
+        assert_eq!("Sine".parse(), Ok(Waveform::Sine));
+        assert_eq!("saw".parse(), Ok(Waveform::Sawtooth));
+        assert!("noise".parse::<Waveform>().is_err());
+    }
+
+    #[test]
+    fn test_beeper_plays_pattern() {
+        let config = AudioConfig::new();
+        let mut beeper = Beeper::new(config, SAMPLE_RATE);
+        let mut sound = Sound::new();
+        // first half set, second half clear
+        sound.pattern = Some([0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0]);
+        beeper.set_sound(sound);
+        beeper.set_active(true);
+        // a whole pattern at 4000 samples a second
+        let mut out = vec![0.0; SAMPLE_RATE as usize * PATTERN_BITS / 4000];
+        beeper.fill(&mut out);
+        let ramp = (ENVELOPE_SECONDS * SAMPLE_RATE as f32) as usize;
+        let close = |sample: f32, expected: f32| (sample - expected).abs() < 1e-4;
+        assert!(out[ramp + 1..out.len() / 2 - 1].iter().all(|&sample| close(sample, config.volume)));
+        assert!(out[out.len() / 2 + 1..].iter().all(|&sample| close(sample, -config.volume)));
+    }
+
+    #[test]
+    fn test_pattern_level_averages() {
+        let mut pattern = [0; PATTERN_SIZE];
+        pattern[0] = 0b1010_0000;
+        assert_eq!(pattern_level(&pattern, 0.0, 0.5), 1.0);
+        assert_eq!(pattern_level(&pattern, 0.5, 1.0), 0.0);
+        assert_eq!(pattern_level(&pattern, 0.0, 4.0), 0.0);
+        // wraps around the end of the pattern
+        assert_eq!(pattern_level(&pattern, 127.0, 2.0), 0.0);
+    }
+
+    #[test]
+    fn test_wav_capture() {
+        let mut capture = WavCapture::new(AudioConfig::new());
+        capture.frame(true, &Sound::new());
+        capture.frame(false, &Sound::new());
+        let mut wav = Vec::new();
+        capture.write_wav(&mut wav).unwrap();
+        // 735 samples per frame at 44.1kHz
+        assert_eq!(wav.len(), 44 + 2 * 735 * 2);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]), 2 * 735 * 2);
+    }
+}
@@ -0,0 +1,564 @@
+//! Debugger
+//! Terminal debugger: registers, stack and timers, a disassembly around the program counter, memory in hex
+//! and the screen, redrawn after every command. The program can be stepped one instruction at a time,
+//! stepped over subroutine calls, run to the cursor or run freely until it hits a breakpoint, and
+//! stepped backwards as far as the computer's rewind buffer reaches.
+//! The keypad is not connected, a program waiting on a key can be stepped past by hand.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Write;
+use std::time::Duration;
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::style::{Print, Stylize};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+
+use crate::hardware::computer::Computer;
+use crate::hardware::cpus::{instruction::Instruction, Cpu};
+use crate::hardware::display::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::hardware::error::EmulationError;
+use crate::hardware::watch::{WatchHit, Watchpoint};
+use crate::tools::disassembler::{disassemble, Disassembly};
+
+const LEFT_WIDTH: usize = DISPLAY_WIDTH + 2;
+const DISASSEMBLY_ROWS: usize = 26;
+const MEMORY_ROWS: u16 = 8;
+const HELP: &str = "s step  S step back  n step over  c continue  p pause  r run to cursor  b breakpoint  w watch  \
+                    W clear watches  up/down cursor  home cursor to PC  pgup/pgdn memory  i memory at I  q quit";
+const WATCH_PROMPT: &str = "watch (read/write/access ADDR[-END], i ADDR[-END] or a condition like V3 == 0x10 && DT == 0)";
+
+/// Why a running program stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u16),
+    /// Reached the address of a run to cursor or step over.
+    Target(u16),
+    Watch(WatchHit),
+    Fault(EmulationError),
+    /// The program ran the SUPER-CHIP EXIT.
+    Exit,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Breakpoint(address) => write!(f, "breakpoint at {:#05X}", address),
+            Stop::Target(address) => write!(f, "stopped at {:#05X}", address),
+            Stop::Watch(hit) => write!(f, "{}", hit),
+            Stop::Fault(error) => write!(f, "halted: {}", error),
+            Stop::Exit => write!(f, "program exited"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Paused,
+    Running,
+    /// Run until the program counter is `address`, with the stack no deeper than `sp` when given.
+    RunningTo { address: u16, sp: Option<u8> },
+}
+
+/// Debugger
+/// Execution control, independent of the terminal. While running the frontend calls `run_frame`
+/// once per frame, so the program keeps its real speed and the interface stays responsive.
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    mode: Mode,
+    // the instruction execution was resumed at, its breakpoint should not stop it straight away
+    resumed_at: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            mode: Mode::Paused,
+            resumed_at: None,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.mode != Mode::Paused
+    }
+
+    pub fn toggle_breakpoint(&mut self, address: u16) {
+        if !self.breakpoints.remove(&address) {
+            self.breakpoints.insert(address);
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    pub fn resume<C: Cpu>(&mut self, computer: &Computer<C>) {
+        self.start(computer, Mode::Running);
+    }
+
+    pub fn run_to<C: Cpu>(&mut self, computer: &Computer<C>, address: u16) {
+        self.start(computer, Mode::RunningTo { address, sp: None });
+    }
+
+    pub fn step<C: Cpu>(&mut self, computer: &mut Computer<C>) -> Option<Stop> {
+        self.mode = Mode::Paused;
+        step_computer(computer)
+    }
+
+    /// Like `step`, but a CALL runs until the subroutine returns.
+    pub fn step_over<C: Cpu>(&mut self, computer: &mut Computer<C>) -> Option<Stop> {
+        let registers = computer.cpu.registers();
+        let (pc, sp) = (registers.pc, registers.sp);
+        match current_instruction(computer) {
+            Some(Instruction::Call(_)) => {
+                self.start(computer, Mode::RunningTo { address: pc.wrapping_add(2), sp: Some(sp) });
+                None
+            },
+            _ => self.step(computer),
+        }
+    }
+
+    /// Run until the end of the current frame, or until something stops the program.
+    pub fn run_frame<C: Cpu>(&mut self, computer: &mut Computer<C>) -> Option<Stop> {
+        let frame = computer.clock.frames;
+        let mut resumed_at = self.resumed_at.take();
+        while self.is_running() && computer.clock.frames == frame {
+            let registers = computer.cpu.registers();
+            let pc = registers.pc;
+            let stop = match self.mode {
+                _ if resumed_at.take() == Some(pc) => None,
+                Mode::RunningTo { address, sp } if address == pc && sp.is_none_or(|sp| registers.sp <= sp) => {
+                    Some(Stop::Target(pc))
+                },
+                _ if self.breakpoints.contains(&pc) => Some(Stop::Breakpoint(pc)),
+                _ => None,
+            };
+            let stop = stop.or_else(|| step_computer(computer));
+            if stop.is_some() {
+                self.mode = Mode::Paused;
+                return stop;
+            }
+        }
+        None
+    }
+
+    fn start<C: Cpu>(&mut self, computer: &Computer<C>, mode: Mode) {
+        self.mode = mode;
+        self.resumed_at = Some(computer.cpu.registers().pc);
+    }
+}
+
+fn step_computer<C: Cpu>(computer: &mut Computer<C>) -> Option<Stop> {
+    match computer.step() {
+        Ok(()) if computer.cpu.has_exited() => Some(Stop::Exit),
+        Ok(()) => computer.take_watch_hits().first().copied().map(Stop::Watch),
+        Err(error) => Some(Stop::Fault(error)),
+    }
+}
+
+fn current_instruction<C: Cpu>(computer: &Computer<C>) -> Option<Instruction> {
+    computer.cpu.instruction_at(&computer.memory, computer.cpu.registers().pc)
+}
+
+/// What is on screen besides the machine state.
+struct View {
+    /// Disassembly cursor, follows the program counter when None.
+    cursor: Option<u16>,
+    /// Start of the memory view, follows I when None.
+    memory_address: Option<u16>,
+    message: String,
+    /// Watchpoint being typed in.
+    input: Option<String>,
+    /// Static disassembly of the cartridge, for label names.
+    disassembly: Disassembly,
+}
+
+impl View {
+    fn cursor<C: Cpu>(&self, computer: &Computer<C>) -> u16 {
+        self.cursor.unwrap_or(computer.cpu.registers().pc)
+    }
+}
+
+/// Restores the terminal however the debugger exits.
+struct Terminal;
+
+impl Terminal {
+    fn enter() -> std::io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(std::io::stdout(), EnterAlternateScreen, Hide)?;
+        Ok(Terminal)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = execute!(std::io::stdout(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+pub fn run<C: Cpu>(computer: &mut Computer<C>) -> Result<(), String> {
+    let _terminal = Terminal::enter().map_err(|e| e.to_string())?;
+    let mut debugger = Debugger::new();
+    let mut view = View {
+        cursor: None,
+        memory_address: None,
+        message: String::from("paused"),
+        input: None,
+        disassembly: disassemble(&computer.cartridge.rom, computer.cartridge.start_address),
+    };
+    let mut out = std::io::stdout();
+
+    loop {
+        draw(&mut out, &render(computer, &debugger, &view)).map_err(|e| e.to_string())?;
+        if debugger.is_running() {
+            while event::poll(Duration::ZERO).map_err(|e| e.to_string())? {
+                if handle_event(event::read().map_err(|e| e.to_string())?, computer, &mut debugger, &mut view) {
+                    return Ok(());
+                }
+            }
+            if let Some(stop) = debugger.run_frame(computer) {
+                view.message = stop.to_string();
+                view.cursor = None;
+            }
+            computer.clock.wait_for_next_frame();
+        } else if handle_event(event::read().map_err(|e| e.to_string())?, computer, &mut debugger, &mut view) {
+            return Ok(());
+        }
+    }
+}
+
+/// Returns true to quit.
+fn handle_event<C: Cpu>(event: Event, computer: &mut Computer<C>, debugger: &mut Debugger, view: &mut View) -> bool {
+    let key = match event {
+        Event::Key(key) if key.kind != KeyEventKind::Release => key.code,
+        _ => return false,
+    };
+    if let Some(input) = view.input.as_mut() {
+        match key {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            },
+            KeyCode::Enter => {
+                view.message = match input.parse::<Watchpoint>() {
+                    Ok(watchpoint) => {
+                        let text = watchpoint.to_string();
+                        format!("watchpoint {}: {}", computer.add_watchpoint(watchpoint), text)
+                    },
+                    Err(e) => e,
+                };
+                view.input = None;
+            },
+            KeyCode::Esc => view.input = None,
+            _ => {},
+        }
+        return false;
+    }
+    let mut stop = None;
+    match key {
+        KeyCode::Char('q') | KeyCode::Esc => return true,
+        KeyCode::Char('s') => stop = Some(debugger.step(computer)),
+        KeyCode::Char('n') => stop = Some(debugger.step_over(computer)),
+        KeyCode::Char('S') => {
+            debugger.pause();
+            view.cursor = None;
+            view.message = match computer.step_back() {
+                true => format!("stepped back to {:#05X}", computer.cpu.registers().pc),
+                false => String::from("no earlier state to step back to"),
+            };
+        },
+        KeyCode::Char('c') => {
+            debugger.resume(computer);
+            view.message = String::from("running");
+        },
+        KeyCode::Char('p') | KeyCode::Char(' ') if debugger.is_running() => {
+            debugger.pause();
+            view.message = String::from("paused");
+            view.cursor = None;
+        },
+        KeyCode::Char('r') => {
+            debugger.run_to(computer, view.cursor(computer));
+            view.message = format!("running to {:#05X}", view.cursor(computer));
+        },
+        KeyCode::Char('b') => debugger.toggle_breakpoint(view.cursor(computer)),
+        KeyCode::Char('w') => view.input = Some(String::new()),
+        KeyCode::Char('W') => {
+            computer.clear_watchpoints();
+            view.message = String::from("watchpoints cleared");
+        },
+        KeyCode::Up => view.cursor = Some(view.cursor(computer).saturating_sub(2)),
+        KeyCode::Down => {
+            let last = (computer.memory.size() - 2) as u16;
+            view.cursor = Some(view.cursor(computer).saturating_add(2).min(last));
+        },
+        KeyCode::Home => view.cursor = None,
+        KeyCode::PageUp | KeyCode::PageDown => {
+            let address = view.memory_address.unwrap_or(computer.cpu.registers().i) & !0xF;
+            view.memory_address = Some(if key == KeyCode::PageUp {
+                address.saturating_sub(MEMORY_ROWS * 16)
+            } else {
+                address.saturating_add(MEMORY_ROWS * 16).min(last_memory_page(computer))
+            });
+        },
+        KeyCode::Char('i') => view.memory_address = None,
+        _ => {},
+    }
+    if let Some(stop) = stop {
+        // a step moves the cursor back to the program counter
+        view.cursor = None;
+        view.message = match (stop, debugger.is_running()) {
+            (Some(stop), _) => stop.to_string(),
+            (None, true) => String::from("running"),
+            (None, false) => String::from("paused"),
+        };
+    }
+    false
+}
+
+fn draw(out: &mut impl Write, lines: &[String]) -> std::io::Result<()> {
+    for (row, line) in lines.iter().enumerate() {
+        queue!(out, MoveTo(0, row as u16), Print(line), Clear(ClearType::UntilNewLine))?;
+    }
+    queue!(out, Clear(ClearType::FromCursorDown))?;
+    out.flush()
+}
+
+fn render<C: Cpu>(computer: &Computer<C>, debugger: &Debugger, view: &View) -> Vec<String> {
+    let mut left = render_registers(computer);
+    left.push(String::new());
+    left.push(String::from("Display"));
+    left.extend(render_display(computer));
+    left.push(String::new());
+    left.push(String::from("Memory"));
+    let memory_address = view.memory_address.unwrap_or(computer.cpu.registers().i) & !0xF;
+    let memory_address = memory_address.min(last_memory_page(computer));
+    left.extend(computer.memory.dump_lines(memory_address, memory_address + (MEMORY_ROWS * 16 - 1)));
+
+    let mut right = render_disassembly(computer, debugger, view);
+    right.resize(DISASSEMBLY_ROWS + 2, String::new());
+    right.push(String::from("Watchpoints"));
+    right.extend(computer.watchpoints().iter().map(|(id, watchpoint)| format!("{:>2}  {}", id, watchpoint)));
+    let mut lines: Vec<String> = (0..left.len().max(right.len()))
+        .map(|row| {
+            let left = left.get(row).map(String::as_str).unwrap_or("");
+            let right = right.get(row).map(String::as_str).unwrap_or("");
+            format!("{:width$}  {}", left, right, width = LEFT_WIDTH)
+        })
+        .collect();
+    lines.push(String::new());
+    match &view.input {
+        Some(input) => lines.push(format!("{}: {}_", WATCH_PROMPT, input)),
+        None => lines.push(view.message.clone()),
+    }
+    lines.push(HELP.to_string());
+    lines
+}
+
+/// Where the memory view starts when it shows the end of memory.
+fn last_memory_page<C: Cpu>(computer: &Computer<C>) -> u16 {
+    (computer.memory.size() - MEMORY_ROWS as usize * 16) as u16
+}
+
+fn render_registers<C: Cpu>(computer: &Computer<C>) -> Vec<String> {
+    let registers = computer.cpu.registers();
+    let mut lines = vec![String::from("Registers")];
+    for (row, values) in registers.v.chunks(4).enumerate() {
+        let cells: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(column, value)| format!("V{:X} {:02X}", row * 4 + column, value))
+            .collect();
+        lines.push(cells.join("   "));
+    }
+    lines.push(format!("I  {:03X}   PC {:03X}   SP {:X}", registers.i, registers.pc, registers.sp));
+    let waiting = if computer.cpu.is_waiting_for_key() { "   waiting for key" } else { "" };
+    lines.push(format!("DT {:02X}    ST {:02X}{}", computer.timers.delay, computer.timers.sound, waiting));
+    let stack: Vec<String> = registers.stack[..registers.sp as usize].iter().map(|address| format!("{:03X}", address)).collect();
+    lines.push(format!("Stack {}", stack.join(" ")));
+    lines
+}
+
+/// Two rows of pixels per line with half blocks. In 128x64 a cell shows a 2x2 block, lit when any of it is.
+fn render_display<C: Cpu>(computer: &Computer<C>) -> Vec<String> {
+    let display = &computer.display;
+    let scale = display.width() / DISPLAY_WIDTH;
+    let lit = |x: usize, y: usize| (0..scale).any(|dy| (0..scale).any(|dx| display.get_pixel(x * scale + dx, y * scale + dy)));
+    (0..DISPLAY_HEIGHT / 2)
+        .map(|row| {
+            (0..DISPLAY_WIDTH)
+                .map(|x| match (lit(x, row * 2), lit(x, row * 2 + 1)) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => '·',
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Decoded straight from memory, so code the program writes shows up as it is.
+fn render_disassembly<C: Cpu>(computer: &Computer<C>, debugger: &Debugger, view: &View) -> Vec<String> {
+    let pc = computer.cpu.registers().pc;
+    let cursor = view.cursor(computer);
+    let first = cursor.saturating_sub(DISASSEMBLY_ROWS as u16 / 2 * 2);
+    let mut lines = vec![String::from("Disassembly")];
+    let mut address = first as usize;
+    while address + 1 < computer.memory.size() && lines.len() <= DISASSEMBLY_ROWS {
+        let (high, low) = match (computer.memory.read(address as u16), computer.memory.read(address as u16 + 1)) {
+            (Ok(high), Ok(low)) => (high, low),
+            _ => break,
+        };
+        let instruction = computer.cpu.instruction_at(&computer.memory, address as u16);
+        let mnemonic = match instruction {
+            Some(instruction) => instruction.format_with(|target| match view.disassembly.label(target) {
+                Some(label) => label.to_string(),
+                None => format!("0x{:03X}", target),
+            }),
+            None => format!("db 0x{:02X}, 0x{:02X}", high, low),
+        };
+        let bytes: String = match instruction {
+            Some(instruction) => instruction.to_bytes().iter().map(|byte| format!("{:02X}", byte)).collect(),
+            None => format!("{:02X}{:02X}", high, low),
+        };
+        let line = format!(
+            "{}{} {:03X}  {}  {:<24}",
+            if debugger.breakpoints.contains(&(address as u16)) { '●' } else { ' ' },
+            if address as u16 == pc { '▶' } else { ' ' },
+            address, bytes, mnemonic,
+        );
+        lines.push(if address as u16 == cursor { line.reverse().to_string() } else { line });
+        address += instruction.map_or(2, |instruction| instruction.size() as usize);
+    }
+    lines
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::cartridge::Cartridge;
+    use crate::hardware::computer::test_support::{powered_on, Skipper};
+    use crate::hardware::cpus::quirks::Quirks;
+
+    // 200: CALL 0x206, 202: ADD V1, 1, 204: JP 0x200, 206: ADD V0, 1, 208: RET
+    const PROGRAM: [u8; 10] = [0x22, 0x06, 0x71, 0x01, 0x12, 0x00, 0x70, 0x01, 0x00, 0xEE];
+
+    fn view() -> View {
+        View {
+            cursor: None,
+            memory_address: None,
+            message: String::new(),
+            input: None,
+            disassembly: disassemble(&PROGRAM, 0x200),
+        }
+    }
+
+    #[test]
+    fn test_step_over_call() {
+        let mut computer = powered_on(PROGRAM.to_vec());
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step_over(&mut computer), None);
+        assert!(debugger.is_running());
+        assert_eq!(debugger.run_frame(&mut computer), Some(Stop::Target(0x202)));
+        assert_eq!(computer.cpu.registers().v[0], 1);
+        assert_eq!(computer.cpu.registers().sp, 0);
+
+        // anything else is a single step
+        assert_eq!(debugger.step_over(&mut computer), None);
+        assert!(!debugger.is_running());
+        assert_eq!(computer.cpu.registers().pc, 0x204);
+    }
+
+    #[test]
+    fn test_breakpoint_stops_and_resumes() {
+        let mut computer = powered_on(PROGRAM.to_vec());
+        let mut debugger = Debugger::new();
+        debugger.toggle_breakpoint(0x208);
+        debugger.resume(&computer);
+        assert_eq!(debugger.run_frame(&mut computer), Some(Stop::Breakpoint(0x208)));
+        assert_eq!(computer.cpu.registers().pc, 0x208);
+
+        // resuming from a breakpoint does not stop on it again until the loop comes back
+        debugger.resume(&computer);
+        assert_eq!(debugger.run_frame(&mut computer), Some(Stop::Breakpoint(0x208)));
+        assert_eq!(computer.cpu.registers().v[1], 1);
+    }
+
+    #[test]
+    fn test_run_to_and_faults() {
+        let mut computer = powered_on(PROGRAM.to_vec());
+        let mut debugger = Debugger::new();
+        debugger.run_to(&computer, 0x204);
+        assert_eq!(debugger.run_frame(&mut computer), Some(Stop::Target(0x204)));
+
+        let mut faulty = powered_on(vec![0x00, 0xEE]);
+        debugger.resume(&faulty);
+        assert_eq!(
+            debugger.run_frame(&mut faulty),
+            Some(Stop::Fault(EmulationError::StackUnderflow { address: 0x200 })),
+        );
+        assert!(!debugger.is_running());
+    }
+
+    #[test]
+    fn test_watchpoint_stops() {
+        let mut computer = powered_on(PROGRAM.to_vec());
+        let mut debugger = Debugger::new();
+        let id = computer.add_watchpoint("V1 == 1".parse().unwrap());
+        debugger.resume(&computer);
+        let stop = debugger.run_frame(&mut computer);
+        assert!(matches!(stop, Some(Stop::Watch(hit)) if hit.id == id && hit.pc == 0x202));
+        assert_eq!(computer.cpu.registers().pc, 0x204);
+    }
+
+    #[test]
+    fn test_render() {
+        let computer = powered_on(PROGRAM.to_vec());
+        let mut debugger = Debugger::new();
+        debugger.toggle_breakpoint(0x206);
+        let view = view();
+        let screen = render(&computer, &debugger, &view).join("\n");
+        assert!(screen.contains("▶ 200  2206  CALL sub_206"));
+        assert!(screen.contains("●  206  7001  ADD V0, 0x01"));
+        assert!(screen.contains("PC 200"));
+        assert!(screen.contains("000: F0 90 90 90 F0"));
+        assert!(screen.contains("Watchpoints"));
+    }
+
+    #[test]
+    fn test_page_down_stops_at_the_end_of_memory() {
+        let mut computer = Computer::new(Cartridge::new(PROGRAM.to_vec()).with_quirks(Quirks::xochip()));
+        computer.power_on().unwrap();
+        let mut debugger = Debugger::new();
+        let mut view = view();
+        let page_down = Event::Key(KeyCode::PageDown.into());
+        for _ in 0..600 {
+            handle_event(page_down.clone(), &mut computer, &mut debugger, &mut view);
+        }
+        assert_eq!(view.memory_address, Some(0xFF80));
+        assert!(render(&computer, &debugger, &view).join("\n").contains("FFF0: "));
+    }
+
+    #[test]
+    fn test_other_cpu() {
+        let mut computer = Computer::with_cpu(Cartridge::new(PROGRAM.to_vec()), Skipper::new());
+        computer.power_on().unwrap();
+        let mut debugger = Debugger::new();
+        let view = view();
+        // the core decodes nothing, so the CALL is shown and stepped as plain bytes
+        let screen = render(&computer, &debugger, &view).join("\n");
+        assert!(screen.contains("▶ 200  2206  db 0x22, 0x06"));
+        assert_eq!(debugger.step_over(&mut computer), None);
+        assert_eq!((computer.cpu.registers().pc, computer.cpu.steps), (0x202, 1));
+
+        debugger.toggle_breakpoint(0x206);
+        debugger.resume(&computer);
+        assert_eq!(debugger.run_frame(&mut computer), Some(Stop::Breakpoint(0x206)));
+        assert_eq!(computer.cpu.steps, 3);
+    }
+}
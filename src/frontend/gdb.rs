@@ -0,0 +1,670 @@
+//! GDB remote
+//! Server for the GDB remote serial protocol on a local TCP socket, so ROMs can be debugged with gdb or
+//! any other client that speaks it. It serves the registers (described by a target description, gdb has
+//! no CHIP-8 architecture of its own), memory reads and writes, single steps, continue with Ctrl-C,
+//! software breakpoints and watchpoints, and reverse steps and continues through the computer's rewind
+//! buffer. Execution control is the terminal debugger's `Debugger`, so a continued program runs at its
+//! real speed.
+//! The transport is TCP, packets that fail their checksum are refused but replies are never resent.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+use crate::frontend::debugger::{Debugger, Stop};
+use crate::hardware::computer::Computer;
+use crate::hardware::cpus::Cpu;
+use crate::hardware::error::EmulationError;
+use crate::hardware::watch::{Trigger, Watchpoint, WatchpointId};
+
+const PACKET_SIZE: usize = 0x1000;
+const INTERRUPT: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+// stop reply for a reverse execution that ran out of history
+const HISTORY_START: &str = "T05replaylog:begin;";
+
+/// Name and size in bytes of every register, in the order of the target description.
+/// Values are little endian on the wire.
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 1), ("v1", 1), ("v2", 1), ("v3", 1), ("v4", 1), ("v5", 1), ("v6", 1), ("v7", 1),
+    ("v8", 1), ("v9", 1), ("va", 1), ("vb", 1), ("vc", 1), ("vd", 1), ("ve", 1), ("vf", 1),
+    ("i", 2), ("pc", 2), ("sp", 1), ("dt", 1), ("st", 1),
+];
+
+/// What the server does after a packet.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    /// Run until something stops the program, then reply with why.
+    Continue,
+    /// End the session, after sending the reply if there is one.
+    Quit(Option<String>),
+}
+
+/// Answers packets for one session, independent of the socket.
+struct GdbStub<'a, C: Cpu> {
+    computer: &'a mut Computer<C>,
+    debugger: Debugger,
+    /// Watchpoints set by Z2 (write), Z3 (read) and Z4 (access), by type, address and length.
+    watchpoints: BTreeMap<(u8, u16, u16), WatchpointId>,
+}
+
+impl<'a, C: Cpu> GdbStub<'a, C> {
+    fn new(computer: &'a mut Computer<C>) -> Self {
+        GdbStub {
+            computer,
+            debugger: Debugger::new(),
+            watchpoints: BTreeMap::new(),
+        }
+    }
+
+    fn handle(&mut self, packet: &str) -> Action {
+        // the command is one character, which a misbehaving client can make a multi-byte one
+        let command_length = packet.chars().next().map_or(0, char::len_utf8);
+        let reply = match packet.split_at(command_length) {
+            (command, _) if !command.is_ascii() => String::from("E01"),
+            ("?", _) => stop_reply_signal(SIGTRAP),
+            ("g", "") => hex(&self.read_registers()),
+            ("G", data) => self.write_registers(data),
+            ("p", number) => self.read_register(number),
+            ("P", assignment) => self.write_register(assignment),
+            ("m", arguments) => self.read_memory(arguments),
+            ("M", arguments) => self.write_memory(arguments),
+            ("s", address) => {
+                self.jump(address);
+                let stop = self.debugger.step(self.computer);
+                self.stop_reply(stop)
+            },
+            ("c", address) => {
+                self.jump(address);
+                return Action::Continue;
+            },
+            ("b", "s") => self.step_back(),
+            ("b", "c") => self.continue_back(),
+            ("Z", arguments) => self.set_breakpoint(arguments, true),
+            ("z", arguments) => self.set_breakpoint(arguments, false),
+            ("H", _) | ("T", _) => String::from("OK"),
+            ("k", _) => return Action::Quit(None),
+            ("D", _) => return Action::Quit(Some(String::from("OK"))),
+            _ => self.query(packet),
+        };
+        Action::Reply(reply)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;ReverseStep+;ReverseContinue+", PACKET_SIZE);
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_pair(range) {
+                Some((offset, length)) => transfer(target_description().as_bytes(), offset, length),
+                None => String::from("E01"),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" | "qSymbol::" => String::from("OK"),
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            // everything else, vCont? and vMustReplyEmpty included, is unsupported
+            _ => String::new(),
+        }
+    }
+
+    /// Continue, checking `interrupted` between frames for a Ctrl-C from the client.
+    fn resume(&mut self, mut interrupted: impl FnMut() -> io::Result<bool>) -> io::Result<String> {
+        self.debugger.resume(self.computer);
+        loop {
+            if let Some(stop) = self.debugger.run_frame(self.computer) {
+                return Ok(self.stop_reply(Some(stop)));
+            }
+            if interrupted()? {
+                self.debugger.pause();
+                return Ok(stop_reply_signal(SIGINT));
+            }
+            self.computer.clock.wait_for_next_frame();
+        }
+    }
+
+    fn step_back(&mut self) -> String {
+        match self.computer.step_back() {
+            true => stop_reply_signal(SIGTRAP),
+            false => String::from(HISTORY_START),
+        }
+    }
+
+    /// Step back until a breakpoint or the start of the history.
+    fn continue_back(&mut self) -> String {
+        while self.computer.step_back() {
+            if self.debugger.breakpoints.contains(&self.computer.cpu.registers().pc) {
+                return format!("T{:02x}swbreak:;", SIGTRAP);
+            }
+        }
+        String::from(HISTORY_START)
+    }
+
+    fn stop_reply(&self, stop: Option<Stop>) -> String {
+        match stop {
+            None | Some(Stop::Target(_)) => stop_reply_signal(SIGTRAP),
+            Some(Stop::Breakpoint(_)) => format!("T{:02x}swbreak:;", SIGTRAP),
+            Some(Stop::Watch(hit)) => {
+                let kind = self.watchpoints.iter().find(|(_, &id)| id == hit.id).map(|(&(kind, _, _), _)| kind);
+                match (kind, hit.trigger) {
+                    (Some(kind), Trigger::Access(access)) => {
+                        let name = match kind {
+                            2 => "watch",
+                            3 => "rwatch",
+                            _ => "awatch",
+                        };
+                        format!("T{:02x}{}:{:x};", SIGTRAP, name, access.address)
+                    },
+                    _ => stop_reply_signal(SIGTRAP),
+                }
+            },
+            Some(Stop::Fault(EmulationError::UnknownOpcode { .. })) => stop_reply_signal(SIGILL),
+            Some(Stop::Fault(_)) => stop_reply_signal(SIGSEGV),
+            // the process exited with status 0
+            Some(Stop::Exit) => String::from("W00"),
+        }
+    }
+
+    /// `s` and `c` may give the address to resume at.
+    fn jump(&mut self, address: &str) {
+        if let Ok(address) = u16::from_str_radix(address, 16) {
+            self.computer.cpu.set_pc(address);
+        }
+    }
+
+    fn read_registers(&self) -> Vec<u8> {
+        (0..REGISTERS.len()).flat_map(|number| self.register(number)).collect()
+    }
+
+    fn register(&self, number: usize) -> Vec<u8> {
+        let registers = self.computer.cpu.registers();
+        let value = match number {
+            0..=15 => registers.v[number] as u16,
+            16 => registers.i,
+            17 => registers.pc,
+            18 => registers.sp as u16,
+            19 => self.computer.timers.delay as u16,
+            _ => self.computer.timers.sound as u16,
+        };
+        value.to_le_bytes()[..REGISTERS[number].1].to_vec()
+    }
+
+    fn set_register(&mut self, number: usize, bytes: &[u8]) {
+        let value = match bytes {
+            [low, high] => u16::from_le_bytes([*low, *high]),
+            [low] => *low as u16,
+            _ => return,
+        };
+        let registers = self.computer.cpu.registers_mut();
+        match number {
+            0..=15 => registers.v[number] = value as u8,
+            16 => registers.i = value,
+            17 => registers.pc = value,
+            // the stack only has room for 16 return addresses
+            18 => registers.sp = (value as u8).min(registers.stack.len() as u8),
+            19 => self.computer.timers.delay = value as u8,
+            _ => self.computer.timers.sound = value as u8,
+        }
+    }
+
+    fn read_register(&self, number: &str) -> String {
+        match usize::from_str_radix(number, 16) {
+            Ok(number) if number < REGISTERS.len() => hex(&self.register(number)),
+            _ => String::from("E01"),
+        }
+    }
+
+    fn write_register(&mut self, assignment: &str) -> String {
+        let parsed = assignment.split_once('=').and_then(|(number, value)| {
+            let number = usize::from_str_radix(number, 16).ok().filter(|&number| number < REGISTERS.len())?;
+            Some((number, unhex(value).filter(|bytes| bytes.len() == REGISTERS[number].1)?))
+        });
+        match parsed {
+            Some((number, bytes)) => {
+                self.set_register(number, &bytes);
+                String::from("OK")
+            },
+            None => String::from("E01"),
+        }
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let bytes = match unhex(data) {
+            Some(bytes) if bytes.len() == REGISTERS.iter().map(|(_, size)| size).sum() => bytes,
+            _ => return String::from("E01"),
+        };
+        let mut offset = 0;
+        for (number, (_, size)) in REGISTERS.iter().enumerate() {
+            self.set_register(number, &bytes[offset..offset + size]);
+            offset += size;
+        }
+        String::from("OK")
+    }
+
+    /// Reads stop at the end of memory, only a read that gets nothing at all is an error.
+    fn read_memory(&self, arguments: &str) -> String {
+        let (address, length) = match parse_pair(arguments) {
+            Some(pair) => pair,
+            None => return String::from("E01"),
+        };
+        let bytes: Vec<u8> = (0..length.min(PACKET_SIZE / 2))
+            .map_while(|offset| address.checked_add(offset).and_then(|address| u16::try_from(address).ok()))
+            .map_while(|address| self.computer.memory.read(address).ok())
+            .collect();
+        match bytes.is_empty() && length > 0 {
+            true => String::from("E01"),
+            false => hex(&bytes),
+        }
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let parsed = arguments.split_once(':').and_then(|(range, data)| {
+            let (address, length) = parse_pair(range)?;
+            let bytes = unhex(data).filter(|bytes| bytes.len() == length)?;
+            Some((u16::try_from(address).ok()?, bytes))
+        });
+        let (address, bytes) = match parsed {
+            Some(parsed) => parsed,
+            None => return String::from("E01"),
+        };
+        for (offset, value) in bytes.into_iter().enumerate() {
+            if self.computer.memory.write(address.wrapping_add(offset as u16), value).is_err() {
+                return String::from("E01");
+            }
+        }
+        String::from("OK")
+    }
+
+    /// Z and z packets: `TYPE,ADDR,KIND`, where KIND is the length for watchpoints.
+    fn set_breakpoint(&mut self, arguments: &str, insert: bool) -> String {
+        let mut fields = arguments.splitn(3, ',');
+        let kind = fields.next().and_then(|kind| kind.parse::<u8>().ok());
+        let address = fields.next().and_then(|address| u16::from_str_radix(address, 16).ok());
+        let length = fields.next().and_then(|length| u16::from_str_radix(length, 16).ok());
+        let (kind, address, length) = match (kind, address, length) {
+            (Some(kind), Some(address), Some(length)) => (kind, address, length),
+            _ => return String::from("E01"),
+        };
+        match (kind, insert) {
+            (0 | 1, true) => {
+                self.debugger.breakpoints.insert(address);
+            },
+            (0 | 1, false) => {
+                self.debugger.breakpoints.remove(&address);
+            },
+            (2..=4, true) => {
+                let end = address.saturating_add(length.max(1) - 1);
+                let watchpoint = Watchpoint::Memory { range: address..=end, read: kind != 2, write: kind != 3 };
+                let id = self.computer.add_watchpoint(watchpoint);
+                if let Some(replaced) = self.watchpoints.insert((kind, address, length), id) {
+                    self.computer.remove_watchpoint(replaced);
+                }
+            },
+            (2..=4, false) => {
+                if let Some(id) = self.watchpoints.remove(&(kind, address, length)) {
+                    self.computer.remove_watchpoint(id);
+                }
+            },
+            _ => return String::new(),
+        }
+        String::from("OK")
+    }
+}
+
+fn stop_reply_signal(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn target_description() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  \
+         <feature name=\"org.chip8rs.chip8\">\n",
+    );
+    for (number, (name, size)) in REGISTERS.iter().enumerate() {
+        let kind = match *name {
+            "pc" => "code_ptr",
+            "i" => "data_ptr",
+            _ => "uint8",
+        };
+        xml.push_str(&format!(
+            "    <reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\" type=\"{}\"/>\n",
+            name,
+            size * 8,
+            number,
+            kind
+        ));
+    }
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+/// A qXfer chunk: `m` when there is more to read, `l` for the last one.
+fn transfer(document: &[u8], offset: usize, length: usize) -> String {
+    let start = offset.min(document.len());
+    let end = offset.saturating_add(length).min(document.len());
+    let marker = if end == document.len() { 'l' } else { 'm' };
+    let mut reply = marker.to_string();
+    for &byte in &document[start..end] {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => {
+                reply.push('}');
+                reply.push((byte ^ 0x20) as char);
+            },
+            _ => reply.push(byte as char),
+        }
+    }
+    reply
+}
+
+/// `ADDR,LENGTH` in hex.
+fn parse_pair(text: &str) -> Option<(usize, usize)> {
+    let (first, second) = text.split_once(',')?;
+    Some((usize::from_str_radix(first, 16).ok()?, usize::from_str_radix(second, 16).ok()?))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+/// Packet framing over the socket: `$data#checksum`, acknowledged with `+` until the client turns
+/// acknowledgements off.
+struct Connection {
+    stream: TcpStream,
+    pending: VecDeque<u8>,
+    no_ack: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream,
+            pending: VecDeque::new(),
+            no_ack: false,
+        })
+    }
+
+    /// The next packet or interrupt, None once the client has gone.
+    fn receive(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(INTERRUPT) => return Ok(Some(Incoming::Interrupt)),
+                Some(b'$') => {},
+                // acknowledgements and noise between packets
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut sum = [0; 2];
+            for digit in sum.iter_mut() {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *digit = byte,
+                }
+            }
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                == Some(checksum(&data));
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(Incoming::Packet(String::from_utf8_lossy(&data).into_owned())));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    /// True when the client has sent a Ctrl-C, without waiting for one.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 256];
+        self.stream.set_nonblocking(true)?;
+        let read = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false)?;
+        match read {
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "gdb disconnected")),
+            Ok(count) => self.pending.extend(&buffer[..count]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+            Err(e) => return Err(e),
+        }
+        match self.pending.iter().position(|&byte| byte == INTERRUPT) {
+            Some(position) => {
+                self.pending.remove(position);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pending.is_empty() {
+            let mut buffer = [0; 256];
+            let count = self.stream.read(&mut buffer)?;
+            self.pending.extend(&buffer[..count]);
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+/// Wait for gdb on localhost at `port` and serve it until it detaches or kills the program.
+pub fn run<C: Cpu>(computer: &mut Computer<C>, port: u16) -> Result<(), String> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).map_err(|e| format!("unable to listen on port {}: {}", port, e))?;
+    println!("Waiting for gdb on {}, connect with: target remote {}", listener.local_addr().map_err(|e| e.to_string())?, port);
+    let (stream, peer) = listener.accept().map_err(|e| e.to_string())?;
+    println!("gdb connected from {}", peer);
+    serve(computer, stream).map_err(|e| e.to_string())
+}
+
+/// Serve one client on an accepted connection.
+pub fn serve<C: Cpu>(computer: &mut Computer<C>, stream: TcpStream) -> io::Result<()> {
+    let mut connection = Connection::new(stream)?;
+    let mut stub = GdbStub::new(computer);
+    while let Some(incoming) = connection.receive()? {
+        let packet = match incoming {
+            Incoming::Packet(packet) => packet,
+            // the program is already stopped
+            Incoming::Interrupt => continue,
+        };
+        let reply = match stub.handle(&packet) {
+            Action::Reply(reply) => reply,
+            Action::Continue => stub.resume(|| connection.poll_interrupt())?,
+            Action::Quit(reply) => {
+                if let Some(reply) = reply {
+                    connection.send(&reply)?;
+                }
+                return Ok(());
+            },
+        };
+        connection.send(&reply)?;
+        if packet == "QStartNoAckMode" {
+            connection.no_ack = true;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::computer::test_support::powered_on;
+    use crate::hardware::rewind::Rewind;
+    use std::thread;
+
+    // LD V0, 0x2A; LD I, 0x300; LD [I], V0; JP 0x206
+    const ROM: [u8; 8] = [0x60, 0x2A, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x06];
+
+    fn reply<C: Cpu>(stub: &mut GdbStub<C>, packet: &str) -> String {
+        match stub.handle(packet) {
+            Action::Reply(reply) => reply,
+            Action::Continue => stub.resume(|| Ok(false)).unwrap(),
+            action => panic!("unexpected {:?} for {}", action, packet),
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut computer = powered_on(ROM.to_vec());
+        let mut stub = GdbStub::new(&mut computer);
+        assert_eq!(reply(&mut stub, "g"), format!("{}0000{}000000", "00".repeat(16), "0002"));
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(reply(&mut stub, "p0"), "2a");
+        assert_eq!(reply(&mut stub, "p11"), "0202");
+        assert_eq!(reply(&mut stub, "P13=3c"), "OK");
+        assert_eq!(reply(&mut stub, "P10=0004"), "OK");
+        assert_eq!(reply(&mut stub, "p10"), "0004");
+        assert_eq!(reply(&mut stub, "p15"), "E01");
+        assert_eq!(reply(&mut stub, "G00"), "E01");
+
+        assert_eq!(reply(&mut stub, "m200,4"), "602aa300");
+        assert_eq!(reply(&mut stub, "M300,2:0102"), "OK");
+        assert_eq!(reply(&mut stub, "m300,2"), "0102");
+        assert_eq!(reply(&mut stub, "mffe,8"), "0000");
+        assert_eq!(reply(&mut stub, "M0,1:00"), "E01");
+        assert_eq!(reply(&mut stub, "mffffffffffffffff,10"), "E01");
+        drop(stub);
+        assert_eq!(computer.timers.delay, 0x3C);
+        assert_eq!(computer.cpu.registers().i, 0x400);
+    }
+
+    #[test]
+    fn test_non_ascii_packet() {
+        let mut computer = powered_on(ROM.to_vec());
+        let mut stub = GdbStub::new(&mut computer);
+        assert_eq!(reply(&mut stub, "é"), "E01");
+        assert_eq!(reply(&mut stub, "€00"), "E01");
+        assert_eq!(reply(&mut stub, "p€"), "E01");
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let mut computer = powered_on(ROM.to_vec());
+        let mut stub = GdbStub::new(&mut computer);
+        assert_eq!(reply(&mut stub, "Z0,204,2"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "T05swbreak:;");
+        assert_eq!(reply(&mut stub, "p11"), "0402");
+        assert_eq!(reply(&mut stub, "z0,204,2"), "OK");
+        assert_eq!(reply(&mut stub, "Z2,300,1"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "T05watch:300;");
+        assert_eq!(reply(&mut stub, "z2,300,1"), "OK");
+        assert!(stub.computer.watchpoints().is_empty());
+        assert_eq!(reply(&mut stub, "c1000"), "S0b");
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut computer = powered_on(ROM.to_vec());
+        computer.set_rewind(Some(Rewind::new(60)));
+        let mut stub = GdbStub::new(&mut computer);
+        assert_eq!(reply(&mut stub, "bs"), "T05replaylog:begin;");
+        for _ in 0..3 {
+            reply(&mut stub, "s");
+        }
+        assert_eq!(reply(&mut stub, "p11"), "0602");
+        assert_eq!(reply(&mut stub, "bs"), "S05");
+        assert_eq!(reply(&mut stub, "p11"), "0402");
+        assert_eq!(reply(&mut stub, "Z0,202,2"), "OK");
+        assert_eq!(reply(&mut stub, "bc"), "T05swbreak:;");
+        assert_eq!(reply(&mut stub, "p11"), "0202");
+        assert_eq!(reply(&mut stub, "bc"), "T05replaylog:begin;");
+        assert_eq!(reply(&mut stub, "p11"), "0002");
+    }
+
+    #[test]
+    fn test_target_description() {
+        let mut computer = powered_on(ROM.to_vec());
+        let mut stub = GdbStub::new(&mut computer);
+        let xml = target_description();
+        assert!(xml.contains("<reg name=\"vf\" bitsize=\"8\" regnum=\"15\" type=\"uint8\"/>"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" regnum=\"17\" type=\"code_ptr\"/>"));
+
+        let first = reply(&mut stub, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &xml[..16]));
+        let rest = reply(&mut stub, &format!("qXfer:features:read:target.xml:10,{:x}", PACKET_SIZE));
+        assert_eq!(rest, format!("l{}", &xml[16..]));
+        assert_eq!(transfer(b"a#b", 0, 3), "la}\x03b");
+    }
+
+    /// A client speaking the protocol over a real socket, the way gdb opens a session.
+    #[test]
+    fn test_scripted_session() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut computer = powered_on(ROM.to_vec());
+            let (stream, _) = listener.accept().unwrap();
+            serve(&mut computer, stream).unwrap();
+            computer.cpu.registers().pc
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        let mut exchange = |packet: &str| -> String {
+            let framed = format!("${}#{:02x}", packet, checksum(packet.as_bytes()));
+            client.write_all(framed.as_bytes()).unwrap();
+            let mut received = Vec::new();
+            let mut byte = [0];
+            while !(received.len() >= 3 && received[received.len() - 3] == b'#') {
+                client.read_exact(&mut byte).unwrap();
+                if !(received.is_empty() && byte[0] == b'+') {
+                    received.push(byte[0]);
+                }
+            }
+            let data = &received[1..received.len() - 3];
+            let sum = std::str::from_utf8(&received[received.len() - 2..]).unwrap();
+            assert_eq!(u8::from_str_radix(sum, 16).unwrap(), checksum(data));
+            String::from_utf8(data.to_vec()).unwrap()
+        };
+
+        assert!(exchange("qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+        assert_eq!(exchange("QStartNoAckMode"), "OK");
+        assert_eq!(exchange("vMustReplyEmpty"), "");
+        assert_eq!(exchange("?"), "S05");
+        assert_eq!(exchange("Z0,206,2"), "OK");
+        assert_eq!(exchange("c"), "T05swbreak:;");
+        assert_eq!(exchange("z0,206,2"), "OK");
+
+        client.write_all(b"$c#63").unwrap();
+        client.write_all(&[INTERRUPT]).unwrap();
+        let mut stop = [0; 7];
+        client.read_exact(&mut stop).unwrap();
+        assert_eq!(&stop, b"$S02#b5");
+
+        client.write_all(b"$k#6b").unwrap();
+        assert_eq!(server.join().unwrap(), 0x206);
+    }
+}
@@ -0,0 +1,28 @@
+use crate::hardware::{computer::Computer, cpus::Cpu};
+use super::audio::{AudioConfig, AudioOutput, NullAudio, WavCapture};
+
+/// Run without a window for a fixed number of frames, as fast as the host allows.
+/// The beeper is rendered to a WAV file when a path is given and the final screen is printed.
+/// A SUPER-CHIP program that exits ends the run early.
+pub fn run<C: Cpu>(computer: &mut Computer<C>, frames: u128, wav_path: Option<&str>, audio_config: AudioConfig) -> Result<(), String> {
+    let mut capture = wav_path.map(|_| WavCapture::new(audio_config));
+    let mut null_audio = NullAudio;
+    let audio: &mut dyn AudioOutput = match capture.as_mut() {
+        Some(capture) => capture,
+        None => &mut null_audio,
+    };
+
+    for _ in 0..frames {
+        if computer.cpu.has_exited() {
+            break;
+        }
+        computer.run().map_err(|e| e.to_string())?;
+        audio.frame(computer.timers.sound > 0, &computer.sound);
+    }
+    computer.display.dump();
+
+    if let (Some(capture), Some(path)) = (capture, wav_path) {
+        capture.save(path)?;
+    }
+    Ok(())
+}
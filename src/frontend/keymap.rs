@@ -0,0 +1,128 @@
+//! Key map
+//! Translates host keys into the 16 keys of the Chip-8 keypad. The default layout puts the
+//! keypad on the left hand side of a QWERTY keyboard:
+//! 1 2 3 C      1 2 3 4
+//! 4 5 6 D  ->  Q W E R
+//! 7 8 9 E      A S D F
+//! A 0 B F      Z X C V
+//!
+//! A config file can override any of the keys, one binding per line, `#` starts a comment:
+//! 5 = W, Up
+//! C = 4
+
+use std::collections::HashMap;
+
+use sdl2::keyboard::Keycode;
+
+pub const DEFAULT_KEYMAP_PATH: &str = "keymap.cfg";
+
+const DEFAULT_LAYOUT: [(u8, Keycode); 16] = [
+    (0x1, Keycode::Num1), (0x2, Keycode::Num2), (0x3, Keycode::Num3), (0xC, Keycode::Num4),
+    (0x4, Keycode::Q),    (0x5, Keycode::W),    (0x6, Keycode::E),    (0xD, Keycode::R),
+    (0x7, Keycode::A),    (0x8, Keycode::S),    (0x9, Keycode::D),    (0xE, Keycode::F),
+    (0xA, Keycode::Z),    (0x0, Keycode::X),    (0xB, Keycode::C),    (0xF, Keycode::V),
+];
+
+#[derive(Clone, Debug)]
+pub struct KeyMap {
+    keys: HashMap<Keycode, u8>,
+}
+
+impl KeyMap {
+    pub fn new() -> Self {
+        KeyMap {
+            keys: DEFAULT_LAYOUT.iter().map(|&(key, keycode)| (keycode, key)).collect(),
+        }
+    }
+
+    /// Load the default layout and apply the bindings found in the config file on top of it.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let config = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read key map {}: {}", path, e))?;
+        let mut keymap = KeyMap::new();
+        for (line, key, names) in parse_bindings(&config).map_err(|e| format!("{}:{}", path, e))? {
+            // a rebound key loses its default host keys
+            keymap.keys.retain(|_, bound| *bound != key);
+            for name in names {
+                let keycode = Keycode::from_name(&name)
+                    .ok_or_else(|| format!("{}:{}: unknown key name '{}'", path, line, name))?;
+                keymap.keys.insert(keycode, key);
+            }
+        }
+        Ok(keymap)
+    }
+
+    /// Use the config file in the working directory when there is one, the default layout otherwise.
+    pub fn load_or_default() -> Result<Self, String> {
+        if std::path::Path::new(DEFAULT_KEYMAP_PATH).exists() {
+            KeyMap::load(DEFAULT_KEYMAP_PATH)
+        } else {
+            Ok(KeyMap::new())
+        }
+    }
+
+    pub fn get(&self, keycode: Keycode) -> Option<u8> {
+        self.keys.get(&keycode).copied()
+    }
+}
+
+/// Parse `<chip8 key> = <host key>[, <host key>...]` lines into (line number, key, host key names).
+fn parse_bindings(config: &str) -> Result<Vec<(usize, u8, Vec<String>)>, String> {
+    let mut bindings = Vec::new();
+    for (index, line) in config.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, names) = line
+            .split_once('=')
+            .ok_or_else(|| format!("{}: expected '<key> = <host key>'", line_number))?;
+        let key = key.trim();
+        let key = match (key.len(), u8::from_str_radix(key, 16)) {
+            (1, Ok(key)) => key,
+            _ => return Err(format!("{}: '{}' is not a keypad key (0-F)", line_number, key)),
+        };
+        let names: Vec<String> = names
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if names.is_empty() {
+            return Err(format!("{}: no host key given", line_number));
+        }
+        bindings.push((line_number, key, names));
+    }
+    Ok(bindings)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_layout() {
+        let keymap = KeyMap::new();
+        assert_eq!(keymap.get(Keycode::Num4), Some(0xC));
+        assert_eq!(keymap.get(Keycode::X), Some(0x0));
+        assert_eq!(keymap.get(Keycode::P), None);
+    }
+
+    #[test]
+    fn test_parse_bindings() {
+        let bindings = parse_bindings("# azerty\n4 = A\n\nc = 4, Up # two keys\n").unwrap();
+        assert_eq!(bindings, vec![
+            (2, 0x4, vec!["A".to_string()]),
+            (4, 0xC, vec!["4".to_string(), "Up".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_bindings_errors() {
+        assert!(parse_bindings("G = A").is_err());
+        assert!(parse_bindings("10 = A").is_err());
+        assert!(parse_bindings("1 A").is_err());
+        assert!(parse_bindings("1 = ").is_err());
+    }
+}
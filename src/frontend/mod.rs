@@ -0,0 +1,10 @@
+pub mod audio;
+pub mod debugger;
+pub mod gdb;
+pub mod headless;
+#[cfg(feature = "gui")]
+pub mod keymap;
+#[cfg(feature = "gui")]
+pub mod renderer;
+#[cfg(feature = "gui")]
+pub mod sdl;
@@ -0,0 +1,150 @@
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::{Point, Rect};
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+
+use crate::hardware::display::{Display, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+const BYTES_PER_PIXEL: usize = 3; // RGB24
+
+#[derive(Clone, Copy, Debug)]
+pub struct RenderConfig {
+    pub scale: u32,
+    pub foreground: Color,
+    pub background: Color,
+    /// XO-CHIP pixels lit on the second plane only.
+    pub second: Color,
+    /// XO-CHIP pixels lit on both planes.
+    pub both: Color,
+    pub grid: bool,
+    /// Present in step with the display's refresh, which then paces the frames instead of the clock.
+    pub vsync: bool,
+}
+
+impl RenderConfig {
+    pub fn new() -> Self {
+        RenderConfig {
+            scale: 15,
+            foreground: Color::RGB(255, 255, 255),
+            background: Color::RGB(0, 0, 0),
+            second: Color::RGB(0xAA, 0xAA, 0xAA),
+            both: Color::RGB(0x55, 0x55, 0x55),
+            grid: false,
+            vsync: false,
+        }
+    }
+
+    /// The window keeps its size when a program switches to 128x64, its pixels are half the size.
+    pub fn window_size(&self) -> (u32, u32) {
+        (DISPLAY_WIDTH as u32 * self.scale, DISPLAY_HEIGHT as u32 * self.scale)
+    }
+
+    // a quarter of the way from the background to the foreground, visible but not distracting
+    fn grid_colour(&self) -> Color {
+        let blend = |bg: u8, fg: u8| ((bg as u16 * 3 + fg as u16) / 4) as u8;
+        Color::RGB(
+            blend(self.background.r, self.foreground.r),
+            blend(self.background.g, self.foreground.g),
+            blend(self.background.b, self.foreground.b),
+        )
+    }
+}
+
+/// Renderer
+/// Copies the emulator framebuffer into a streaming texture at its native resolution and lets
+/// SDL scale it up to the window, so each frame only uploads width * height pixels. The texture
+/// is created again when the program changes the resolution.
+pub struct Renderer<'a> {
+    texture_creator: &'a TextureCreator<WindowContext>,
+    texture: Texture<'a>,
+    size: (usize, usize),
+    config: RenderConfig,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(texture_creator: &'a TextureCreator<WindowContext>, display: &Display, config: RenderConfig) -> Result<Self, String> {
+        let size = (display.width(), display.height());
+        let texture = create_texture(texture_creator, size)?;
+        Ok(Renderer { texture_creator, texture, size, config })
+    }
+
+    pub fn draw(&mut self, canvas: &mut Canvas<Window>, display: &Display) -> Result<(), String> {
+        let config = self.config;
+        let size = (display.width(), display.height());
+        if size != self.size {
+            self.texture = create_texture(self.texture_creator, size)?;
+            self.size = size;
+        }
+        self.texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+            fill_pixels(display, &config, buffer, pitch);
+        })?;
+
+        canvas.set_draw_color(config.background);
+        canvas.clear();
+        let (width, height) = config.window_size();
+        canvas.copy(&self.texture, None, Rect::new(0, 0, width, height))?;
+
+        if config.grid {
+            canvas.set_draw_color(config.grid_colour());
+            let (columns, rows) = (display.width() as u32, display.height() as u32);
+            for x in 1..columns {
+                let x = (x * width / columns) as i32;
+                canvas.draw_line(Point::new(x, 0), Point::new(x, height as i32))?;
+            }
+            for y in 1..rows {
+                let y = (y * height / rows) as i32;
+                canvas.draw_line(Point::new(0, y), Point::new(width as i32, y))?;
+            }
+        }
+
+        canvas.present();
+        Ok(())
+    }
+}
+
+fn create_texture(texture_creator: &TextureCreator<WindowContext>, (width, height): (usize, usize)) -> Result<Texture<'_>, String> {
+    texture_creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, width as u32, height as u32)
+        .map_err(|e| e.to_string())
+}
+
+fn fill_pixels(display: &Display, config: &RenderConfig, buffer: &mut [u8], pitch: usize) {
+    for y in 0..display.height() {
+        for x in 0..display.width() {
+            let colour = match display.colour(x, y) {
+                0 => config.background,
+                1 => config.foreground,
+                2 => config.second,
+                _ => config.both,
+            };
+            let offset = y * pitch + x * BYTES_PER_PIXEL;
+            buffer[offset] = colour.r;
+            buffer[offset + 1] = colour.g;
+            buffer[offset + 2] = colour.b;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_pixels_uses_palette() {
+        let mut display = Display::new();
+        display.draw_sprite(1, 0, &[0x80], true);
+        let mut config = RenderConfig::new();
+        config.foreground = Color::RGB(0x10, 0x20, 0x30);
+        config.background = Color::RGB(0x01, 0x02, 0x03);
+        let pitch = display.width() * BYTES_PER_PIXEL;
+        let mut buffer = vec![0; pitch * display.height()];
+        fill_pixels(&display, &config, &mut buffer, pitch);
+        assert_eq!(&buffer[0..6], &[0x01, 0x02, 0x03, 0x10, 0x20, 0x30]);
+
+        display.select_planes(0b10);
+        display.draw_sprite(1, 0, &[0xC0], true);
+        fill_pixels(&display, &config, &mut buffer, pitch);
+        assert_eq!(&buffer[3..9], &[0x55, 0x55, 0x55, 0xAA, 0xAA, 0xAA]);
+    }
+}
@@ -0,0 +1,127 @@
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+
+use crate::hardware::{clock::TIMER_FREQUENCY, computer::Computer, cpus::Cpu, savestate::SaveSlots};
+use super::{audio::{AudioConfig, AudioOutput, SdlAudio}, keymap::KeyMap, renderer::{RenderConfig, Renderer}};
+
+// how long a save state message stays in the title bar
+const MESSAGE_FRAMES: u128 = 2 * TIMER_FREQUENCY as u128;
+
+/// Holding backspace runs the program backwards as far as the rewind buffer goes.
+/// Save states: F5 saves to the current slot, F9 loads it, F6 and F7 pick the previous and next slot.
+pub fn run<C: Cpu>(computer: &mut Computer<C>, render_config: RenderConfig, audio_config: AudioConfig, keymap_path: Option<&str>, mut saves: SaveSlots) -> Result<(), String> {
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let audio_subsystem = sdl_context.audio()?;
+
+    let (width, height) = render_config.window_size();
+    let window = video_subsystem.window("Chip8 Window", width, height)
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut canvas_builder = window.into_canvas();
+    if render_config.vsync {
+        canvas_builder = canvas_builder.present_vsync();
+    }
+    let mut canvas = canvas_builder.build()
+        .map_err(|e| e.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut renderer = Renderer::new(&texture_creator, &computer.display, render_config)?;
+    let mut audio = SdlAudio::new(&audio_subsystem, audio_config)?;
+
+    let keymap = match keymap_path {
+        Some(path) => KeyMap::load(path)?,
+        None => KeyMap::load_or_default()?,
+    };
+    let mut fault = None;
+    // text for the title bar and the frame it expires at
+    let mut message: Option<(String, u128)> = None;
+    let mut rewinding = false;
+    let mut event_pump = sdl_context.event_pump()?;
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    break 'running;
+                },
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => rewinding = true,
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => rewinding = false,
+                Event::KeyDown { keycode: Some(keycode @ (Keycode::F5 | Keycode::F6 | Keycode::F7 | Keycode::F9)), repeat: false, .. } => {
+                    let text = match keycode {
+                        Keycode::F5 => saves.save(computer).map(|path| format!("saved {}", path.display())),
+                        Keycode::F9 => saves.load(computer).map(|path| {
+                            fault = None;
+                            format!("loaded {}", path.display())
+                        }),
+                        Keycode::F6 => {
+                            saves.previous();
+                            Ok(format!("slot {}", saves.slot))
+                        },
+                        _ => {
+                            saves.next();
+                            Ok(format!("slot {}", saves.slot))
+                        },
+                    };
+                    let text = text.unwrap_or_else(|error| {
+                        eprintln!("Save state failed: {}", error);
+                        error
+                    });
+                    canvas.window_mut().set_title(&format!("Chip8 Window - {}", text)).map_err(|e| e.to_string())?;
+                    message = Some((text, computer.clock.frames + MESSAGE_FRAMES));
+                },
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    if let Some(key) = keymap.get(keycode) {
+                        computer.keyboard.press(key);
+                    }
+                },
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(key) = keymap.get(keycode) {
+                        computer.keyboard.release(key);
+                    }
+                },
+                Event::Window { win_event: WindowEvent::Exposed, .. } => {
+                    // the window contents were lost, draw the current frame again
+                    renderer.draw(&mut canvas, &computer.display)?;
+                },
+                // Handle other keys or events
+                _ => {}
+            }
+        }
+        if rewinding {
+            if computer.rewind_frame() {
+                fault = None;
+            }
+        } else if fault.is_none() {
+            if computer.cpu.has_exited() {
+                break 'running;
+            }
+            if let Err(error) = computer.run() {
+                // keep the window open on the last frame so the screen can be inspected
+                eprintln!("Emulation halted: {}", error);
+                canvas.window_mut().set_title(&format!("Chip8 Window - halted: {}", error))
+                    .map_err(|e| e.to_string())?;
+                fault = Some(error);
+            }
+        }
+        audio.frame(fault.is_none() && !rewinding && computer.timers.sound > 0, &computer.sound);
+
+        // with vsync every frame is presented, that is what waits for the refresh
+        if computer.display.take_dirty() || render_config.vsync {
+            renderer.draw(&mut canvas, &computer.display)?;
+        }
+        message = message.filter(|(_, expires)| computer.clock.frames < *expires);
+        if fault.is_none() && message.is_none() && computer.clock.frames.is_multiple_of(TIMER_FREQUENCY as u128) {
+            let stats = computer.clock.stats();
+            let title = format!("Chip8 Window - {:.0} IPS - {:.2} ms/frame",
+                stats.instructions_per_second, stats.frame_time.as_secs_f64() * 1000.0);
+            canvas.window_mut().set_title(&title).map_err(|e| e.to_string())?;
+        }
+        if render_config.vsync {
+            computer.clock.end_frame();
+        } else {
+            computer.clock.wait_for_next_frame();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use super::{clock::Timers, display::Display, error::EmulationError, keyboard::Keyboard, memory::Memory, sound::Sound, watch::{AccessKind, MemoryAccess}};
+
+/// Bus
+/// Everything the CPU can reach while executing an instruction. The peripherals stay owned by
+/// the `Computer`, the bus only borrows them for the duration of a cycle.
+pub struct Bus<'a> {
+    pub memory: &'a mut Memory,
+    pub display: &'a mut Display,
+    pub keyboard: &'a mut Keyboard,
+    pub timers: &'a mut Timers,
+    pub sound: &'a mut Sound,
+    accesses: Option<&'a mut Vec<MemoryAccess>>,
+}
+
+impl<'a> Bus<'a> {
+    pub fn new(
+        memory: &'a mut Memory,
+        display: &'a mut Display,
+        keyboard: &'a mut Keyboard,
+        timers: &'a mut Timers,
+        sound: &'a mut Sound,
+    ) -> Self {
+        Bus {
+            memory,
+            display,
+            keyboard,
+            timers,
+            sound,
+            accesses: None,
+        }
+    }
+
+    /// Record the data reads and writes of the instruction, for watchpoints.
+    pub fn with_access_log(mut self, accesses: &'a mut Vec<MemoryAccess>) -> Self {
+        self.accesses = Some(accesses);
+        self
+    }
+
+    /// Memory read on behalf of an instruction, opcode fetches go to `memory` directly.
+    pub fn read(&mut self, address: u16) -> Result<u8, EmulationError> {
+        let value = self.memory.read(address)?;
+        if let Some(accesses) = self.accesses.as_mut() {
+            accesses.push(MemoryAccess { kind: AccessKind::Read, address, value });
+        }
+        Ok(value)
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) -> Result<(), EmulationError> {
+        self.memory.write(address, value)?;
+        if let Some(accesses) = self.accesses.as_mut() {
+            accesses.push(MemoryAccess { kind: AccessKind::Write, address, value });
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,27 @@
+use super::cpus::quirks::Quirks;
+
+pub const DEFAULT_START_ADDRESS: u16 = 0x200;
+
 pub struct Cartridge {
     pub rom: Vec<u8>,
+    pub start_address: u16,
+    pub quirks: Quirks,
 }
 
 impl Cartridge {
     pub fn new(rom: Vec<u8>) -> Self {
-        Cartridge { rom }
+        Cartridge { rom, start_address: DEFAULT_START_ADDRESS, quirks: Quirks::vip() }
+    }
+
+    /// Load and start the program somewhere else than 0x200, ETI 660 programs start at 0x600.
+    pub fn with_start_address(mut self, start_address: u16) -> Self {
+        self.start_address = start_address;
+        self
+    }
+
+    /// Run the program with the behaviour of another interpreter than the COSMAC VIP.
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
     }
 }
@@ -1,91 +1,265 @@
+use std::time::{Duration, Instant};
 
 const CLOCK_SPEED: u16 = 500;
+/// The delay and sound timers count down at 60Hz, whatever the speed of the CPU.
+pub const TIMER_FREQUENCY: u16 = 60;
+// once the emulator is this many frames late it stops trying to catch up and starts over from now
+const MAX_FRAMES_BEHIND: u128 = 5;
+// sleeping overshoots by up to a millisecond on most systems, the last stretch is spent spinning
+const SPIN_THRESHOLD: Duration = Duration::from_millis(1);
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay and sound timers, both count down towards zero.
+#[derive(Clone, Debug)]
+pub struct Timers {
+    pub delay: u8,
+    pub sound: u8,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Timers { delay: 0, sound: 0 }
+    }
+
+    pub fn tick(&mut self) {
+        self.delay = self.delay.saturating_sub(1);
+        self.sound = self.sound.saturating_sub(1);
+    }
+}
+
+/// Measurements over the last `STATS_INTERVAL`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockStats {
+    pub instructions_per_second: f64,
+    pub frames_per_second: f64,
+    /// Average time spent on a frame before waiting for the next one.
+    pub frame_time: Duration,
+}
+
+impl ClockStats {
+    fn new() -> Self {
+        ClockStats {
+            instructions_per_second: 0.0,
+            frames_per_second: 0.0,
+            frame_time: Duration::ZERO,
+        }
+    }
+}
+
+/// Clock
+/// Emulation runs in frames of 1/60s: `instructions_per_frame` instructions and one timer tick.
+/// `wait_for_next_frame` holds that rate in real time. Deadlines are computed from the frame count
+/// since the clock started, so rounding and late wake ups never add up to drift.
 pub struct Clock {
     pub cycles: u128,
-    seconds: u128,
-    last_cycle_update: std::time::Instant,
-    last_second_update: std::time::Instant,
-    cycle_interval: std::time::Duration,
+    pub frames: u128,
+    pub instructions_per_frame: u16,
+    epoch: Instant,
+    scheduled_frames: u128,
+    frame_started: Instant,
+    stats: ClockStats,
+    stats_started: Instant,
+    stats_cycles: u128,
+    stats_frames: u128,
+    stats_busy: Duration,
 }
 
 impl Clock {
     pub fn new() -> Self {
+        let now = Instant::now();
         Self {
             cycles: 0,
-            seconds: 0,
-            last_cycle_update: std::time::Instant::now(),
-            last_second_update: std::time::Instant::now(),
-            cycle_interval: std::time::Duration::from_millis(1000 / CLOCK_SPEED as u64),
+            frames: 0,
+            instructions_per_frame: CLOCK_SPEED / TIMER_FREQUENCY,
+            epoch: now,
+            scheduled_frames: 0,
+            frame_started: now,
+            stats: ClockStats::new(),
+            stats_started: now,
+            stats_cycles: 0,
+            stats_frames: 0,
+            stats_busy: Duration::ZERO,
         }
     }
 
-    fn update(&mut self) {
-        let now = std::time::Instant::now();
-        if self.cycles == u128::MAX {
-            panic!("cycles overflowed"); // highly unlikely :)
-        }
+    /// Restart scheduling from now, counters are kept.
+    pub fn reset(&mut self) {
+        let now = Instant::now();
+        self.epoch = now;
+        self.scheduled_frames = 0;
+        self.frame_started = now;
+        self.stats_started = now;
+        self.stats_cycles = self.cycles;
+        self.stats_frames = self.frames;
+        self.stats_busy = Duration::ZERO;
+    }
+
+    /// Run about `instructions_per_second` instructions a second, the nearest whole number a frame
+    /// and at least one.
+    pub fn set_speed(&mut self, instructions_per_second: u32) {
+        let frequency = TIMER_FREQUENCY as u32;
+        let per_frame = instructions_per_second.saturating_add(frequency / 2) / frequency;
+        self.instructions_per_frame = per_frame.clamp(1, u16::MAX as u32) as u16;
+    }
 
-        // if now.duration_since(self.last_cycle_update) >= self.cycle_interval {
-        //     self.last_cycle_update = now;
-        //     self.cycles += 1;
-        // }
+    pub fn cycle(&mut self) {
         self.cycles += 1;
+    }
+
+    pub fn frame(&mut self) {
+        self.frames += 1;
+    }
+
+    pub fn stats(&self) -> ClockStats {
+        self.stats
+    }
 
-        if now.duration_since(self.last_second_update) >= std::time::Duration::from_secs(1) {
-            self.last_second_update = now;
-            self.seconds += 1;
-            println!("seconds: {} - cycles: {}", self.seconds, self.cycles);
+    /// Block until the next 1/60s frame boundary.
+    pub fn wait_for_next_frame(&mut self) {
+        let now = Instant::now();
+        self.record_frame(now);
+        if let Some(deadline) = self.schedule(now) {
+            if let Some(sleep) = deadline.checked_duration_since(now + SPIN_THRESHOLD) {
+                std::thread::sleep(sleep);
+            }
+            while Instant::now() < deadline {
+                std::hint::spin_loop();
+            }
         }
+        self.frame_started = Instant::now();
     }
 
+    /// Finish a frame without waiting, for frontends that something else paces, such as vsync.
+    pub fn end_frame(&mut self) {
+        let now = Instant::now();
+        self.record_frame(now);
+        self.frame_started = now;
+    }
 
-    pub fn first_run(&mut self) {
-        self.cycles += 1;
+    fn deadline(&self, frame: u128) -> Instant {
+        let nanos = frame * 1_000_000_000 / TIMER_FREQUENCY as u128;
+        self.epoch + Duration::from_nanos(nanos as u64)
+    }
+
+    /// Returns when the next frame should start, or None when it is already late.
+    fn schedule(&mut self, now: Instant) -> Option<Instant> {
+        self.scheduled_frames += 1;
+        let deadline = self.deadline(self.scheduled_frames);
+        if now >= self.deadline(self.scheduled_frames + MAX_FRAMES_BEHIND) {
+            // too far behind (debugger pause, host hiccup), running a burst of frames would only
+            // make the game jump, start counting again from now
+            self.epoch = now;
+            self.scheduled_frames = 0;
+            return None;
+        }
+        if now >= deadline {
+            None
+        } else {
+            Some(deadline)
+        }
     }
 
-    pub fn run(&mut self) -> bool {
-        self.update();
-        true
+    fn record_frame(&mut self, now: Instant) {
+        self.stats_busy += now.saturating_duration_since(self.frame_started);
+        let elapsed = now.saturating_duration_since(self.stats_started);
+        if elapsed < STATS_INTERVAL {
+            return;
+        }
+        let seconds = elapsed.as_secs_f64();
+        let frames = self.frames - self.stats_frames;
+        self.stats = ClockStats {
+            instructions_per_second: (self.cycles - self.stats_cycles) as f64 / seconds,
+            frames_per_second: frames as f64 / seconds,
+            frame_time: if frames > 0 { self.stats_busy / frames as u32 } else { Duration::ZERO },
+        };
+        self.stats_started = now;
+        self.stats_cycles = self.cycles;
+        self.stats_frames = self.frames;
+        self.stats_busy = Duration::ZERO;
     }
 }
 
 
-// pub fn start(mut chip8_cpu: Cpu) {
-
-//     let mut cycles: u128 = 0;
-//     let mut seconds: u128 = 0;
-//     let start = std::time::Instant::now();
-//     let mut last_cycle_update = start;
-//     let mut last_second_update = start;
-//     let cycle_interval = std::time::Duration::from_millis(1000 / CLOCK_SPEED as u64);
-
-//     // run one cycle to start
-//     cycles += 1;
-//     chip8_cpu.run();
-//     chip8_cpu.memory.reset();
-
-//     while seconds < 15 {
-//         let now = std::time::Instant::now();
-//         if cycles == u128::MAX {
-//             panic!("cycles overflowed"); // highly unlikely :)
-//         }
-
-//         if now.duration_since(last_cycle_update) >= cycle_interval {
-//             last_cycle_update = now;
-//             cycles += 1;
-//             chip8_cpu.run();
-//         }
-
-//         if now.duration_since(last_second_update) >= std::time::Duration::from_secs(1) {
-//             println!();
-//             last_second_update = now;
-//             seconds += 1;
-//             chip8_cpu.memory.write(chip8_cpu.memory.get_reserved_end() + (seconds as u16), seconds as u8);
-//             println!("seconds: {} - cycles: {}", seconds, cycles);
-//         }
-//     }
-//     chip8_cpu.memory.write(0x0FFF, 0xFF);
-//     chip8_cpu.memory.dump(0x0000, 0xFFF);
-//     println!("Value at 0x0000: {:02X}", chip8_cpu.memory.read(0x0000));
-//     println!("Value at 0x0FFF: {:02X}", chip8_cpu.memory.read(0x0FFF));
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timers_tick_down_to_zero() {
+        let mut timers = Timers { delay: 2, sound: 1 };
+        timers.tick();
+        assert_eq!((timers.delay, timers.sound), (1, 0));
+        timers.tick();
+        timers.tick();
+        assert_eq!((timers.delay, timers.sound), (0, 0));
+    }
+
+    #[test]
+    fn test_set_speed() {
+        let mut clock = Clock::new();
+        clock.set_speed(500);
+        assert_eq!(clock.instructions_per_frame, 8);
+        // 8.83 rounds up
+        clock.set_speed(530);
+        assert_eq!(clock.instructions_per_frame, 9);
+        clock.set_speed(100);
+        assert_eq!(clock.instructions_per_frame, 2);
+        clock.set_speed(1);
+        assert_eq!(clock.instructions_per_frame, 1);
+        clock.set_speed(u32::MAX);
+        assert_eq!(clock.instructions_per_frame, u16::MAX);
+    }
+
+    #[test]
+    fn test_schedule_does_not_drift() {
+        let mut clock = Clock::new();
+        let epoch = clock.epoch;
+        for _ in 0..59 {
+            clock.schedule(epoch);
+        }
+        // the 60th frame ends exactly one second after the start
+        assert_eq!(clock.schedule(epoch), Some(epoch + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_schedule_late_frame_does_not_wait() {
+        let mut clock = Clock::new();
+        let epoch = clock.epoch;
+        assert_eq!(clock.schedule(epoch + Duration::from_millis(20)), None);
+        // still on the original timeline, frame 2 ends at 33.3ms
+        assert!(clock.schedule(epoch + Duration::from_millis(20)).is_some());
+    }
+
+    #[test]
+    fn test_schedule_resyncs_when_far_behind() {
+        let mut clock = Clock::new();
+        let late = clock.epoch + Duration::from_secs(1);
+        assert_eq!(clock.schedule(late), None);
+        assert_eq!(clock.epoch, late);
+        assert_eq!(clock.schedule(late), Some(clock.deadline(1)));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut clock = Clock::new();
+        let start = clock.stats_started;
+        clock.cycles = 600;
+        clock.frames = 60;
+        clock.record_frame(start + Duration::from_millis(500));
+        assert_eq!(clock.stats(), ClockStats::new());
+        clock.frame_started = start + Duration::from_millis(1900);
+        clock.record_frame(start + Duration::from_secs(2));
+        let stats = clock.stats();
+        assert_eq!(stats.instructions_per_second, 300.0);
+        assert_eq!(stats.frames_per_second, 30.0);
+        assert_eq!(stats.frame_time, Duration::from_millis(600) / 60);
+    }
+
+    #[test]
+    fn test_end_frame_does_not_schedule() {
+        let mut clock = Clock::new();
+        clock.end_frame();
+        assert_eq!(clock.scheduled_frames, 0);
+        assert!(clock.frame_started >= clock.epoch);
+    }
+}
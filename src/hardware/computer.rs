@@ -1,42 +1,567 @@
-use super::{cpus::chip8::Chip8, cartridge::Cartridge, clock::Clock, display, keyboard, memory::Memory};
+use super::{bus::Bus, cpus::{chip8::Chip8, Cpu}, cartridge::Cartridge, clock::{Clock, Timers}, display, error::EmulationError, keyboard, memory::Memory, sound::Sound};
+use super::rewind::Rewind;
+use super::savestate::{StateChunks, StateWriter};
+use super::trace::{TraceRecord, Tracer};
+use super::watch::{MemoryAccess, WatchHit, Watchpoint, WatchpointId, Watchpoints};
 
-pub struct Computer {
+/// Computer
+/// The machine around an interpreter core: memory, display, keypad, timers and sound on a bus, the
+/// clock that paces them and the debugging aids. Any `Cpu` can be plugged in, `Chip8` unless told otherwise.
+pub struct Computer<C: Cpu = Chip8> {
     pub clock: Clock,
-    pub cpu: Chip8,
+    pub cpu: C,
     pub memory: Memory,
     pub display: display::Display,
     pub keyboard: keyboard::Keyboard,
+    pub timers: Timers,
+    pub sound: Sound,
     pub cartridge: Cartridge,
-    pub last_cycle: u128,
+    // instructions run since the last timer tick
+    frame_cycles: u16,
+    watchpoints: Watchpoints,
+    // scratch for the memory accesses of one instruction, only filled while there are watchpoints
+    accesses: Vec<MemoryAccess>,
+    watch_hits: Vec<WatchHit>,
+    tracer: Option<Tracer>,
+    rewind: Option<Rewind>,
 } 
 
 impl Computer {
     pub fn new(cartridge: Cartridge) -> Self {
+        let cpu = Chip8::new().with_quirks(cartridge.quirks);
+        Computer::with_cpu(cartridge, cpu)
+    }
+}
+
+impl<C: Cpu> Computer<C> {
+    /// A computer running another core than the default `Chip8`.
+    pub fn with_cpu(cartridge: Cartridge, cpu: C) -> Self {
         Computer {
             clock: Clock::new(),
-            cpu: Chip8::new(),
-            memory: Memory::new(),
+            cpu,
+            memory: Memory::with_size(cartridge.quirks.memory_size),
             display: display::Display::new(),
             keyboard: keyboard::Keyboard::new(),
+            timers: Timers::new(),
+            sound: Sound::new(),
             cartridge,
-            last_cycle: 0,
+            frame_cycles: 0,
+            watchpoints: Watchpoints::new(),
+            accesses: Vec::new(),
+            watch_hits: Vec::new(),
+            tracer: None,
+            rewind: None,
         }
     }
     
-    pub fn power_on(&mut self) {
-        println!("Powering on... ");
+    pub fn power_on(&mut self) -> Result<(), EmulationError> {
         self.memory.reset();
-        self.memory.load_rom(self.cartridge.rom.clone());
+        self.keyboard.reset();
+        self.sound.reset();
+        self.memory.load_rom_at(self.cartridge.rom.clone(), self.cartridge.start_address)?;
+        self.cpu.reset();
+        self.cpu.set_pc(self.cartridge.start_address);
+        self.frame_cycles = 0;
+        self.clock.reset();
+        self.restart_rewind();
+        Ok(())
+    }
+
+    /// Run one 1/60s frame: `instructions_per_frame` instructions and one timer tick.
+    /// Call `clock.wait_for_next_frame` between frames to run in real time.
+    /// Stops at the first instruction that faults and returns why.
+    pub fn run(&mut self) -> Result<(), EmulationError> {
+        let frame = self.clock.frames;
+        while self.clock.frames == frame {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Run a single instruction, the timers tick after every `instructions_per_frame` of them.
+    pub fn step(&mut self) -> Result<(), EmulationError> {
+        self.clock.cycle();
+        self.step_cpu()?;
+        self.frame_cycles += 1;
+        if self.cpu.take_display_wait() {
+            self.frame_cycles = self.clock.instructions_per_frame;
+        }
+        if self.frame_cycles >= self.clock.instructions_per_frame {
+            self.frame_cycles = 0;
+            self.timers.tick();
+            self.clock.frame();
+            self.record_snapshot();
+        }
+        Ok(())
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> WatchpointId {
+        self.watchpoints.add(watchpoint, self.cpu.registers(), &self.timers)
+    }
+
+    pub fn remove_watchpoint(&mut self, id: WatchpointId) -> bool {
+        self.watchpoints.remove(id)
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    /// Watchpoints set off since the last call, in the order they fired.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    /// Attach a tracer, or detach it with None. Returns the one that was attached before.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    /// Attach a rewind buffer, or detach it with None. It starts with a snapshot of the machine as it is now.
+    pub fn set_rewind(&mut self, rewind: Option<Rewind>) -> Option<Rewind> {
+        let previous = std::mem::replace(&mut self.rewind, rewind);
+        self.restart_rewind();
+        previous
+    }
+
+    pub fn rewind(&self) -> Option<&Rewind> {
+        self.rewind.as_ref()
+    }
+
+    /// Go back to the start of the current frame, or of the frame before when already there.
+    /// Returns false when the rewind buffer has nothing older.
+    pub fn rewind_frame(&mut self) -> bool {
+        self.rewind_before(self.clock.cycles).is_some()
+    }
+
+    /// Undo the last instruction: go back to the frame it ran in and run that frame up to the instruction
+    /// before it again. Returns false when the rewind buffer does not reach back that far.
+    pub fn step_back(&mut self) -> bool {
+        let target = match self.clock.cycles.checked_sub(1) {
+            Some(target) => target,
+            None => return false,
+        };
+        if self.rewind_before(target + 1).is_none() {
+            return false;
+        }
+        // the instructions already ran once, the tracer, the watchpoints and the rewind buffer have seen them
+        let tracer = self.tracer.take();
+        let rewind = self.rewind.take();
+        let mut replayed = Ok(());
+        while self.clock.cycles < target && replayed.is_ok() {
+            replayed = self.step();
+        }
+        self.watch_hits.clear();
+        self.tracer = tracer;
+        self.rewind = rewind;
+        replayed.is_ok()
+    }
+
+    /// Restore the newest snapshot taken before instruction `cycles` and return its instruction count.
+    fn rewind_before(&mut self, cycles: u128) -> Option<u128> {
+        let rewind = self.rewind.as_mut()?;
+        let (snapshot_cycles, state) = rewind.rewind_to(cycles.checked_sub(1)?)?;
+        let state = state.to_vec();
+        self.restore_state(&state).ok()?;
+        Some(snapshot_cycles)
+    }
+
+    fn restart_rewind(&mut self) {
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.clear();
+        }
+        self.record_snapshot();
+    }
+
+    fn record_snapshot(&mut self) {
+        if self.rewind.is_some() {
+            let state = self.save_state();
+            if let Some(rewind) = self.rewind.as_mut() {
+                rewind.push(self.clock.cycles, state);
+            }
+        }
+    }
+
+    /// The machine as a save state, see `savestate` for the format.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.chunk(b"CART", |out| {
+            out.u16(self.cartridge.start_address);
+            out.u64(rom_fingerprint(&self.cartridge.rom));
+        });
+        state.chunk(b"CPU ", |out| self.cpu.write_state(out));
+        state.chunk(b"MEM ", |out| self.memory.write_state(out));
+        state.chunk(b"DISP", |out| self.display.write_state(out));
+        state.chunk(b"KEYS", |out| self.keyboard.write_state(out));
+        state.chunk(b"TIME", |out| {
+            out.u8(self.timers.delay);
+            out.u8(self.timers.sound);
+        });
+        state.chunk(b"SND ", |out| self.sound.write_state(out));
+        state.chunk(b"CLCK", |out| {
+            out.u64(self.clock.cycles as u64);
+            out.u64(self.clock.frames as u64);
+            out.u16(self.frame_cycles);
+        });
+        state.finish()
+    }
+
+    /// Restore a save state taken from the same cartridge. Nothing changes when it fails.
+    /// The rewind history starts over from the loaded state.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        self.restore_state(state)?;
+        self.restart_rewind();
+        Ok(())
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let chunks = StateChunks::parse(state)?;
+        let mut cartridge = chunks.require(b"CART")?;
+        if (cartridge.u16()?, cartridge.u64()?) != (self.cartridge.start_address, rom_fingerprint(&self.cartridge.rom)) {
+            return Err(String::from("save state was taken from a different ROM"));
+        }
+
+        let mut cpu = self.cpu.clone();
+        cpu.read_state(&mut chunks.require(b"CPU ")?)?;
+        let mut memory = self.memory.clone();
+        memory.read_state(&mut chunks.require(b"MEM ")?)?;
+        let mut display = self.display.clone();
+        display.read_state(&mut chunks.require(b"DISP")?)?;
+        let mut keyboard = self.keyboard.clone();
+        keyboard.read_state(&mut chunks.require(b"KEYS")?)?;
+        let mut time = chunks.require(b"TIME")?;
+        let timers = Timers { delay: time.u8()?, sound: time.u8()? };
+        // states from before XO-CHIP audio have no sound chunk
+        let mut sound = Sound::new();
+        if let Some(mut input) = chunks.get(b"SND ") {
+            sound.read_state(&mut input)?;
+        }
+        let mut clock = chunks.require(b"CLCK")?;
+        let (cycles, frames, frame_cycles) = (clock.u64()?, clock.u64()?, clock.u16()?);
+
+        self.cpu = cpu;
+        self.memory = memory;
+        self.display = display;
+        self.keyboard = keyboard;
+        self.timers = timers;
+        self.sound = sound;
+        self.clock.cycles = cycles as u128;
+        self.clock.frames = frames as u128;
+        self.clock.reset();
+        self.frame_cycles = frame_cycles;
+        Ok(())
+    }
+
+    fn step_cpu(&mut self) -> Result<(), EmulationError> {
+        // opcode, registers and timers before the instruction, when it is traced
+        let traced = self.tracer.as_ref().and_then(|tracer| {
+            let pc = self.cpu.registers().pc;
+            let opcode = (self.memory.read(pc).ok()? as u16) << 8 | self.memory.read(pc.wrapping_add(1)).ok()? as u16;
+            tracer.filter().accepts(pc, opcode).then(|| (opcode, self.cpu.registers().clone(), self.timers.clone()))
+        });
+        let result = self.execute();
+        if let (Some(tracer), Some((opcode, before, timers))) = (self.tracer.as_mut(), traced) {
+            tracer.record(TraceRecord::new(opcode, &before, self.cpu.registers()).with_timers(&timers, &self.timers));
+        }
+        result
+    }
+
+    fn execute(&mut self) -> Result<(), EmulationError> {
+        let mut bus = Bus::new(&mut self.memory, &mut self.display, &mut self.keyboard, &mut self.timers, &mut self.sound);
+        if self.watchpoints.is_empty() {
+            return self.cpu.step(&mut bus);
+        }
+
+        let pc = self.cpu.registers().pc;
+        self.accesses.clear();
+        let mut bus = bus.with_access_log(&mut self.accesses);
+        self.cpu.step(&mut bus)?;
+        let hits = self.watchpoints.check(pc, &self.accesses, self.cpu.registers(), &self.timers);
+        self.watch_hits.extend(hits);
+        Ok(())
+    }
+}
+
+/// FNV-1a of the ROM and its length, to tell which ROM a save state belongs to.
+fn rom_fingerprint(rom: &[u8]) -> u64 {
+    let hash = rom.iter().fold(0x811C_9DC5u32, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193));
+    (rom.len() as u64) << 32 | hash as u64
+}
+
 
-        self.cpu.run(&mut self.memory);
-        self.clock.first_run();
+/// Fixtures for the tests of the computer and the frontends that drive it.
+#[cfg(test)]
+pub mod test_support {
+    use super::{Bus, Computer, Cpu, EmulationError, Memory, StateWriter};
+    use crate::hardware::cartridge::Cartridge;
+    use crate::hardware::cpus::{instruction::Instruction, registers::Registers};
+    use crate::hardware::savestate::StateReader;
+
+    /// A computer that has been powered on with `rom` loaded at 0x200.
+    pub fn powered_on(rom: Vec<u8>) -> Computer {
+        let mut computer = Computer::new(Cartridge::new(rom));
+        computer.power_on().unwrap();
+        computer
+    }
+
+    /// A core that skips every instruction and counts them, it runs any ROM without `Chip8`.
+    #[derive(Clone)]
+    pub struct Skipper {
+        pub registers: Registers,
+        pub steps: u16,
+    }
+
+    impl Skipper {
+        pub fn new() -> Self {
+            Skipper { registers: Registers::new(), steps: 0 }
+        }
+    }
+
+    impl Cpu for Skipper {
+        fn step(&mut self, _bus: &mut Bus) -> Result<(), EmulationError> {
+            self.registers.increment_pc();
+            self.steps += 1;
+            Ok(())
+        }
+
+        fn reset(&mut self) {
+            self.registers = Registers::new();
+        }
+
+        fn set_pc(&mut self, pc: u16) {
+            self.registers.pc = pc;
+        }
+
+        fn registers(&self) -> &Registers {
+            &self.registers
+        }
+
+        fn registers_mut(&mut self) -> &mut Registers {
+            &mut self.registers
+        }
+
+        fn is_waiting_for_key(&self) -> bool {
+            false
+        }
+
+        fn has_exited(&self) -> bool {
+            false
+        }
+
+        fn take_display_wait(&mut self) -> bool {
+            false
+        }
+
+        // there is no instruction set to decode, a debugger shows the bytes
+        fn instruction_at(&self, _memory: &Memory, _address: u16) -> Option<Instruction> {
+            None
+        }
+
+        fn write_state(&self, out: &mut StateWriter) {
+            out.u16(self.registers.pc);
+            out.u16(self.steps);
+        }
+
+        fn read_state(&mut self, input: &mut StateReader) -> Result<(), String> {
+            self.registers.pc = input.u16()?;
+            self.steps = input.u16()?;
+            Ok(())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_support::{powered_on, Skipper};
+    use crate::hardware::trace::{OpcodeClass, TraceFilter};
+    use crate::hardware::watch::{AccessKind, Trigger};
+
+    #[test]
+    fn test_memory_watchpoint() {
+        // LD I, 0x300; LD V0, 0x2A; LD [I], V0; LD V1, [I]
+        let mut computer = powered_on(vec![0xA3, 0x00, 0x60, 0x2A, 0xF0, 0x55, 0xF1, 0x65]);
+        let write = computer.add_watchpoint("write 0x300-0x30F".parse().unwrap());
+        let read = computer.add_watchpoint("read 0x301".parse().unwrap());
+        for _ in 0..3 {
+            computer.step().unwrap();
+        }
+        let hits = computer.take_watch_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id, write);
+        assert_eq!(hits[0].pc, 0x204);
+        assert!(matches!(hits[0].trigger, Trigger::Access(access) if access.kind == AccessKind::Write && access.value == 0x2A));
+
+        computer.step().unwrap();
+        let hits = computer.take_watch_hits();
+        assert_eq!((hits.len(), hits[0].id), (1, read));
+    }
+
+    #[test]
+    fn test_index_and_condition_watchpoints() {
+        // LD I, 0x300; LD V3, 0x10; LD I, 0x200
+        let mut computer = powered_on(vec![0xA3, 0x00, 0x63, 0x10, 0xA2, 0x00]);
+        let index = computer.add_watchpoint("i 0x300-0x3FF".parse().unwrap());
+        let condition = computer.add_watchpoint("V3 == 0x10 && DT == 0".parse().unwrap());
+        computer.step().unwrap();
+        assert_eq!(computer.take_watch_hits().iter().map(|hit| hit.id).collect::<Vec<_>>(), vec![index]);
+        computer.step().unwrap();
+        assert_eq!(computer.take_watch_hits().iter().map(|hit| hit.id).collect::<Vec<_>>(), vec![condition]);
+
+        assert!(computer.remove_watchpoint(condition));
+        computer.step().unwrap();
+        assert!(computer.take_watch_hits().is_empty());
+    }
+
+    #[test]
+    fn test_save_state() {
+        // RND V0, 0xFF; LD I, 0x300; LD [I], V0; DRW V0, V0, 1; JP 0x200
+        let rom = vec![0xC0, 0xFF, 0xA3, 0x00, 0xF0, 0x55, 0xD0, 0x01, 0x12, 0x00];
+        let mut computer = powered_on(rom.clone());
+        computer.keyboard.press(7);
+        computer.timers.delay = 42;
+        computer.run().unwrap();
+        let state = computer.save_state();
+        let saved = (computer.cpu.registers().clone(), computer.display.pixels().to_vec(), computer.clock.cycles);
+
+        computer.run().unwrap();
+        computer.load_state(&state).unwrap();
+        let registers = computer.cpu.registers();
+        assert_eq!((registers.v, registers.i, registers.pc), (saved.0.v, saved.0.i, saved.0.pc));
+        assert_eq!(computer.display.pixels(), &saved.1[..]);
+        assert_eq!(computer.clock.cycles, saved.2);
+        assert_eq!(computer.timers.delay, 41);
+        assert!(computer.keyboard.is_pressed(7));
+
+        // the random numbers after a load are the ones that came after the save
+        let mut other = powered_on(rom);
+        other.load_state(&state).unwrap();
+        for _ in 0..3 {
+            computer.run().unwrap();
+            other.run().unwrap();
+            assert_eq!(computer.cpu.registers().v, other.cpu.registers().v);
+            assert_eq!(computer.memory.read(0x300), other.memory.read(0x300));
+        }
+
+        let mut different = powered_on(vec![0x12, 0x00]);
+        assert!(different.load_state(&state).unwrap_err().contains("different ROM"));
+        assert!(different.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(different.cpu.registers().pc, 0x200);
+    }
+
+    #[test]
+    fn test_save_state_hires() {
+        // HIGH; LD V0, 0x7F; DRW V0, V0, 5; LD R, V0; JP 0x208
+        let mut computer = powered_on(vec![0x00, 0xFF, 0x60, 0x7F, 0xD0, 0x05, 0xF0, 0x75, 0x12, 0x08]);
+        computer.run().unwrap();
+        computer.run().unwrap();
+        let state = computer.save_state();
+
+        let mut other = powered_on(vec![0x00, 0xFF, 0x60, 0x7F, 0xD0, 0x05, 0xF0, 0x75, 0x12, 0x08]);
+        other.load_state(&state).unwrap();
+        assert!(other.display.is_hires());
+        assert_eq!(other.display.pixels(), computer.display.pixels());
+        assert_eq!(other.save_state(), state);
     }
 
-    pub fn run(&mut self) {
-        self.clock.run();
-        //if self.clock.cycles != self.last_cycle {
-        self.cpu.run(&mut self.memory);
-        //    self.last_cycle = self.clock.cycles;
-        //}
+    #[test]
+    fn test_save_state_sound() {
+        // LD I, 0x200; AUDIO; LD V0, 80; LD PITCH, V0; JP 0x208
+        let rom = vec![0xA2, 0x00, 0xF0, 0x02, 0x60, 0x50, 0xF0, 0x3A, 0x12, 0x08];
+        let mut computer = powered_on(rom.clone());
+        computer.run().unwrap();
+        let state = computer.save_state();
+
+        let mut other = powered_on(rom);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.sound, computer.sound);
+        assert_eq!(other.sound.pitch, 80);
+        other.power_on().unwrap();
+        assert_eq!(other.sound, Sound::new());
+    }
+
+    #[test]
+    fn test_power_on_rom_too_large() {
+        let mut computer = Computer::new(Cartridge::new(vec![0; 0x10]).with_start_address(0xFF8));
+        assert_eq!(computer.power_on(), Err(EmulationError::RomTooLarge { address: 0xFF8, size: 0x10 }));
+    }
+
+    #[test]
+    fn test_other_cpu() {
+        let cpu = Skipper::new();
+        let mut computer = Computer::with_cpu(Cartridge::new(vec![0x00, 0x00]), cpu);
+        computer.power_on().unwrap();
+        computer.clock.instructions_per_frame = 5;
+        computer.run().unwrap();
+        let state = computer.save_state();
+        assert_eq!((computer.cpu.registers().pc, computer.cpu.steps), (0x20A, 5));
+        assert_eq!(computer.cpu.instruction_at(&computer.memory, 0x200), None);
+
+        computer.run().unwrap();
+        computer.load_state(&state).unwrap();
+        assert_eq!((computer.cpu.registers().pc, computer.cpu.steps), (0x20A, 5));
+    }
+
+    #[test]
+    fn test_rewind() {
+        // LD V0, 0; ADD V0, 1; RND V1, 0xFF; LD I, 0x300; LD [I], V1; JP 0x202
+        let mut computer = powered_on(vec![0x60, 0x00, 0x70, 0x01, 0xC1, 0xFF, 0xA3, 0x00, 0xF1, 0x55, 0x12, 0x02]);
+        computer.clock.instructions_per_frame = 7;
+        assert!(!computer.step_back());
+        computer.set_rewind(Some(Rewind::new(60)));
+        let mut history = Vec::new();
+        for _ in 0..30 {
+            history.push((computer.clock.cycles, computer.cpu.registers().clone(), computer.memory.read(0x300).unwrap()));
+            computer.step().unwrap();
+        }
+        assert_eq!(computer.rewind().unwrap().len(), 5);
+        let end = computer.cpu.registers().v;
+
+        // instruction by instruction back to the start, random numbers and memory included
+        for (cycles, registers, memory) in history.iter().rev() {
+            assert!(computer.step_back());
+            assert_eq!(computer.clock.cycles, *cycles);
+            assert_eq!(computer.cpu.registers().v, registers.v);
+            assert_eq!(computer.cpu.registers().pc, registers.pc);
+            assert_eq!(computer.memory.read(0x300).unwrap(), *memory);
+        }
+        assert!(!computer.step_back());
+
+        for _ in 0..30 {
+            computer.step().unwrap();
+        }
+        assert_eq!(computer.cpu.registers().v, end);
+        assert!(computer.rewind_frame());
+        assert_eq!(computer.clock.cycles, 28);
+        assert!(computer.rewind_frame());
+        assert_eq!(computer.clock.cycles, 21);
+    }
+
+    #[test]
+    fn test_trace() {
+        // LD I, 0x300; LD V0, 0x2A; LD [I], V0; CALL 0x20A; JP 0x208; RET
+        let mut computer = powered_on(vec![0xA3, 0x00, 0x60, 0x2A, 0xF0, 0x55, 0x22, 0x0A, 0x12, 0x08, 0x00, 0xEE]);
+        let filter = TraceFilter::new().with_classes(vec![OpcodeClass::Load, OpcodeClass::Flow]);
+        assert!(computer.set_tracer(Some(Tracer::ring(16).with_filter(filter))).is_none());
+        for _ in 0..6 {
+            computer.step().unwrap();
+        }
+        let records: Vec<String> = computer.tracer().unwrap().records().map(|record| record.to_string()).collect();
+        assert_eq!(records, vec![
+            "200: A300  LD I, 0x300          I=0300",
+            "202: 602A  LD V0, 0x2A          V0=2A",
+            "206: 220A  CALL 0x20A           SP=01",
+            "20A: 00EE  RET                  SP=00",
+            "208: 1208  JP 0x208",
+        ]);
     }
 }
@@ -1,32 +1,55 @@
-use crate::hardware::memory::Memory;
+use crate::hardware::{bus::Bus, error::EmulationError, memory::{Memory, BIG_FONT_ADDRESS}};
+use crate::hardware::savestate::{StateReader, StateWriter};
+use crate::hardware::sound::PATTERN_SIZE;
+use super::Cpu;
+use super::instruction::Instruction;
+use super::quirks::{IndexIncrement, Quirks};
+use super::registers::Registers;
 
 #[derive(Clone)]
 pub struct Chip8 {
     registers: Registers,
+    waiting_for_key: bool,
+    rng: Rng,
+    quirks: Quirks,
+    // set by a DRW when the display wait quirk is on, the computer then ends the frame
+    waiting_for_display: bool,
+    // SUPER-CHIP's RPL user flags, they survive a reset like the HP-48 calculator's did
+    flags: [u8; 16],
+    exited: bool,
 }
 
 impl Chip8 {
     pub fn new() -> Self {
         Chip8 {
             registers: Registers::new(),
+            waiting_for_key: false,
+            rng: Rng::new(),
+            quirks: Quirks::vip(),
+            waiting_for_display: false,
+            flags: [0; 16],
+            exited: false,
         }
     }
 
-    pub fn run(&mut self, memory: &mut Memory) {
-        //get random between 512 and 4095
-        //let random_address = rand::random::<u16>() % 3583 + 512;
-        //let random_value = rand::random::<u8>();
-        //memory.write(random_address, random_value);
-        // print!(".");
-        // let op_code: u16 = memory.read(self.registers.pc) as u;
-        // self.registers.increment_pc();
-        // println!("op_code: {:04X}", op_code);
-        let op_code: u16 = self.get_op_code(memory);
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Skip the next instruction, which on XO-CHIP can be the 4 bytes of F000 nnnn.
+    fn skip(&mut self, memory: &Memory) {
+        if self.get_op_code(memory).is_ok_and(Instruction::is_long) {
+            self.registers.increment_pc();
+        }
         self.registers.increment_pc();
-        self.execute_op_code(op_code, memory);
     }
 
-    fn get_op_code(&self, memory: &Memory) -> u16 {
+    fn get_op_code(&self, memory: &Memory) -> Result<u16, EmulationError> {
         // read 2 bytes from memory and combine them into a single u16
         // opcodes are 2 bytes long
         // shift the first byte 8 bits to the left and combine it with the second byte
@@ -34,286 +57,708 @@ impl Chip8 {
         // not sure about endianness, perhaps I am overthinking but hexdump shows the bytes in reverse order
         //(memory.read(self.registers.pc+1) as u16) << 8 | memory.read(self.registers.pc) as u16
         // I was overthinking, the bytes are in the correct order
-        (memory.read(self.registers.pc) as u16) << 8 | memory.read(self.registers.pc+1) as u16
-    }
-
-    fn execute_op_code(&mut self, op_code: u16, memory: &mut Memory) {
-        // skip copilot
-        //println!("op_code: {:04X} binary: {:#018b}", &op_code, &op_code);
-        match op_code & 0xF000 {
-            // bitwise AND on the first 4 bits of the op_code 1111 0000 0000 0000
-            0x0000 => { // first 4 bits are 0
-                match op_code & 0x00FF { // bitwise AND on the last 8 bits of the op_code 0000 0000 1111 1111
-                    0x00E0 => { // 0000 0000 1110 0000
-                        println!("CLS");
-                    },
-                    0x00EE => { // 0000 0000 1110 1110
-                        println!("RET");
-                    },
-                    _ => {
-                        println!("SYS addr: {:014b}", op_code & 0x0FFF); 
-                    },
+        Ok((memory.read(self.registers.pc)? as u16) << 8 | memory.read(self.registers.pc.wrapping_add(1))? as u16)
+    }
+
+    // address of the instruction being executed, the pc has already moved past it
+    fn op_code_address(&self) -> u16 {
+        self.registers.pc.wrapping_sub(2)
+    }
+
+    fn execute_op_code(&mut self, op_code: u16, next: u16, bus: &mut Bus) -> Result<(), EmulationError> {
+        match Instruction::decode_long(op_code, next) {
+            Some(instruction) => self.execute(instruction, bus),
+            None => Err(EmulationError::UnknownOpcode { opcode: op_code, address: self.op_code_address() }),
+        }
+    }
+
+    fn execute(&mut self, instruction: Instruction, bus: &mut Bus) -> Result<(), EmulationError> {
+        let v = &mut self.registers.v;
+        match instruction {
+            Instruction::Sys(_) => {
+                // machine code routines of the original interpreters, ignored
+            },
+            Instruction::Cls => {
+                bus.display.clear();
+            },
+            Instruction::ScrollDown(n) => {
+                bus.display.scroll(0, n as isize);
+            },
+            Instruction::ScrollUp(n) => {
+                bus.display.scroll(0, -(n as isize));
+            },
+            Instruction::ScrollRight => {
+                bus.display.scroll(4, 0);
+            },
+            Instruction::ScrollLeft => {
+                bus.display.scroll(-4, 0);
+            },
+            Instruction::Exit => {
+                // stay on the EXIT, the frontend stops once it sees the program has exited
+                self.exited = true;
+                self.registers.pc = self.registers.pc.wrapping_sub(2);
+            },
+            Instruction::LowRes => {
+                bus.display.set_hires(false);
+            },
+            Instruction::HighRes => {
+                bus.display.set_hires(true);
+            },
+            Instruction::Ret => {
+                if self.registers.sp == 0 {
+                    return Err(EmulationError::StackUnderflow { address: self.op_code_address() });
                 }
+                self.registers.sp -= 1;
+                self.registers.pc = self.registers.stack[self.registers.sp as usize];
             },
-            0x1000 => {
-                // println!("JP addr: {:014b}", op_code & 0x0FFF);
-                // println!("JP addr: {:04X}, ", op_code & 0x0FFF);
-                // 1nnn - JP addr -- Jump to location nnn.
-                self.registers.pc = op_code & 0x0FFF;
+            Instruction::Jump(addr) => {
+                self.registers.pc = addr;
             },
-            0x2000 => {
-                println!("CALL addr: {:014b}", op_code & 0x0FFF);
-                println!("CALL addr: {:04X}, ", op_code & 0x0FFF);
-                // 2nnn - CALL addr -- Call subroutine at nnn.
+            Instruction::Call(addr) => {
+                if self.registers.sp as usize == self.registers.stack.len() {
+                    return Err(EmulationError::StackOverflow { address: self.op_code_address() });
+                }
                 self.registers.stack[self.registers.sp as usize] = self.registers.pc;
                 self.registers.sp += 1;
-                self.registers.pc = op_code & 0x0FFF;
-            },
-            0x3000 => {
-                // 3xkk - SE Vx, byte -- Skip next instruction if Vx = kk.
-                let x = ((op_code & 0x0F00) >> 8) as u8;
-                let kk = (op_code & 0x00FF) as u8;
-                if self.registers.v[x as usize] == kk {
-                    self.registers.increment_pc();
+                self.registers.pc = addr;
+            },
+            Instruction::SkipEqualByte { x, byte } => {
+                if v[x as usize] == byte {
+                    self.skip(bus.memory);
                 }
             },
-            0x4000 => {
-                // 4xkk - SNE Vx, byte -- Skip next instruction if Vx != kk.
-                let x = ((op_code & 0x0F00) >> 8) as u8;
-                let kk = (op_code & 0x00FF) as u8;
-                if self.registers.v[x as usize] != kk {
-                    self.registers.increment_pc();
+            Instruction::SkipNotEqualByte { x, byte } => {
+                if v[x as usize] != byte {
+                    self.skip(bus.memory);
                 }
             },
-            0x5000 => {
-                // 5xy0 - SE Vx, Vy -- Skip next instruction if Vx = Vy.
-                let x = ((op_code & 0x0F00) >> 8) as u8;
-                let y = ((op_code & 0x00F0) >> 4) as u8;
-                if self.registers.v[x as usize] == self.registers.v[y as usize] {
-                    self.registers.increment_pc();
+            Instruction::SkipEqual { x, y } => {
+                if v[x as usize] == v[y as usize] {
+                    self.skip(bus.memory);
                 }
             },
-            0x6000 => {
-                // 6xkk - LD Vx, byte -- Set Vx = kk.
-                let x = ((op_code & 0x0F00) >> 8) as u8;
-                let kk = (op_code & 0x00FF) as u8;
-                self.registers.v[x as usize] = kk;
-            },
-            0x7000 => {
-                // 7xkk - ADD Vx, byte -- Set Vx = Vx + kk.
-                let x = ((op_code & 0x0F00) >> 8) as u8;
-                let kk = (op_code & 0x00FF) as u8;
-                self.registers.v[x as usize] += kk;
-            },
-            0x8000 => {
-                let x = ((op_code & 0x0F00) >> 8) as u8;
-                let y = ((op_code & 0x00F0) >> 4) as u8;
-                match op_code & 0x000F {
-                    0x0000 => {
-                        //8xy0 - LD Vx, Vy -- Set Vx = Vy.
-                        self.registers.v[x as usize] = self.registers.v[y as usize];
-                    },
-                    0x0001 => {
-                        // 8xy1 - OR Vx, Vy -- Set Vx = Vx OR Vy.
-                        self.registers.v[x as usize] |= self.registers.v[y as usize];
-                    },
-                    0x0002 => {
-                        // 8xy2 - AND Vx, Vy -- Set Vx = Vx AND Vy.
-                        self.registers.v[x as usize] &= self.registers.v[y as usize];
-                    },
-                    0x0003 => {
-                        // 8xy3 - XOR Vx, Vy -- Set Vx = Vx XOR Vy.
-                        self.registers.v[x as usize] ^= self.registers.v[y as usize];
-                    },
-                    0x0004 => {
-                        // 8xy4 - ADD Vx, Vy - Set Vx = Vx + Vy, set VF = carry.
-                        // 0XFF is the maximum value for a byte aka 255
-                        if self.registers.v[y as usize] > (0xFF - self.registers.v[x as usize]) {
-                            self.registers.v[0xF] = 1;
-                        } else {
-                            self.registers.v[0xF] = 0;
-                        }
-                        self.registers.v[x as usize] += self.registers.v[y as usize];
-
-                    },
-                    0x0005 => {
-                        // 8xy5 - SUB Vx, Vy - Set Vx = Vx - Vy, set VF = NOT borrow.
-                        if self.registers.v[y as usize] > self.registers.v[x as usize] {
-                            self.registers.v[0xF] = 0;
-                        } else {
-                            self.registers.v[0xF] = 1;
-                        }
-                        self.registers.v[x as usize] -= self.registers.v[y as usize];
-                    },
-                    0x0006 => {
-                        // 8xy6 - SHR Vx {, Vy} - Set Vx = Vx SHR 1.
-                        self.registers.v[0xF] = self.registers.v[x as usize] & 0x1; // get the least significant bit
-                        self.registers.v[x as usize] >>= 1; // divide by 2 aka shift right by 1
-                    },
-                    0x0007 => {
-                        // 8xy7 - SUBN Vx, Vy - Set Vx = Vy - Vx, set VF = NOT borrow.
-                        if self.registers.v[y as usize] > self.registers.v[x as usize] { // If Vy > Vx, then VF is set to 1
-                            self.registers.v[0xF] = 1;
-                        } else {
-                            self.registers.v[0xF] = 0;
-                        }
-                        self.registers.v[x as usize] = self.registers.v[y as usize] - self.registers.v[x as usize];
-                    },
-                    0x000E => {
-                        // 8xyE - SHL Vx {, Vy} - Set Vx = Vx SHL 1.
-                        self.registers.v[0xF] = self.registers.v[x as usize] >> 7; // get the most significant bit
-                        self.registers.v[x as usize] <<= 1; // multiply by 2 aka shift left by 1
-                    },
-                    _ => {
-                        println!("we should not see this: Unknown opcode: {:04X}", op_code);
-                    },
+            Instruction::StoreRange { x, y } => {
+                for (offset, register) in register_range(x, y).enumerate() {
+                    bus.write(self.registers.i.wrapping_add(offset as u16), v[register])?;
                 }
             },
-            0x9000 => {
-                // 9xy0 - SNE Vx, Vy -- Skip next instruction if Vx != Vy.
-                let x = ((op_code & 0x0F00) >> 8) as u8;
-                let y = ((op_code & 0x00F0) >> 4) as u8;
-                if self.registers.v[x as usize] != self.registers.v[y as usize] {
-                    self.registers.increment_pc();
+            Instruction::LoadRange { x, y } => {
+                for (offset, register) in register_range(x, y).enumerate() {
+                    v[register] = bus.read(self.registers.i.wrapping_add(offset as u16))?;
                 }
             },
-            0xA000 => {
-                //Annn - LD I, addr -- Set I = nnn.
-                self.registers.i = op_code & 0x0FFF;
-            },
-            0xB000 => {
-                //Bnnn - JP V0, addr -- Jump to location nnn + V0.
-                self.registers.pc = (op_code & 0x0FFF) + self.registers.v[0] as u16;
-            },
-            0xC000 => {
-                //Cxkk - RND Vx, byte -- Set Vx = random byte AND kk.
-                let x = ((op_code & 0x0F00) >> 8) as u8;
-                let kk = (op_code & 0x00FF) as u8;
-                self.registers.v[x as usize] = rand::random::<u8>() & kk;
-            },
-            0xD000 => { // Drawing
-                //println!("DRW Vx, Vy, nibble");
-                //Dxyn - DRW Vx, Vy, nibble -- Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
-                let x = ((op_code & 0x0F00) >> 8) as u8;
-                let y = ((op_code & 0x00F0) >> 4) as u8;
-                let height = (op_code & 0x000F) as u8;
-                let mut collision = false;
-                let mut line: u8 = 0;
-                // Todo: implement drawing
-                while line <= height {
-                    let pixels = memory.read(self.registers.i + line); // row of pixels
-                    for bit in 0..8 {
-                        if (pixels & (0x80 >> bit)) != 0 {
-                            // this gets me the bit at the position - not sure what to do with it yet
-                            // need to read more
-                        }
-                    }
-                    line += 1;
+            Instruction::LoadByte { x, byte } => {
+                v[x as usize] = byte;
+            },
+            Instruction::AddByte { x, byte } => {
+                // no carry flag for this one, the result simply wraps around
+                v[x as usize] = v[x as usize].wrapping_add(byte);
+            },
+            Instruction::Load { x, y } => {
+                v[x as usize] = v[y as usize];
+            },
+            Instruction::Or { x, y } => {
+                v[x as usize] |= v[y as usize];
+                if self.quirks.logic_resets_vf {
+                    v[0xF] = 0;
                 }
             },
-            0xE000 => { // Keyboard input
-                match op_code & 0x00FF {
-                    0x009E => {
-                        // Ex9E - SKP Vx -- Skip next instruction if key with the value of Vx is pressed.
-                        // TODO: implement
-                    },
-                    0x00A1 => {
-                        // ExA1 - SKNP Vx -- Skip next instruction if key with the value of Vx is not pressed.
-                        // TODO: implement
-                    },
-                    _ => {
-                        println!("Unknown opcode: {:04X}", op_code);
-                    },
+            Instruction::And { x, y } => {
+                v[x as usize] &= v[y as usize];
+                if self.quirks.logic_resets_vf {
+                    v[0xF] = 0;
                 }
             },
-            0xF000 => {
-                // all these op codes use Vx - getting the x value here means less repetition of bitwise AND
-                // hopefully making it easier to read
-                let x = ((op_code & 0x0F00) >> 8) as usize;
-                match op_code & 0x00FF {
-                    0x0007 => {
-                        // Fx07 - LD Vx, DT -- Set Vx = delay timer value.
-                        self.registers.v[x] = self.registers.delay_timer;
-                    },
-                    0x000A => {
-                        // Fx0A - LD Vx, K -- Wait for a key press, store the value of the key in Vx.
-                        // TODO: implement
-                    },
-                    0x0015 => {
-                        // Fx15 - LD DT, Vx -- Set delay timer = Vx.
-                        self.registers.delay_timer = self.registers.v[x];
-                    },
-                    0x0018 => {
-                        // Fx18 - LD ST, Vx -- Set sound timer = Vx.
-                        self.registers.sound_timer = self.registers.v[x];
-                    },
-                    0x001E => {
-                        // Fx1E - ADD I, Vx -- Set I = I + Vx.
-                        self.registers.i += self.registers.v[x] as u16;
-                    },
-                    0x0029 => {
-                        // Fx29 - LD F, Vx -- Set I = location of sprite for digit Vx.
-                        self.registers.i = self.registers.v[x] as u16 * 5; // each sprite is 5 bytes long and starts at 0x000
-                    },
-                    0x0033 => {
-                        // Fx33 - LD B, Vx -- Store BCD representation of Vx in memory locations I, I+1, and I+2.
-                        // TODO: implement
-                    },
-                    0x0055 => {
-                        // Fx55 - LD [I], Vx -- Store registers V0 through Vx in memory starting at location I.
-                        for i in 0..=x {
-                            memory.write(self.registers.i + i as u16, self.registers.v[i]);
-                        }
-                    },
-                    0x0065 => {
-                        // Fx65 - LD Vx, [I] -- Read registers V0 through Vx from memory starting at location I.
-                        for i in 0..=x {
-                            self.registers.v[i] = memory.read(self.registers.i + i as u16);
-                        }
+            Instruction::Xor { x, y } => {
+                v[x as usize] ^= v[y as usize];
+                if self.quirks.logic_resets_vf {
+                    v[0xF] = 0;
+                }
+            },
+            Instruction::Add { x, y } => {
+                // VF is set after the result so that it wins when x is F
+                let (result, carry) = v[x as usize].overflowing_add(v[y as usize]);
+                v[x as usize] = result;
+                v[0xF] = carry as u8;
+            },
+            Instruction::Sub { x, y } => {
+                let (result, borrow) = v[x as usize].overflowing_sub(v[y as usize]);
+                v[x as usize] = result;
+                v[0xF] = !borrow as u8;
+            },
+            Instruction::ShiftRight { x, y } => {
+                let value = if self.quirks.shift_uses_vy { v[y as usize] } else { v[x as usize] };
+                let lsb = value & 0x1; // get the least significant bit
+                v[x as usize] = value >> 1; // divide by 2 aka shift right by 1
+                v[0xF] = lsb;
+            },
+            Instruction::SubN { x, y } => {
+                let (result, borrow) = v[y as usize].overflowing_sub(v[x as usize]);
+                v[x as usize] = result;
+                v[0xF] = !borrow as u8;
+            },
+            Instruction::ShiftLeft { x, y } => {
+                let value = if self.quirks.shift_uses_vy { v[y as usize] } else { v[x as usize] };
+                let msb = value >> 7; // get the most significant bit
+                v[x as usize] = value << 1; // multiply by 2 aka shift left by 1
+                v[0xF] = msb;
+            },
+            Instruction::SkipNotEqual { x, y } => {
+                if v[x as usize] != v[y as usize] {
+                    self.skip(bus.memory);
+                }
+            },
+            Instruction::LoadI(addr) | Instruction::LoadILong(addr) => {
+                self.registers.i = addr;
+            },
+            Instruction::JumpV0(addr) => {
+                // CHIP-48 read the register from the top nibble of the address, Bxnn
+                let register = if self.quirks.jump_uses_vx { (addr >> 8) as usize & 0xF } else { 0 };
+                self.registers.pc = addr + v[register] as u16;
+            },
+            Instruction::Random { x, byte } => {
+                v[x as usize] = self.rng.next_u8() & byte;
+            },
+            Instruction::Draw { x, y, n } => {
+                // read the n rows of the sprite, one byte per row, starting at I,
+                // or for Dxy0 the 16 rows of two bytes of a 16x16 sprite, for each selected plane.
+                // The VIP has no large sprites, its Dxy0 draws no rows at all
+                let large = n == 0 && (bus.display.is_hires() || self.quirks.large_sprites);
+                let size = if large { 32 } else { n as u16 } * bus.display.plane_count() as u16;
+                let sprite = (0..size)
+                    .map(|line| bus.read(self.registers.i.wrapping_add(line)))
+                    .collect::<Result<Vec<u8>, EmulationError>>()?;
+                let (x, y, clip) = (v[x as usize], v[y as usize], self.quirks.clip_sprites);
+                let collision = match large {
+                    true => bus.display.draw_large_sprite(x, y, &sprite, clip),
+                    false => bus.display.draw_sprite(x, y, &sprite, clip),
+                };
+                v[0xF] = collision as u8;
+                self.waiting_for_display = self.quirks.display_wait;
+            },
+            Instruction::SkipKeyPressed { x } => {
+                if bus.keyboard.is_pressed(v[x as usize]) {
+                    self.skip(bus.memory);
+                }
+            },
+            Instruction::SkipKeyNotPressed { x } => {
+                if !bus.keyboard.is_pressed(v[x as usize]) {
+                    self.skip(bus.memory);
+                }
+            },
+            Instruction::SelectPlanes(n) => {
+                bus.display.select_planes(n);
+            },
+            Instruction::LoadPattern => {
+                let mut pattern = [0; PATTERN_SIZE];
+                for (offset, sample) in pattern.iter_mut().enumerate() {
+                    *sample = bus.read(self.registers.i.wrapping_add(offset as u16))?;
+                }
+                bus.sound.pattern = Some(pattern);
+            },
+            Instruction::LoadDelay { x } => {
+                v[x as usize] = bus.timers.delay;
+            },
+            Instruction::WaitKey { x } => {
+                // All execution stops until a key is pressed and released, rewinding the pc
+                // re-runs this instruction on the next cycle while the timers keep counting down.
+                if !self.waiting_for_key {
+                    bus.keyboard.begin_wait();
+                    self.waiting_for_key = true;
+                }
+                match bus.keyboard.released_key() {
+                    Some(key) => {
+                        v[x as usize] = key;
+                        self.waiting_for_key = false;
                     },
-                    _ => {
-                        println!("Unknown opcode: {:04X}", op_code);
+                    None => {
+                        self.registers.pc = self.registers.pc.wrapping_sub(2);
                     },
                 }
             },
-            _ => {
-                println!("Unknown opcode: {:04X}", op_code);
+            Instruction::SetDelay { x } => {
+                bus.timers.delay = v[x as usize];
+            },
+            Instruction::SetSound { x } => {
+                bus.timers.sound = v[x as usize];
+            },
+            Instruction::SetPitch { x } => {
+                bus.sound.pitch = v[x as usize];
+            },
+            Instruction::AddI { x } => {
+                self.registers.i = self.registers.i.wrapping_add(v[x as usize] as u16);
+            },
+            Instruction::LoadFont { x } => {
+                self.registers.i = (v[x as usize] & 0xF) as u16 * 5; // each sprite is 5 bytes long and starts at 0x000
+            },
+            Instruction::LoadBigFont { x } => {
+                self.registers.i = BIG_FONT_ADDRESS + (v[x as usize] & 0xF) as u16 * 10;
+            },
+            Instruction::StoreBcd { x } => {
+                let value = v[x as usize];
+                bus.write(self.registers.i, value / 100)?; // hundreds
+                bus.write(self.registers.i.wrapping_add(1), (value / 10) % 10)?; // tens
+                bus.write(self.registers.i.wrapping_add(2), value % 10)?; // ones
+            },
+            Instruction::StoreRegisters { x } => {
+                for (offset, value) in v[..=x as usize].iter().enumerate() {
+                    bus.write(self.registers.i.wrapping_add(offset as u16), *value)?;
+                }
+                self.advance_index(x);
+            },
+            Instruction::LoadRegisters { x } => {
+                for (offset, value) in v[..=x as usize].iter_mut().enumerate() {
+                    *value = bus.read(self.registers.i.wrapping_add(offset as u16))?;
+                }
+                self.advance_index(x);
+            },
+            Instruction::StoreFlags { x } => {
+                self.flags[..=x as usize].copy_from_slice(&v[..=x as usize]);
+            },
+            Instruction::LoadFlags { x } => {
+                v[..=x as usize].copy_from_slice(&self.flags[..=x as usize]);
             },
         }
+        Ok(())
+    }
 
+    /// Where Fx55 and Fx65 leave I depends on the interpreter.
+    fn advance_index(&mut self, x: u8) {
+        let i = &mut self.registers.i;
+        match self.quirks.load_store {
+            IndexIncrement::Increment => *i = i.wrapping_add(x as u16 + 1),
+            IndexIncrement::IncrementX => *i = i.wrapping_add(x as u16),
+            IndexIncrement::Unchanged => {},
+        }
     }
+}
 
-    pub fn reset(&mut self) {
+impl Cpu for Chip8 {
+    fn step(&mut self, bus: &mut Bus) -> Result<(), EmulationError> {
+        let op_code: u16 = self.get_op_code(bus.memory)?;
+        self.registers.increment_pc();
+        let next = match Instruction::is_long(op_code) {
+            true => {
+                let next = self.get_op_code(bus.memory)?;
+                self.registers.increment_pc();
+                next
+            },
+            false => 0,
+        };
+        self.execute_op_code(op_code, next, bus)
+    }
+
+    fn reset(&mut self) {
         self.registers = Registers::new();
+        self.waiting_for_key = false;
+        self.waiting_for_display = false;
+        self.exited = false;
+    }
+
+    /// True once the program ran 00FD to exit the interpreter.
+    fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    /// True once after a DRW that has to wait for the display, the rest of the frame is skipped.
+    fn take_display_wait(&mut self) -> bool {
+        std::mem::take(&mut self.waiting_for_display)
+    }
+
+    fn set_pc(&mut self, pc: u16) {
+        self.registers.pc = pc;
+    }
+
+    /// Register state, for debuggers and frontends.
+    fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    /// Register state for debuggers that change it between instructions.
+    fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    /// True while Fx0A holds the program until a key is pressed and released.
+    fn is_waiting_for_key(&self) -> bool {
+        self.waiting_for_key
+    }
+
+    /// Decoded like `step` would, the 4 bytes of F000 nnnn are one instruction.
+    fn instruction_at(&self, memory: &Memory, address: u16) -> Option<Instruction> {
+        let word = |address: u16| -> Option<u16> {
+            Some((memory.read(address).ok()? as u16) << 8 | memory.read(address.wrapping_add(1)).ok()? as u16)
+        };
+        let op_code = word(address)?;
+        match Instruction::is_long(op_code) {
+            true => Instruction::decode_long(op_code, word(address.wrapping_add(2))?),
+            false => Instruction::decode(op_code),
+        }
+    }
+
+    fn write_state(&self, out: &mut StateWriter) {
+        let registers = &self.registers;
+        out.bytes(&registers.v);
+        out.u16(registers.i);
+        out.u16(registers.pc);
+        out.u8(registers.sp);
+        for address in registers.stack {
+            out.u16(address);
+        }
+        out.u8(self.waiting_for_key as u8);
+        out.u64(self.rng.state);
+        out.bytes(&self.flags);
+        out.u8(self.exited as u8);
+    }
+
+    fn read_state(&mut self, input: &mut StateReader) -> Result<(), String> {
+        let registers = &mut self.registers;
+        registers.v.copy_from_slice(input.bytes(16)?);
+        registers.i = input.u16()?;
+        registers.pc = input.u16()?;
+        registers.sp = input.u8()?.min(registers.stack.len() as u8);
+        for address in registers.stack.iter_mut() {
+            *address = input.u16()?;
+        }
+        self.waiting_for_key = input.u8()? != 0;
+        self.rng = Rng::with_state(input.u64()?);
+        // added after the first version of the format
+        if !input.is_empty() {
+            self.flags.copy_from_slice(input.bytes(16)?);
+            self.exited = input.u8()? != 0;
+        }
+        Ok(())
     }
 }
 
+/// Vx to Vy, counting down when y is below x.
+fn register_range(x: u8, y: u8) -> impl Iterator<Item = usize> {
+    let (x, y) = (x as usize, y as usize);
+    (0..=x.abs_diff(y)).map(move |offset| if x <= y { x + offset } else { x - offset })
+}
 
-#[derive(Clone)]
-struct Registers {
-    pub v: [u8; 16],
-    pub pc: u16,
-    pub sp: u8,
-    pub stack: [u16; 16],
-    pub i: u16,
-    pub delay_timer: u8,
-    pub sound_timer: u8,
+/// xorshift64*, for RND. Unlike the rand generators its whole state is one number a save state can hold.
+#[derive(Clone, Debug)]
+struct Rng {
+    state: u64,
 }
 
-impl Registers {
-    pub fn new() -> Self {
-        Registers {
-            v: [0; 16],
-            pc: 0x200,
-            sp: 0,
-            stack: [0; 16], // 16 levels of stack in (0xEA0-0xEAF) in (0xEA0-0xEFF) 
-            i: 0,
-            delay_timer: 0,
-            sound_timer: 0,
+impl Rng {
+    fn new() -> Self {
+        Rng::with_state(rand::random())
+    }
+
+    fn with_state(state: u64) -> Self {
+        // zero is the one state xorshift never leaves
+        Rng { state: state.max(1) }
+    }
+
+    fn next_u8(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::{clock::Timers, display::Display, keyboard::Keyboard, sound::Sound};
+
+    struct Machine {
+        cpu: Chip8,
+        memory: Memory,
+        display: Display,
+        keyboard: Keyboard,
+        timers: Timers,
+        sound: Sound,
+    }
+
+    impl Machine {
+        fn new(rom: Vec<u8>) -> Self {
+            let mut memory = Memory::new();
+            memory.load_rom(rom).unwrap();
+            Machine {
+                cpu: Chip8::new(),
+                memory,
+                display: Display::new(),
+                keyboard: Keyboard::new(),
+                timers: Timers::new(),
+                sound: Sound::new(),
+            }
+        }
+
+        fn try_step(&mut self) -> Result<(), EmulationError> {
+            let mut bus = Bus::new(&mut self.memory, &mut self.display, &mut self.keyboard, &mut self.timers, &mut self.sound);
+            self.cpu.step(&mut bus)
+        }
+
+        fn step(&mut self) {
+            self.try_step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_draw_sets_collision_flag() {
+        // I points at the "0" font sprite, draw it twice at (0, 0)
+        let mut machine = Machine::new(vec![0xD0, 0x15, 0xD0, 0x15]);
+        machine.step();
+        assert_eq!(machine.cpu.registers.v[0xF], 0);
+        assert!(machine.display.get_pixel(0, 0));
+        machine.step();
+        assert_eq!(machine.cpu.registers.v[0xF], 1);
+        assert!(machine.display.pixels().iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn test_cls_clears_display() {
+        let mut machine = Machine::new(vec![0xD0, 0x15, 0x00, 0xE0]);
+        machine.step();
+        machine.step();
+        assert!(machine.display.pixels().iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn test_call_and_ret() {
+        // 0x200: CALL 0x206, 0x202: JP 0x202, 0x206: RET
+        let mut machine = Machine::new(vec![0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x00, 0xEE]);
+        machine.step();
+        assert_eq!(machine.cpu.registers.pc, 0x206);
+        machine.step();
+        assert_eq!(machine.cpu.registers.pc, 0x202);
+        assert_eq!(machine.cpu.registers.sp, 0);
+    }
+
+    #[test]
+    fn test_timers_through_bus() {
+        // LD V0, 0x3C; LD DT, V0; LD ST, V0
+        let mut machine = Machine::new(vec![0x60, 0x3C, 0xF0, 0x15, 0xF0, 0x18]);
+        machine.step();
+        machine.step();
+        machine.step();
+        assert_eq!(machine.timers.delay, 0x3C);
+        assert_eq!(machine.timers.sound, 0x3C);
+    }
+
+    #[test]
+    fn test_bcd() {
+        // LD V0, 0xFE (254); LD I, 0x300; LD B, V0
+        let mut machine = Machine::new(vec![0x60, 0xFE, 0xA3, 0x00, 0xF0, 0x33]);
+        machine.step();
+        machine.step();
+        machine.step();
+        assert_eq!(machine.memory.read(0x300), Ok(2));
+        assert_eq!(machine.memory.read(0x301), Ok(5));
+        assert_eq!(machine.memory.read(0x302), Ok(4));
+    }
+
+    #[test]
+    fn test_skip_if_key_pressed() {
+        // LD V0, 0x7; SKP V0; LD V1, 0x1; SKNP V0
+        let mut machine = Machine::new(vec![0x60, 0x07, 0xE0, 0x9E, 0x61, 0x01, 0xE0, 0xA1]);
+        machine.keyboard.press(0x7);
+        machine.step();
+        machine.step();
+        assert_eq!(machine.cpu.registers.pc, 0x206);
+        machine.step();
+        assert_eq!(machine.cpu.registers.pc, 0x208);
+    }
+
+    #[test]
+    fn test_wait_for_key_blocks_until_release() {
+        // LD V3, K
+        let mut machine = Machine::new(vec![0xF3, 0x0A]);
+        machine.step();
+        assert_eq!(machine.cpu.registers.pc, 0x200);
+        machine.keyboard.press(0xC);
+        machine.step();
+        assert_eq!(machine.cpu.registers.pc, 0x200);
+        machine.keyboard.release(0xC);
+        machine.step();
+        assert_eq!(machine.cpu.registers.pc, 0x202);
+        assert_eq!(machine.cpu.registers.v[3], 0xC);
+    }
+
+    #[test]
+    fn test_wait_for_key_at_the_end_of_memory() {
+        // LD V3, K in the last word of a 64K memory, pc wraps around to 0 while it waits
+        let mut machine = Machine::new(vec![]);
+        machine.memory = Memory::with_size(0x10000);
+        machine.memory.load_rom_at(vec![0xF3, 0x0A], 0xFFFE).unwrap();
+        machine.cpu.set_pc(0xFFFE);
+        machine.step();
+        assert_eq!(machine.cpu.registers.pc, 0xFFFE);
+        assert!(machine.cpu.is_waiting_for_key());
+    }
+
+    #[test]
+    fn test_add_wraps_and_sets_carry() {
+        // LD V0, 0xFF; ADD V0, 0x02; LD V1, 0xFF; ADD V1, V1
+        let mut machine = Machine::new(vec![0x60, 0xFF, 0x70, 0x02, 0x61, 0xFF, 0x81, 0x14]);
+        machine.step();
+        machine.step();
+        assert_eq!(machine.cpu.registers.v[0], 0x01);
+        machine.step();
+        machine.step();
+        assert_eq!(machine.cpu.registers.v[1], 0xFE);
+        assert_eq!(machine.cpu.registers.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let mut machine = Machine::new(vec![0x00, 0xE0, 0x80, 0x08]);
+        machine.step();
+        assert_eq!(machine.try_step(), Err(EmulationError::UnknownOpcode { opcode: 0x8008, address: 0x202 }));
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut machine = Machine::new(vec![0x00, 0xEE]);
+        assert_eq!(machine.try_step(), Err(EmulationError::StackUnderflow { address: 0x200 }));
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // CALL 0x200 forever
+        let mut machine = Machine::new(vec![0x22, 0x00]);
+        for _ in 0..16 {
+            machine.step();
         }
+        assert_eq!(machine.try_step(), Err(EmulationError::StackOverflow { address: 0x200 }));
     }
 
-    pub fn increment_pc(&mut self) {
-        self.pc += 2;
+    #[test]
+    fn test_store_into_reserved_memory() {
+        // LD I, 0x100; LD [I], V0
+        let mut machine = Machine::new(vec![0xA1, 0x00, 0xF0, 0x55]);
+        machine.step();
+        assert_eq!(machine.try_step(), Err(EmulationError::WriteProtected { address: 0x100 }));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_quirks() {
+        // LD V1, 0x81; LD V2, 0x0F; SHR V1, V2; OR V1, V2
+        let rom = vec![0x61, 0x81, 0x62, 0x0F, 0x81, 0x26, 0x81, 0x21];
+        let mut machine = Machine::new(rom.clone());
+        (0..3).for_each(|_| machine.step());
+        assert_eq!((machine.cpu.registers.v[1], machine.cpu.registers.v[0xF]), (0x07, 1));
+        machine.step();
+        assert_eq!((machine.cpu.registers.v[1], machine.cpu.registers.v[0xF]), (0x0F, 0));
+
+        machine = Machine::new(rom);
+        machine.cpu = Chip8::new().with_quirks(Quirks::schip());
+        (0..4).for_each(|_| machine.step());
+        assert_eq!((machine.cpu.registers.v[1], machine.cpu.registers.v[0xF]), (0x4F, 1));
+    }
+
+    #[test]
+    fn test_index_and_jump_quirks() {
+        // LD I, 0x300; LD [I], V2; LD V2, 0x10; JP V0, 0x204
+        let rom = vec![0xA3, 0x00, 0xF2, 0x55, 0x62, 0x10, 0xB2, 0x04];
+        for (quirks, i, pc) in [(Quirks::vip(), 0x303, 0x204), (Quirks::chip48(), 0x302, 0x214), (Quirks::schip(), 0x300, 0x214)] {
+            let mut machine = Machine::new(rom.clone());
+            machine.cpu = Chip8::new().with_quirks(quirks);
+            (0..4).for_each(|_| machine.step());
+            assert_eq!((machine.cpu.registers.i, machine.cpu.registers.pc), (i, pc), "{}", quirks);
+        }
+    }
+
+    #[test]
+    fn test_display_wait() {
+        let mut machine = Machine::new(vec![0xD0, 0x15, 0xD0, 0x15]);
+        machine.step();
+        assert!(machine.cpu.take_display_wait());
+        assert!(!machine.cpu.take_display_wait());
+
+        machine.cpu = Chip8::new().with_quirks(Quirks::xochip());
+        machine.step();
+        assert!(!machine.cpu.take_display_wait());
+    }
+
+    #[test]
+    fn test_schip_display() {
+        // HIGH; LD V0, 0x7; LD HF, V0; DRW V1, V1, 0; SCR; SCD 2
+        let mut machine = Machine::new(vec![0x00, 0xFF, 0x60, 0x07, 0xF0, 0x30, 0xD1, 0x10, 0x00, 0xFB, 0x00, 0xC2]);
+        (0..4).for_each(|_| machine.step());
+        assert!(machine.display.is_hires());
+        assert_eq!(machine.cpu.registers.i, BIG_FONT_ADDRESS + 70);
+        // a 16x16 sprite takes two bytes a row, the first row is the big seven's 0xFF 0xFF
+        assert!((0..16).all(|x| machine.display.get_pixel(x, 0)));
+        assert!(!machine.display.get_pixel(16, 0));
+        machine.step();
+        machine.step();
+        assert!(!machine.display.get_pixel(0, 2));
+        assert!((4..20).all(|x| machine.display.get_pixel(x, 2)));
+    }
+
+    #[test]
+    fn test_low_resolution_large_sprite() {
+        // LD I, 0x300; DRW V1, V1, 0 with a solid 16x16 sprite at 0x300
+        let profiles = [(Quirks::vip(), false), (Quirks::chip48(), false), (Quirks::schip(), true), (Quirks::xochip(), true)];
+        for (quirks, large) in profiles {
+            let mut machine = Machine::new(vec![0xA3, 0x00, 0xD1, 0x10]);
+            machine.memory.load_rom_at(vec![0xFF; 32], 0x300).unwrap();
+            machine.cpu = Chip8::new().with_quirks(quirks);
+            (0..2).for_each(|_| machine.step());
+            assert_eq!(machine.display.get_pixel(0, 0), large, "{}", quirks);
+            assert_eq!(machine.display.get_pixel(15, 15), large, "{}", quirks);
+            assert_eq!(machine.cpu.registers.v[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn test_flags_and_exit() {
+        // LD V0, 0x12; LD V1, 0x34; LD R, V1; LD V0, 0; LD V1, R; EXIT
+        let mut machine = Machine::new(vec![0x60, 0x12, 0x61, 0x34, 0xF1, 0x75, 0x60, 0x00, 0xF1, 0x85, 0x00, 0xFD]);
+        (0..6).for_each(|_| machine.step());
+        assert_eq!(&machine.cpu.registers.v[..2], &[0x12, 0x34]);
+        assert!(machine.cpu.has_exited());
+        assert_eq!(machine.cpu.registers.pc, 0x20A);
+        machine.cpu.reset();
+        assert!(!machine.cpu.has_exited());
+        assert_eq!(machine.cpu.flags[..2], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_xochip_instructions() {
+        // LD I, LONG 0x0300; LD V1, 1; LD V2, 2; LD [I], V2-V1; SE V1, 1; LD I, LONG 0x0000; LD V1-V2, [I]
+        let mut machine = Machine::new(vec![
+            0xF0, 0x00, 0x03, 0x00, 0x61, 0x01, 0x62, 0x02, 0x52, 0x12, 0x31, 0x01, 0xF0, 0x00, 0x00, 0x00, 0x51, 0x23,
+        ]);
+        machine.step();
+        assert_eq!((machine.cpu.registers.i, machine.cpu.registers.pc), (0x300, 0x204));
+        (0..3).for_each(|_| machine.step());
+        assert_eq!((machine.memory.read(0x300), machine.memory.read(0x301)), (Ok(2), Ok(1)));
+        machine.step();
+        assert_eq!(machine.cpu.registers.pc, 0x210);
+        machine.step();
+        assert_eq!(&machine.cpu.registers.v[1..3], &[2, 1]);
+        assert_eq!(machine.cpu.registers.i, 0x300);
+    }
+
+    #[test]
+    fn test_draw_on_planes() {
+        // PLANE 3; LD I, 0x300; DRW V0, V0, 1
+        let mut machine = Machine::new(vec![0xF3, 0x01, 0xA3, 0x00, 0xD0, 0x01]);
+        machine.memory.write(0x300, 0x80).unwrap();
+        machine.memory.write(0x301, 0xC0).unwrap();
+        (0..3).for_each(|_| machine.step());
+        assert_eq!((machine.display.colour(0, 0), machine.display.colour(1, 0)), (3, 2));
+    }
+
+    #[test]
+    fn test_audio_instructions() {
+        // LD I, 0x300; AUDIO; LD V4, 0x70; LD PITCH, V4
+        let mut machine = Machine::new(vec![0xA3, 0x00, 0xF0, 0x02, 0x64, 0x70, 0xF4, 0x3A]);
+        for offset in 0..16 {
+            machine.memory.write(0x300 + offset, offset as u8).unwrap();
+        }
+        (0..2).for_each(|_| machine.step());
+        assert_eq!(machine.sound.pattern, Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]));
+        assert_eq!(machine.cpu.registers.i, 0x300);
+        (0..2).for_each(|_| machine.step());
+        assert_eq!(machine.sound.pitch, 0x70);
+    }
+}
@@ -0,0 +1,422 @@
+use std::fmt;
+
+/// Instruction
+/// From: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#3.1
+/// Every Chip-8 instruction is 2 bytes long, most significant byte first. In the opcode patterns below
+/// nnn is a 12-bit address, kk a byte, n a nibble, and x and y are register numbers (V0 - VF).
+/// The SUPER-CHIP 1.1 instructions are those of http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#3.2
+/// and the XO-CHIP ones those of https://johnearnest.github.io/Octo/docs/XO-ChipSpecification.html,
+/// F000 nnnn is the one instruction that takes 4 bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0nnn - SYS addr -- Jump to a machine code routine at nnn, ignored by modern interpreters.
+    Sys(u16),
+    /// 00E0 - CLS -- Clear the display.
+    Cls,
+    /// 00EE - RET -- Return from a subroutine.
+    Ret,
+    /// 00Cn - SCD nibble -- Scroll the display down n lines.
+    ScrollDown(u8),
+    /// 00Dn - SCU nibble -- Scroll the display up n lines.
+    ScrollUp(u8),
+    /// 00FB - SCR -- Scroll the display right 4 pixels.
+    ScrollRight,
+    /// 00FC - SCL -- Scroll the display left 4 pixels.
+    ScrollLeft,
+    /// 00FD - EXIT -- Exit the interpreter.
+    Exit,
+    /// 00FE - LOW -- Switch to the 64x32 display.
+    LowRes,
+    /// 00FF - HIGH -- Switch to the 128x64 display.
+    HighRes,
+    /// 1nnn - JP addr -- Jump to location nnn.
+    Jump(u16),
+    /// 2nnn - CALL addr -- Call subroutine at nnn.
+    Call(u16),
+    /// 3xkk - SE Vx, byte -- Skip next instruction if Vx = kk.
+    SkipEqualByte { x: u8, byte: u8 },
+    /// 4xkk - SNE Vx, byte -- Skip next instruction if Vx != kk.
+    SkipNotEqualByte { x: u8, byte: u8 },
+    /// 5xy0 - SE Vx, Vy -- Skip next instruction if Vx = Vy.
+    SkipEqual { x: u8, y: u8 },
+    /// 5xy2 - LD [I], Vx-Vy -- Store registers Vx through Vy in memory starting at location I, I is unchanged.
+    StoreRange { x: u8, y: u8 },
+    /// 5xy3 - LD Vx-Vy, [I] -- Read registers Vx through Vy from memory starting at location I, I is unchanged.
+    LoadRange { x: u8, y: u8 },
+    /// 6xkk - LD Vx, byte -- Set Vx = kk.
+    LoadByte { x: u8, byte: u8 },
+    /// 7xkk - ADD Vx, byte -- Set Vx = Vx + kk.
+    AddByte { x: u8, byte: u8 },
+    /// 8xy0 - LD Vx, Vy -- Set Vx = Vy.
+    Load { x: u8, y: u8 },
+    /// 8xy1 - OR Vx, Vy -- Set Vx = Vx OR Vy.
+    Or { x: u8, y: u8 },
+    /// 8xy2 - AND Vx, Vy -- Set Vx = Vx AND Vy.
+    And { x: u8, y: u8 },
+    /// 8xy3 - XOR Vx, Vy -- Set Vx = Vx XOR Vy.
+    Xor { x: u8, y: u8 },
+    /// 8xy4 - ADD Vx, Vy -- Set Vx = Vx + Vy, set VF = carry.
+    Add { x: u8, y: u8 },
+    /// 8xy5 - SUB Vx, Vy -- Set Vx = Vx - Vy, set VF = NOT borrow.
+    Sub { x: u8, y: u8 },
+    /// 8xy6 - SHR Vx {, Vy} -- Set Vx = Vx SHR 1.
+    ShiftRight { x: u8, y: u8 },
+    /// 8xy7 - SUBN Vx, Vy -- Set Vx = Vy - Vx, set VF = NOT borrow.
+    SubN { x: u8, y: u8 },
+    /// 8xyE - SHL Vx {, Vy} -- Set Vx = Vx SHL 1.
+    ShiftLeft { x: u8, y: u8 },
+    /// 9xy0 - SNE Vx, Vy -- Skip next instruction if Vx != Vy.
+    SkipNotEqual { x: u8, y: u8 },
+    /// Annn - LD I, addr -- Set I = nnn.
+    LoadI(u16),
+    /// F000 nnnn - LD I, LONG addr -- Set I = nnnn, the address in the word after the opcode.
+    LoadILong(u16),
+    /// Bnnn - JP V0, addr -- Jump to location nnn + V0.
+    JumpV0(u16),
+    /// Cxkk - RND Vx, byte -- Set Vx = random byte AND kk.
+    Random { x: u8, byte: u8 },
+    /// Dxyn - DRW Vx, Vy, nibble -- Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
+    /// Dxy0 draws a 16x16 sprite of 32 bytes.
+    Draw { x: u8, y: u8, n: u8 },
+    /// Ex9E - SKP Vx -- Skip next instruction if key with the value of Vx is pressed.
+    SkipKeyPressed { x: u8 },
+    /// ExA1 - SKNP Vx -- Skip next instruction if key with the value of Vx is not pressed.
+    SkipKeyNotPressed { x: u8 },
+    /// Fn01 - PLANE n -- Draw, scroll and clear on the planes in bits 0 and 1 of n.
+    SelectPlanes(u8),
+    /// F002 - AUDIO -- Load the 16-byte audio pattern from memory starting at location I.
+    LoadPattern,
+    /// Fx07 - LD Vx, DT -- Set Vx = delay timer value.
+    LoadDelay { x: u8 },
+    /// Fx0A - LD Vx, K -- Wait for a key press, store the value of the key in Vx.
+    WaitKey { x: u8 },
+    /// Fx15 - LD DT, Vx -- Set delay timer = Vx.
+    SetDelay { x: u8 },
+    /// Fx18 - LD ST, Vx -- Set sound timer = Vx.
+    SetSound { x: u8 },
+    /// Fx3A - LD PITCH, Vx -- Set the audio pattern playback pitch = Vx.
+    SetPitch { x: u8 },
+    /// Fx1E - ADD I, Vx -- Set I = I + Vx.
+    AddI { x: u8 },
+    /// Fx29 - LD F, Vx -- Set I = location of sprite for digit Vx.
+    LoadFont { x: u8 },
+    /// Fx30 - LD HF, Vx -- Set I = location of the 10-byte sprite for digit Vx.
+    LoadBigFont { x: u8 },
+    /// Fx33 - LD B, Vx -- Store BCD representation of Vx in memory locations I, I+1, and I+2.
+    StoreBcd { x: u8 },
+    /// Fx55 - LD [I], Vx -- Store registers V0 through Vx in memory starting at location I.
+    StoreRegisters { x: u8 },
+    /// Fx65 - LD Vx, [I] -- Read registers V0 through Vx from memory starting at location I.
+    LoadRegisters { x: u8 },
+    /// Fx75 - LD R, Vx -- Store V0 through Vx in the RPL user flags.
+    StoreFlags { x: u8 },
+    /// Fx85 - LD Vx, R -- Read V0 through Vx from the RPL user flags.
+    LoadFlags { x: u8 },
+}
+
+impl Instruction {
+    /// True for the first word of F000 nnnn, which `decode_long` decodes along with the word after it.
+    pub fn is_long(op_code: u16) -> bool {
+        op_code == 0xF000
+    }
+
+    /// Like `decode`, with the word following the opcode for F000 nnnn.
+    pub fn decode_long(op_code: u16, next: u16) -> Option<Instruction> {
+        match Instruction::is_long(op_code) {
+            true => Some(Instruction::LoadILong(next)),
+            false => Instruction::decode(op_code),
+        }
+    }
+
+    /// Returns None for opcodes that are not part of the instruction set, and for F000 which needs `decode_long`.
+    pub fn decode(op_code: u16) -> Option<Instruction> {
+        // split the op_code into the nibbles and fields the instructions are made of
+        let nnn = op_code & 0x0FFF;
+        let x = ((op_code & 0x0F00) >> 8) as u8;
+        let y = ((op_code & 0x00F0) >> 4) as u8;
+        let n = (op_code & 0x000F) as u8;
+        let byte = (op_code & 0x00FF) as u8;
+
+        let instruction = match op_code & 0xF000 {
+            0x0000 => match op_code {
+                0x00E0 => Instruction::Cls,
+                0x00EE => Instruction::Ret,
+                0x00FB => Instruction::ScrollRight,
+                0x00FC => Instruction::ScrollLeft,
+                0x00FD => Instruction::Exit,
+                0x00FE => Instruction::LowRes,
+                0x00FF => Instruction::HighRes,
+                _ if op_code & 0xFFF0 == 0x00C0 => Instruction::ScrollDown(n),
+                _ if op_code & 0xFFF0 == 0x00D0 => Instruction::ScrollUp(n),
+                _ => Instruction::Sys(nnn),
+            },
+            0x1000 => Instruction::Jump(nnn),
+            0x2000 => Instruction::Call(nnn),
+            0x3000 => Instruction::SkipEqualByte { x, byte },
+            0x4000 => Instruction::SkipNotEqualByte { x, byte },
+            0x5000 => match n {
+                0x0 => Instruction::SkipEqual { x, y },
+                0x2 => Instruction::StoreRange { x, y },
+                0x3 => Instruction::LoadRange { x, y },
+                _ => return None,
+            },
+            0x6000 => Instruction::LoadByte { x, byte },
+            0x7000 => Instruction::AddByte { x, byte },
+            0x8000 => match n {
+                0x0 => Instruction::Load { x, y },
+                0x1 => Instruction::Or { x, y },
+                0x2 => Instruction::And { x, y },
+                0x3 => Instruction::Xor { x, y },
+                0x4 => Instruction::Add { x, y },
+                0x5 => Instruction::Sub { x, y },
+                0x6 => Instruction::ShiftRight { x, y },
+                0x7 => Instruction::SubN { x, y },
+                0xE => Instruction::ShiftLeft { x, y },
+                _ => return None,
+            },
+            0x9000 if n == 0 => Instruction::SkipNotEqual { x, y },
+            0xA000 => Instruction::LoadI(nnn),
+            0xB000 => Instruction::JumpV0(nnn),
+            0xC000 => Instruction::Random { x, byte },
+            0xD000 => Instruction::Draw { x, y, n },
+            0xE000 => match byte {
+                0x9E => Instruction::SkipKeyPressed { x },
+                0xA1 => Instruction::SkipKeyNotPressed { x },
+                _ => return None,
+            },
+            0xF000 => match byte {
+                0x01 => Instruction::SelectPlanes(x),
+                0x02 if x == 0 => Instruction::LoadPattern,
+                0x07 => Instruction::LoadDelay { x },
+                0x0A => Instruction::WaitKey { x },
+                0x15 => Instruction::SetDelay { x },
+                0x18 => Instruction::SetSound { x },
+                0x1E => Instruction::AddI { x },
+                0x29 => Instruction::LoadFont { x },
+                0x30 => Instruction::LoadBigFont { x },
+                0x33 => Instruction::StoreBcd { x },
+                0x3A => Instruction::SetPitch { x },
+                0x55 => Instruction::StoreRegisters { x },
+                0x65 => Instruction::LoadRegisters { x },
+                0x75 => Instruction::StoreFlags { x },
+                0x85 => Instruction::LoadFlags { x },
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    /// Bytes the instruction takes up in memory.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// The instruction as it is stored in memory, `encode` and the address of F000 nnnn.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.encode().to_be_bytes().to_vec();
+        if let Instruction::LoadILong(addr) = *self {
+            bytes.extend(addr.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// The opcode for the instruction, the inverse of `decode`. Fields are masked to their width.
+    /// For F000 nnnn this is the first word.
+    pub fn encode(&self) -> u16 {
+        let nnn = |base: u16, addr: u16| base | (addr & 0x0FFF);
+        let xkk = |base: u16, x: u8, byte: u8| base | (x as u16 & 0xF) << 8 | byte as u16;
+        let xyn = |base: u16, x: u8, y: u8, n: u8| base | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | (n as u16 & 0xF);
+        match *self {
+            Instruction::Sys(addr) => nnn(0x0000, addr),
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            Instruction::ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::Jump(addr) => nnn(0x1000, addr),
+            Instruction::Call(addr) => nnn(0x2000, addr),
+            Instruction::SkipEqualByte { x, byte } => xkk(0x3000, x, byte),
+            Instruction::SkipNotEqualByte { x, byte } => xkk(0x4000, x, byte),
+            Instruction::SkipEqual { x, y } => xyn(0x5000, x, y, 0x0),
+            Instruction::StoreRange { x, y } => xyn(0x5000, x, y, 0x2),
+            Instruction::LoadRange { x, y } => xyn(0x5000, x, y, 0x3),
+            Instruction::LoadByte { x, byte } => xkk(0x6000, x, byte),
+            Instruction::AddByte { x, byte } => xkk(0x7000, x, byte),
+            Instruction::Load { x, y } => xyn(0x8000, x, y, 0x0),
+            Instruction::Or { x, y } => xyn(0x8000, x, y, 0x1),
+            Instruction::And { x, y } => xyn(0x8000, x, y, 0x2),
+            Instruction::Xor { x, y } => xyn(0x8000, x, y, 0x3),
+            Instruction::Add { x, y } => xyn(0x8000, x, y, 0x4),
+            Instruction::Sub { x, y } => xyn(0x8000, x, y, 0x5),
+            Instruction::ShiftRight { x, y } => xyn(0x8000, x, y, 0x6),
+            Instruction::SubN { x, y } => xyn(0x8000, x, y, 0x7),
+            Instruction::ShiftLeft { x, y } => xyn(0x8000, x, y, 0xE),
+            Instruction::SkipNotEqual { x, y } => xyn(0x9000, x, y, 0x0),
+            Instruction::LoadI(addr) => nnn(0xA000, addr),
+            Instruction::LoadILong(_) => 0xF000,
+            Instruction::JumpV0(addr) => nnn(0xB000, addr),
+            Instruction::Random { x, byte } => xkk(0xC000, x, byte),
+            Instruction::Draw { x, y, n } => xyn(0xD000, x, y, n),
+            Instruction::SkipKeyPressed { x } => xkk(0xE000, x, 0x9E),
+            Instruction::SkipKeyNotPressed { x } => xkk(0xE000, x, 0xA1),
+            Instruction::SelectPlanes(n) => xkk(0xF000, n, 0x01),
+            Instruction::LoadPattern => 0xF002,
+            Instruction::LoadDelay { x } => xkk(0xF000, x, 0x07),
+            Instruction::WaitKey { x } => xkk(0xF000, x, 0x0A),
+            Instruction::SetDelay { x } => xkk(0xF000, x, 0x15),
+            Instruction::SetSound { x } => xkk(0xF000, x, 0x18),
+            Instruction::AddI { x } => xkk(0xF000, x, 0x1E),
+            Instruction::LoadFont { x } => xkk(0xF000, x, 0x29),
+            Instruction::LoadBigFont { x } => xkk(0xF000, x, 0x30),
+            Instruction::StoreBcd { x } => xkk(0xF000, x, 0x33),
+            Instruction::SetPitch { x } => xkk(0xF000, x, 0x3A),
+            Instruction::StoreRegisters { x } => xkk(0xF000, x, 0x55),
+            Instruction::LoadRegisters { x } => xkk(0xF000, x, 0x65),
+            Instruction::StoreFlags { x } => xkk(0xF000, x, 0x75),
+            Instruction::LoadFlags { x } => xkk(0xF000, x, 0x85),
+        }
+    }
+
+    /// The address a jump or call goes to.
+    pub fn target(&self) -> Option<u16> {
+        match *self {
+            Instruction::Jump(addr) | Instruction::Call(addr) | Instruction::JumpV0(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Cowgod's mnemonic for the instruction, `address` renders the nnn operands so labels can be used instead.
+    pub fn format_with(&self, address: impl Fn(u16) -> String) -> String {
+        let byte = |byte: u8| format!("0x{:02X}", byte);
+        match *self {
+            Instruction::Sys(addr) => format!("SYS {}", address(addr)),
+            Instruction::Cls => "CLS".to_string(),
+            Instruction::Ret => "RET".to_string(),
+            Instruction::ScrollDown(n) => format!("SCD {}", n),
+            Instruction::ScrollUp(n) => format!("SCU {}", n),
+            Instruction::ScrollRight => "SCR".to_string(),
+            Instruction::ScrollLeft => "SCL".to_string(),
+            Instruction::Exit => "EXIT".to_string(),
+            Instruction::LowRes => "LOW".to_string(),
+            Instruction::HighRes => "HIGH".to_string(),
+            Instruction::Jump(addr) => format!("JP {}", address(addr)),
+            Instruction::Call(addr) => format!("CALL {}", address(addr)),
+            Instruction::SkipEqualByte { x, byte: kk } => format!("SE V{:X}, {}", x, byte(kk)),
+            Instruction::SkipNotEqualByte { x, byte: kk } => format!("SNE V{:X}, {}", x, byte(kk)),
+            Instruction::SkipEqual { x, y } => format!("SE V{:X}, V{:X}", x, y),
+            Instruction::StoreRange { x, y } => format!("LD [I], V{:X}-V{:X}", x, y),
+            Instruction::LoadRange { x, y } => format!("LD V{:X}-V{:X}, [I]", x, y),
+            Instruction::LoadByte { x, byte: kk } => format!("LD V{:X}, {}", x, byte(kk)),
+            Instruction::AddByte { x, byte: kk } => format!("ADD V{:X}, {}", x, byte(kk)),
+            Instruction::Load { x, y } => format!("LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+            Instruction::Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftRight { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+            Instruction::SubN { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShiftLeft { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNotEqual { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+            Instruction::LoadI(addr) => format!("LD I, {}", address(addr)),
+            Instruction::LoadILong(addr) => format!("LD I, LONG {}", address(addr)),
+            Instruction::JumpV0(addr) => format!("JP V0, {}", address(addr)),
+            Instruction::Random { x, byte: kk } => format!("RND V{:X}, {}", x, byte(kk)),
+            Instruction::Draw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKeyPressed { x } => format!("SKP V{:X}", x),
+            Instruction::SkipKeyNotPressed { x } => format!("SKNP V{:X}", x),
+            Instruction::SelectPlanes(n) => format!("PLANE {}", n),
+            Instruction::LoadPattern => "AUDIO".to_string(),
+            Instruction::LoadDelay { x } => format!("LD V{:X}, DT", x),
+            Instruction::WaitKey { x } => format!("LD V{:X}, K", x),
+            Instruction::SetDelay { x } => format!("LD DT, V{:X}", x),
+            Instruction::SetSound { x } => format!("LD ST, V{:X}", x),
+            Instruction::SetPitch { x } => format!("LD PITCH, V{:X}", x),
+            Instruction::AddI { x } => format!("ADD I, V{:X}", x),
+            Instruction::LoadFont { x } => format!("LD F, V{:X}", x),
+            Instruction::LoadBigFont { x } => format!("LD HF, V{:X}", x),
+            Instruction::StoreBcd { x } => format!("LD B, V{:X}", x),
+            Instruction::StoreRegisters { x } => format!("LD [I], V{:X}", x),
+            Instruction::LoadRegisters { x } => format!("LD V{:X}, [I]", x),
+            Instruction::StoreFlags { x } => format!("LD R, V{:X}", x),
+            Instruction::LoadFlags { x } => format!("LD V{:X}, R", x),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format_with(|addr| format!("0x{:03X}", addr)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Instruction::decode(0x00E0), Some(Instruction::Cls));
+        assert_eq!(Instruction::decode(0x00EE), Some(Instruction::Ret));
+        assert_eq!(Instruction::decode(0x0123), Some(Instruction::Sys(0x123)));
+        assert_eq!(Instruction::decode(0x2ABC), Some(Instruction::Call(0xABC)));
+        assert_eq!(Instruction::decode(0x631F), Some(Instruction::LoadByte { x: 3, byte: 0x1F }));
+        assert_eq!(Instruction::decode(0x8AB4), Some(Instruction::Add { x: 0xA, y: 0xB }));
+        assert_eq!(Instruction::decode(0xD015), Some(Instruction::Draw { x: 0, y: 1, n: 5 }));
+        assert_eq!(Instruction::decode(0xE79E), Some(Instruction::SkipKeyPressed { x: 7 }));
+        assert_eq!(Instruction::decode(0xF265), Some(Instruction::LoadRegisters { x: 2 }));
+        assert_eq!(Instruction::decode(0x00C4), Some(Instruction::ScrollDown(4)));
+        assert_eq!(Instruction::decode(0x00FF), Some(Instruction::HighRes));
+        assert_eq!(Instruction::decode(0xD120), Some(Instruction::Draw { x: 1, y: 2, n: 0 }));
+        assert_eq!(Instruction::decode(0xF385), Some(Instruction::LoadFlags { x: 3 }));
+        assert_eq!(Instruction::decode(0x5232), Some(Instruction::StoreRange { x: 2, y: 3 }));
+        assert_eq!(Instruction::decode(0xF201), Some(Instruction::SelectPlanes(2)));
+        assert_eq!(Instruction::decode(0xF000), None);
+        assert_eq!(Instruction::decode(0xF002), Some(Instruction::LoadPattern));
+        assert_eq!(Instruction::decode(0xF43A), Some(Instruction::SetPitch { x: 4 }));
+        assert_eq!(Instruction::decode_long(0xF000, 0x1234), Some(Instruction::LoadILong(0x1234)));
+        assert_eq!(Instruction::LoadILong(0x1234).to_bytes(), vec![0xF0, 0x00, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        assert_eq!(Instruction::decode(0x5121), None);
+        assert_eq!(Instruction::decode(0x5124), None);
+        assert_eq!(Instruction::decode(0x8128), None);
+        assert_eq!(Instruction::decode(0x9121), None);
+        assert_eq!(Instruction::decode(0xE1FF), None);
+        assert_eq!(Instruction::decode(0xF1FF), None);
+        assert_eq!(Instruction::decode(0xF102), None);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Instruction::LoadByte { x: 3, byte: 0x1F }.to_string(), "LD V3, 0x1F");
+        assert_eq!(Instruction::Draw { x: 0, y: 1, n: 5 }.to_string(), "DRW V0, V1, 5");
+        assert_eq!(Instruction::Jump(0x2A0).to_string(), "JP 0x2A0");
+        assert_eq!(Instruction::StoreRegisters { x: 0xA }.to_string(), "LD [I], VA");
+        assert_eq!(Instruction::ScrollDown(0xC).to_string(), "SCD 12");
+        assert_eq!(Instruction::LoadBigFont { x: 1 }.to_string(), "LD HF, V1");
+        assert_eq!(Instruction::LoadRange { x: 1, y: 4 }.to_string(), "LD V1-V4, [I]");
+        assert_eq!(Instruction::LoadILong(0xE000).to_string(), "LD I, LONG 0xE000");
+        assert_eq!(Instruction::Call(0x2A0).format_with(|_| "draw".to_string()), "CALL draw");
+    }
+
+    #[test]
+    fn test_encode_is_inverse_of_decode() {
+        for op_code in 0..=0xFFFF {
+            if let Some(instruction) = Instruction::decode(op_code) {
+                assert_eq!(instruction.encode(), op_code, "{}", instruction);
+            }
+        }
+    }
+}
@@ -1 +1,46 @@
-pub mod chip8;
\ No newline at end of file
+pub mod chip8;
+pub mod instruction;
+pub mod quirks;
+pub mod registers;
+
+use super::{bus::Bus, error::EmulationError, memory::Memory};
+use super::savestate::{StateReader, StateWriter};
+use registers::Registers;
+use instruction::Instruction;
+
+/// Cpu
+/// An interpreter core the `Computer` runs. The core owns the registers and whatever state its
+/// instruction set needs, everything else is reached through the bus one instruction at a time.
+/// `Chip8` is the reference core, it runs CHIP-8, SUPER-CHIP and XO-CHIP programs under a quirk profile.
+pub trait Cpu: Clone {
+    /// Fetch and execute the instruction at the program counter.
+    fn step(&mut self, bus: &mut Bus) -> Result<(), EmulationError>;
+
+    /// Back to power on, with the program counter at 0x200.
+    fn reset(&mut self);
+
+    fn set_pc(&mut self, pc: u16);
+
+    /// Register state, for debuggers and frontends.
+    fn registers(&self) -> &Registers;
+
+    /// Register state for debuggers that change it between instructions.
+    fn registers_mut(&mut self) -> &mut Registers;
+
+    /// True while the program is held until a key is pressed and released.
+    fn is_waiting_for_key(&self) -> bool;
+
+    /// True once the program asked to leave the interpreter.
+    fn has_exited(&self) -> bool;
+
+    /// True once after an instruction that has to wait for the display, the rest of the frame is skipped.
+    fn take_display_wait(&mut self) -> bool;
+
+    /// The instruction at `address` as this core decodes it, for disassembly in debuggers.
+    /// None when the core has no instruction there.
+    fn instruction_at(&self, memory: &Memory, address: u16) -> Option<Instruction>;
+
+    fn write_state(&self, out: &mut StateWriter);
+
+    fn read_state(&mut self, input: &mut StateReader) -> Result<(), String>;
+}
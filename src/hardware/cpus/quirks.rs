@@ -0,0 +1,222 @@
+//! Quirks
+//! From: https://github.com/Timendus/chip8-test-suite#quirks-test
+//! The interpreters that followed the COSMAC VIP's changed a few instructions, on purpose or by
+//! accident, and programs came to rely on either behaviour. A profile is a preset for one of those
+//! interpreters, with any quirk overridden by name: `schip,jump=v0`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::hardware::memory::{MEMORY_SIZE, XO_MEMORY_SIZE};
+
+/// Where Fx55 and Fx65 leave I.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// I + x + 1, past the last register, as on the VIP.
+    Increment,
+    /// I + x, one short, as on the HP-48 interpreters.
+    IncrementX,
+    Unchanged,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8xy6 and 8xyE shift Vy into Vx instead of shifting Vx in place.
+    pub shift_uses_vy: bool,
+    pub load_store: IndexIncrement,
+    /// Bxnn jumps to xnn + Vx instead of nnn + V0.
+    pub jump_uses_vx: bool,
+    /// 8xy1, 8xy2 and 8xy3 set VF to 0.
+    pub logic_resets_vf: bool,
+    /// Sprites are cut off at the edges of the screen instead of wrapping around to the other side.
+    pub clip_sprites: bool,
+    /// DRW waits for the display refresh, so a program draws at most once per frame.
+    pub display_wait: bool,
+    /// Dxy0 draws a 16x16 sprite in low resolution too, the VIP draws nothing. It always does in high resolution.
+    pub large_sprites: bool,
+    /// Bytes of memory, XO-CHIP has 64kb.
+    pub memory_size: usize,
+}
+
+impl Quirks {
+    /// The original CHIP-8 interpreter on the COSMAC VIP.
+    pub fn vip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store: IndexIncrement::Increment,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+            display_wait: true,
+            large_sprites: false,
+            memory_size: MEMORY_SIZE,
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators.
+    pub fn chip48() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: IndexIncrement::IncrementX,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+            large_sprites: false,
+            memory_size: MEMORY_SIZE,
+        }
+    }
+
+    /// SUPER-CHIP 1.1.
+    pub fn schip() -> Self {
+        Quirks {
+            load_store: IndexIncrement::Unchanged,
+            large_sprites: true,
+            ..Quirks::chip48()
+        }
+    }
+
+    /// Octo's XO-CHIP.
+    pub fn xochip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            load_store: IndexIncrement::Increment,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+            display_wait: false,
+            large_sprites: true,
+            memory_size: XO_MEMORY_SIZE,
+        }
+    }
+
+    fn preset(name: &str) -> Option<Self> {
+        match name {
+            "vip" | "chip8" | "chip-8" => Some(Quirks::vip()),
+            "chip48" | "chip-48" => Some(Quirks::chip48()),
+            "schip" | "superchip" | "super-chip" => Some(Quirks::schip()),
+            "xochip" | "xo-chip" => Some(Quirks::xochip()),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let flag = || match value {
+            "on" | "true" | "yes" => Ok(true),
+            "off" | "false" | "no" => Ok(false),
+            _ => Err(format!("quirk {} is on or off, not '{}'", name, value)),
+        };
+        match name {
+            "shift" => {
+                self.shift_uses_vy = match value {
+                    "vy" => true,
+                    "vx" => false,
+                    _ => return Err(format!("quirk shift is vx or vy, not '{}'", value)),
+                }
+            },
+            "memory" => {
+                self.load_store = match value {
+                    "increment" => IndexIncrement::Increment,
+                    "x" => IndexIncrement::IncrementX,
+                    "unchanged" => IndexIncrement::Unchanged,
+                    _ => return Err(format!("quirk memory is increment, x or unchanged, not '{}'", value)),
+                }
+            },
+            "jump" => {
+                self.jump_uses_vx = match value {
+                    "vx" => true,
+                    "v0" => false,
+                    _ => return Err(format!("quirk jump is v0 or vx, not '{}'", value)),
+                }
+            },
+            "vf-reset" => self.logic_resets_vf = flag()?,
+            "clip" => self.clip_sprites = flag()?,
+            "display-wait" => self.display_wait = flag()?,
+            "large-sprites" => self.large_sprites = flag()?,
+            "ram" => {
+                self.memory_size = match value {
+                    "4k" => MEMORY_SIZE,
+                    "64k" => XO_MEMORY_SIZE,
+                    _ => return Err(format!("quirk ram is 4k or 64k, not '{}'", value)),
+                }
+            },
+            _ => {
+                return Err(format!(
+                    "unknown quirk '{}', expected shift, memory, jump, vf-reset, clip, display-wait, large-sprites or ram",
+                    name
+                ))
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Every quirk by name, in the form `FromStr` reads.
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |on: bool| if on { "on" } else { "off" };
+        let memory = match self.load_store {
+            IndexIncrement::Increment => "increment",
+            IndexIncrement::IncrementX => "x",
+            IndexIncrement::Unchanged => "unchanged",
+        };
+        write!(
+            f,
+            "shift={},memory={},jump={},vf-reset={},clip={},display-wait={},large-sprites={},ram={}",
+            if self.shift_uses_vy { "vy" } else { "vx" },
+            memory,
+            if self.jump_uses_vx { "vx" } else { "v0" },
+            flag(self.logic_resets_vf),
+            flag(self.clip_sprites),
+            flag(self.display_wait),
+            flag(self.large_sprites),
+            if self.memory_size == XO_MEMORY_SIZE { "64k" } else { "4k" },
+        )
+    }
+}
+
+/// A preset, quirks or both separated by commas, quirks override the preset: `vip`, `xochip,clip=on`
+/// or `shift=vx,jump=vx`. Quirks that are not given come from the VIP.
+impl FromStr for Quirks {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut quirks = Quirks::vip();
+        for (index, part) in text.split(',').map(|part| part.trim().to_ascii_lowercase()).enumerate() {
+            match part.split_once('=') {
+                Some((name, value)) => quirks.set(name.trim(), value.trim())?,
+                None if index == 0 => {
+                    quirks = Quirks::preset(&part).ok_or_else(|| {
+                        format!("unknown quirk profile '{}', expected vip, chip48, schip or xochip", part)
+                    })?
+                },
+                None => return Err(format!("'{}' should be a quirk=value pair", part)),
+            }
+        }
+        Ok(quirks)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profiles() {
+        assert_eq!("vip".parse(), Ok(Quirks::vip()));
+        assert_eq!("SCHIP".parse(), Ok(Quirks::schip()));
+        let quirks: Quirks = "xo-chip, clip=on, memory=unchanged".parse().unwrap();
+        assert_eq!(quirks, Quirks { clip_sprites: true, load_store: IndexIncrement::Unchanged, ..Quirks::xochip() });
+        assert!("schip,jump=v1".parse::<Quirks>().is_err());
+        assert!("cosmac".parse::<Quirks>().is_err());
+        assert!("vip,schip".parse::<Quirks>().is_err());
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for quirks in [Quirks::vip(), Quirks::chip48(), Quirks::schip(), Quirks::xochip()] {
+            assert_eq!(quirks.to_string().parse(), Ok(quirks));
+        }
+    }
+}
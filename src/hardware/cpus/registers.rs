@@ -0,0 +1,30 @@
+//! Registers
+//! From: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#2.2
+//! The CHIP-8 register file: 16 general purpose 8-bit registers V0 to VF, the 16-bit address register
+//! I, the program counter and a 16 level call stack. Every interpreter core exposes its registers in
+//! this form, which is what debuggers, traces and watchpoints read and write.
+
+#[derive(Clone, Debug)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub pc: u16,
+    pub sp: u8,
+    pub stack: [u16; 16],
+    pub i: u16,
+}
+
+impl Registers {
+    pub fn new() -> Self {
+        Registers {
+            v: [0; 16],
+            pc: 0x200,
+            sp: 0,
+            stack: [0; 16], // 16 levels of stack in (0xEA0-0xEAF) in (0xEA0-0xEFF) 
+            i: 0,
+        }
+    }
+
+    pub fn increment_pc(&mut self) {
+        self.pc = self.pc.wrapping_add(2);
+    }
+}
@@ -1,11 +1,332 @@
-pub struct Display;
+use super::savestate::{StateReader, StateWriter};
 
-const DISPLAY_WIDTH: usize = 64;
-const DISPLAY_HEIGHT: usize = 32;
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+/// SUPER-CHIP's high resolution mode.
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+/// Bits of the two XO-CHIP planes in a pixel.
+pub const PLANES: u8 = 0b11;
+
+/// Display
+/// From: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#2.4
+/// The original implementation of the Chip-8 language used a 64x32-pixel monochrome display.
+/// Sprites are XORed onto the existing screen. If this causes any pixels to be erased, VF is set to 1, otherwise it is set to 0.
+/// SUPER-CHIP adds a 128x64 mode, 16x16 sprites and scrolling. XO-CHIP gives the screen a second
+/// bitplane, so a pixel is one of four colours, and instructions draw, scroll and clear the selected planes.
+#[derive(Clone, Debug)]
+pub struct Display {
+    // one bit per plane
+    pixels: Vec<u8>,
+    width: usize,
+    height: usize,
+    // the planes drawing, scrolling and clearing apply to
+    planes: u8,
+    dirty: bool,
+}
 
 impl Display {
     pub fn new() -> Self {
-        Display
+        Display {
+            pixels: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            width: DISPLAY_WIDTH,
+            height: DISPLAY_HEIGHT,
+            planes: 1,
+            dirty: true,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.width == HIRES_WIDTH
+    }
+
+    /// Switch between 64x32 and 128x64, which clears the screen.
+    pub fn set_hires(&mut self, hires: bool) {
+        (self.width, self.height) = if hires { (HIRES_WIDTH, HIRES_HEIGHT) } else { (DISPLAY_WIDTH, DISPLAY_HEIGHT) };
+        self.pixels = vec![0; self.width * self.height];
+        self.dirty = true;
+    }
+
+    /// The planes to draw on, bit 0 for the first and bit 1 for the second, as XO-CHIP's Fn01 sets them.
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & PLANES;
+    }
+
+    pub fn selected_planes(&self) -> u8 {
+        self.planes
+    }
+
+    /// The number of selected planes, a sprite holds the rows for each of them one after the other.
+    pub fn plane_count(&self) -> usize {
+        self.planes.count_ones() as usize
+    }
+
+    pub fn clear(&mut self) {
+        let planes = self.planes;
+        self.pixels.iter_mut().for_each(|pixel| *pixel &= !planes);
+        self.dirty = true;
+    }
+
+    /// Lit on any plane.
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        self.colour(x, y) != 0
+    }
+
+    /// The planes a pixel is lit on, 0 to 3.
+    pub fn colour(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    /// XOR a sprite onto the screen and return true if any lit pixel was erased.
+    /// The starting position wraps around the screen, the sprite itself is clipped at the edges or
+    /// wraps around as well.
+    /// With both planes selected the sprite holds the rows for the first plane, then those for the second.
+    pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8], clip: bool) -> bool {
+        let mut collision = false;
+        for (plane, rows) in self.plane_data(sprite) {
+            collision |= self.draw_rows(plane, x, y, rows.iter().map(|&row| (row as u16) << 8), 8, clip);
+        }
+        self.dirty = true;
+        collision
+    }
+
+    /// The 16x16 sprite of SUPER-CHIP's Dxy0, two bytes per row.
+    pub fn draw_large_sprite(&mut self, x: u8, y: u8, sprite: &[u8], clip: bool) -> bool {
+        let mut collision = false;
+        for (plane, rows) in self.plane_data(sprite) {
+            let rows = rows.chunks(2).map(|row| u16::from_be_bytes([row[0], row.get(1).copied().unwrap_or(0)]));
+            collision |= self.draw_rows(plane, x, y, rows, 16, clip);
+        }
+        self.dirty = true;
+        collision
+    }
+
+    // each selected plane's bit and its share of the sprite
+    fn plane_data<'a>(&self, sprite: &'a [u8]) -> Vec<(u8, &'a [u8])> {
+        let planes: Vec<u8> = [1, 2].into_iter().filter(|plane| self.planes & plane != 0).collect();
+        if sprite.is_empty() || planes.is_empty() {
+            return Vec::new();
+        }
+        planes.into_iter().zip(sprite.chunks(sprite.len().div_ceil(self.plane_count()))).collect()
+    }
+
+    // rows of up to 16 pixels, the leftmost one in the top bit
+    fn draw_rows(&mut self, plane: u8, x: u8, y: u8, rows: impl Iterator<Item = u16>, width: usize, clip: bool) -> bool {
+        let x = x as usize % self.width;
+        let y = y as usize % self.height;
+        let mut collision = false;
+
+        for (row, pixels) in rows.enumerate() {
+            let py = y + row;
+            if py >= self.height && clip {
+                break;
+            }
+            let py = py % self.height;
+            for bit in 0..width {
+                let px = x + bit;
+                if px >= self.width && clip {
+                    break;
+                }
+                let px = px % self.width;
+                if (pixels & (0x8000 >> bit)) != 0 {
+                    let index = py * self.width + px;
+                    if self.pixels[index] & plane != 0 {
+                        collision = true;
+                    }
+                    self.pixels[index] ^= plane;
+                }
+            }
+        }
+        collision
+    }
+
+    /// Move the picture on the selected planes `dx` pixels right and `dy` down, what scrolls off is
+    /// lost and blank pixels come in.
+    pub fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width as isize, self.height as isize);
+        let planes = self.planes;
+        let mut pixels: Vec<u8> = self.pixels.iter().map(|pixel| pixel & !planes).collect();
+        for y in 0..height {
+            for x in 0..width {
+                let (from_x, from_y) = (x - dx, y - dy);
+                if (0..width).contains(&from_x) && (0..height).contains(&from_y) {
+                    pixels[(y * width + x) as usize] |= self.pixels[(from_y * width + from_x) as usize] & planes;
+                }
+            }
+        }
+        self.pixels = pixels;
+        self.dirty = true;
     }
 
-}
\ No newline at end of file
+    /// The colour of every pixel, row by row.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Returns the dirty flag and clears it, the frontend calls this once per frame.
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+
+    pub fn dump(&self) {
+        for y in 0..self.height {
+            let row: String = (0..self.width)
+                .map(|x| if self.get_pixel(x, y) { '█' } else { ' ' })
+                .collect();
+            println!("{}", row);
+        }
+    }
+
+    /// Size, then each plane's pixels packed eight to a byte, row by row, and the selected planes.
+    pub fn write_state(&self, out: &mut StateWriter) {
+        out.u16(self.width as u16);
+        out.u16(self.height as u16);
+        for plane in [1, 2] {
+            for pixels in self.pixels.chunks(8) {
+                out.u8(pixels.iter().fold(0, |byte, &pixel| byte << 1 | (pixel & plane != 0) as u8));
+            }
+        }
+        out.u8(self.planes);
+    }
+
+    pub fn read_state(&mut self, input: &mut StateReader) -> Result<(), String> {
+        let (width, height) = (input.u16()? as usize, input.u16()? as usize);
+        match (width, height) {
+            (DISPLAY_WIDTH, DISPLAY_HEIGHT) => self.set_hires(false),
+            (HIRES_WIDTH, HIRES_HEIGHT) => self.set_hires(true),
+            _ => return Err(format!("save state screen is {}x{}, not {}x{} or {}x{}", width, height, DISPLAY_WIDTH, DISPLAY_HEIGHT, HIRES_WIDTH, HIRES_HEIGHT)),
+        }
+        let size = self.pixels.len() / 8;
+        self.read_plane(1, input.bytes(size)?);
+        // the second plane was added after the first version of the format
+        if !input.is_empty() {
+            self.read_plane(2, input.bytes(size)?);
+            self.planes = input.u8()? & PLANES;
+        } else {
+            self.planes = 1;
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn read_plane(&mut self, plane: u8, packed: &[u8]) {
+        for (index, pixel) in self.pixels.iter_mut().enumerate() {
+            if packed[index / 8] & 0x80 >> (index % 8) != 0 {
+                *pixel |= plane;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_draw_sprite() {
+        let mut display = Display::new();
+        let collision = display.draw_sprite(0, 0, &[0xF0], true);
+        assert!(!collision);
+        assert!(display.get_pixel(0, 0));
+        assert!(display.get_pixel(3, 0));
+        assert!(!display.get_pixel(4, 0));
+    }
+
+    #[test]
+    fn test_display_collision() {
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, &[0x80], true);
+        let collision = display.draw_sprite(0, 0, &[0x80], true);
+        assert!(collision);
+        assert!(!display.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn test_display_wraps_start_and_clips_sprite() {
+        let mut display = Display::new();
+        display.draw_sprite(64 + 62, 32 + 31, &[0xFF, 0xFF], true);
+        assert!(display.get_pixel(62, 31));
+        assert!(display.get_pixel(63, 31));
+        assert!(!display.get_pixel(0, 31));
+        assert!(!display.get_pixel(62, 0));
+
+        let mut display = Display::new();
+        display.draw_sprite(62, 31, &[0xFF, 0xFF], false);
+        assert!(display.get_pixel(63, 31));
+        assert!(display.get_pixel(0, 31));
+        assert!(display.get_pixel(5, 0));
+        assert!(!display.get_pixel(6, 0));
+    }
+
+    #[test]
+    fn test_display_clear() {
+        let mut display = Display::new();
+        display.draw_sprite(10, 10, &[0xFF], true);
+        display.take_dirty();
+        display.clear();
+        assert!(display.is_dirty());
+        assert!(display.pixels().iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn test_display_hires() {
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, &[0x80], true);
+        display.set_hires(true);
+        assert_eq!((display.width(), display.height()), (HIRES_WIDTH, HIRES_HEIGHT));
+        assert!(display.pixels().iter().all(|&pixel| pixel == 0));
+
+        let mut sprite = [0; 32];
+        sprite[0..2].copy_from_slice(&[0x80, 0x01]);
+        sprite[30..32].copy_from_slice(&[0xFF, 0xFF]);
+        assert!(!display.draw_large_sprite(120, 60, &sprite, true));
+        assert!(display.get_pixel(120, 60));
+        assert!(!display.get_pixel(127, 60));
+        assert!(display.draw_large_sprite(120, 60, &sprite, false));
+        assert!(display.get_pixel(7, 60));
+        assert!(display.get_pixel(0, 11));
+    }
+
+    #[test]
+    fn test_display_scroll() {
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, &[0x80], true);
+        display.draw_sprite(63, 31, &[0x80], true);
+        display.scroll(4, 2);
+        assert!(display.get_pixel(4, 2));
+        assert_eq!(display.pixels().iter().filter(|&&pixel| pixel != 0).count(), 1);
+        display.scroll(-4, 0);
+        assert!(display.get_pixel(0, 2));
+    }
+
+    #[test]
+    fn test_display_planes() {
+        let mut display = Display::new();
+        display.select_planes(PLANES);
+        assert!(!display.draw_sprite(0, 0, &[0xC0, 0x80], true));
+        assert_eq!((display.colour(0, 0), display.colour(1, 0)), (3, 1));
+
+        display.select_planes(2);
+        display.scroll(0, 1);
+        assert_eq!((display.colour(0, 0), display.colour(0, 1)), (1, 2));
+        assert!(display.draw_sprite(0, 1, &[0x80], true));
+        display.clear();
+        assert_eq!(display.pixels().iter().filter(|&&pixel| pixel != 0).count(), 2);
+
+        display.select_planes(0);
+        assert!(!display.draw_sprite(0, 0, &[0xFF], true));
+        assert_eq!(display.colour(2, 0), 0);
+    }
+}
@@ -0,0 +1,29 @@
+use std::fmt;
+
+/// Everything that stops the emulated machine. The CPU returns these instead of panicking so a
+/// frontend can show what went wrong and tests can assert on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmulationError {
+    UnknownOpcode { opcode: u16, address: u16 },
+    StackOverflow { address: u16 },
+    StackUnderflow { address: u16 },
+    OutOfBounds { address: u16 },
+    WriteProtected { address: u16 },
+    /// A ROM that runs past the end of memory when loaded at `address`.
+    RomTooLarge { address: u16, size: usize },
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulationError::UnknownOpcode { opcode, address } => write!(f, "unknown opcode {:04X} at {:04X}", opcode, address),
+            EmulationError::StackOverflow { address } => write!(f, "stack overflow, CALL at {:04X} with 16 return addresses on the stack", address),
+            EmulationError::StackUnderflow { address } => write!(f, "stack underflow, RET at {:04X} with an empty stack", address),
+            EmulationError::OutOfBounds { address } => write!(f, "memory access out of bounds at {:04X}", address),
+            EmulationError::WriteProtected { address } => write!(f, "write to reserved memory at {:04X}", address),
+            EmulationError::RomTooLarge { address, size } => write!(f, "ROM of {} bytes does not fit in memory at {:04X}", size, address),
+        }
+    }
+}
+
+impl std::error::Error for EmulationError {}
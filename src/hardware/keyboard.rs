@@ -1,7 +1,102 @@
-pub struct Keyboard;
+//! Keyboard
+//! From: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#2.3
+//! The computers which originally used the Chip-8 Language had a 16-key hexadecimal keypad with the following layout:
+//! 1 2 3 C
+//! 4 5 6 D
+//! 7 8 9 E
+//! A 0 B F
+
+use super::savestate::{StateReader, StateWriter};
+
+pub const KEY_COUNT: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct Keyboard {
+    keys: [bool; KEY_COUNT],
+    // keys that went down since the last call to begin_wait, used by Fx0A
+    pressed_since_wait: [bool; KEY_COUNT],
+}
 
 impl Keyboard {
     pub fn new() -> Self {
-        Keyboard
+        Keyboard {
+            keys: [false; KEY_COUNT],
+            pressed_since_wait: [false; KEY_COUNT],
+        }
+    }
+
+    pub fn press(&mut self, key: u8) {
+        let key = key as usize & 0xF;
+        self.keys[key] = true;
+        self.pressed_since_wait[key] = true;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.keys[key as usize & 0xF] = false;
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.keys[key as usize & 0xF]
+    }
+
+    /// Forget earlier presses, only keys pressed after this call count for `released_key`.
+    pub fn begin_wait(&mut self) {
+        self.pressed_since_wait = [false; KEY_COUNT];
+    }
+
+    /// The first key that was both pressed and released since `begin_wait`.
+    /// The COSMAC VIP only returned from Fx0A once the key was let go again.
+    pub fn released_key(&self) -> Option<u8> {
+        (0..KEY_COUNT)
+            .find(|&key| self.pressed_since_wait[key] && !self.keys[key])
+            .map(|key| key as u8)
+    }
+
+    pub fn reset(&mut self) {
+        *self = Keyboard::new();
+    }
+
+    pub fn write_state(&self, out: &mut StateWriter) {
+        let mask = |keys: &[bool; KEY_COUNT]| keys.iter().rev().fold(0u16, |mask, &down| mask << 1 | down as u16);
+        out.u16(mask(&self.keys));
+        out.u16(mask(&self.pressed_since_wait));
+    }
+
+    pub fn read_state(&mut self, input: &mut StateReader) -> Result<(), String> {
+        let (keys, pressed) = (input.u16()?, input.u16()?);
+        for key in 0..KEY_COUNT {
+            self.keys[key] = keys & 1 << key != 0;
+            self.pressed_since_wait[key] = pressed & 1 << key != 0;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyboard_press_release() {
+        let mut keyboard = Keyboard::new();
+        keyboard.press(0xA);
+        assert!(keyboard.is_pressed(0xA));
+        keyboard.release(0xA);
+        assert!(!keyboard.is_pressed(0xA));
+    }
+
+    #[test]
+    fn test_keyboard_released_key_needs_press_after_wait() {
+        let mut keyboard = Keyboard::new();
+        keyboard.press(0x1);
+        keyboard.begin_wait();
+        keyboard.release(0x1);
+        assert_eq!(keyboard.released_key(), None);
+
+        keyboard.press(0x5);
+        assert_eq!(keyboard.released_key(), None);
+        keyboard.release(0x5);
+        assert_eq!(keyboard.released_key(), Some(0x5));
     }
 }
@@ -1,26 +1,33 @@
-/// Memory
-/// From: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#memmap
-/// The Chip-8 language is capable of accessing up to 4KB (4,096 bytes) of RAM, from location 0x000 (0) to 0xFFF (4095). The first 512 bytes, from 0x000 to 0x1FF, are where the original interpreter was located, and should not be used by programs.
-/// Most Chip-8 programs start at location 0x200 (512), but some begin at 0x600 (1536). Programs beginning at 0x600 are intended for the ETI 660 computer.
-/// The uppermost 256 bytes (0xF00-0xFFF) are reserved for display refresh, and the 96 bytes below that (0xEA0-0xEFF) are reserved for call stack, internal use, and other variables.
-
-const MEMORY_SIZE: usize = 0x1000; // 4096 bytes or 4kb
-const RESERVED_MEMORY_SIZE: usize = 0x200; // 512 bytes or 0.5kb
+//! Memory
+//! From: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM#memmap
+//! The Chip-8 language is capable of accessing up to 4KB (4,096 bytes) of RAM, from location 0x000 (0) to 0xFFF (4095). The first 512 bytes, from 0x000 to 0x1FF, are where the original interpreter was located, and should not be used by programs.
+//! Most Chip-8 programs start at location 0x200 (512), but some begin at 0x600 (1536). Programs beginning at 0x600 are intended for the ETI 660 computer.
+//! The uppermost 256 bytes (0xF00-0xFFF) are reserved for display refresh, and the 96 bytes below that (0xEA0-0xEFF) are reserved for call stack, internal use, and other variables.
+
+use super::error::EmulationError;
+use super::savestate::{StateReader, StateWriter};
+
+pub const MEMORY_SIZE: usize = 0x1000; // 4096 bytes or 4kb
+/// XO-CHIP addresses 64kb, the whole range of I.
+pub const XO_MEMORY_SIZE: usize = 0x10000;
+pub const RESERVED_MEMORY_SIZE: usize = 0x200; // 512 bytes or 0.5kb
+/// The 5-byte digits of Fx29 start at 0x000, SUPER-CHIP's 10-byte ones for Fx30 follow them.
+pub const BIG_FONT_ADDRESS: u16 = 0x050;
 
 #[derive(Clone, Debug)]
 pub struct Memory {
-    memory: [u8; MEMORY_SIZE],
+    memory: Vec<u8>,
 }
 
-struct ROM {
+struct Rom {
     rom: [u8; RESERVED_MEMORY_SIZE],
 }
 
-struct RAM {
-    ram: [u8; MEMORY_SIZE - RESERVED_MEMORY_SIZE],
+struct Ram {
+    ram: Vec<u8>,
 }
 
-impl ROM {
+impl Rom {
     pub fn new() -> Self {
         let mut rom = [0; RESERVED_MEMORY_SIZE];
         let fonts = [
@@ -28,7 +35,7 @@ impl ROM {
             0x20, 0x60, 0x20, 0x20, 0x70, //one
             0xF0, 0x10, 0xF0, 0x80, 0xF0, //two
             0xF0, 0x10, 0xF0, 0x10, 0xF0, //three
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, //four
+            0x90, 0x90, 0xF0, 0x10, 0x10, //four
             0xF0, 0x80, 0xF0, 0x10, 0xF0, //five
             0xF0, 0x80, 0xF0, 0x90, 0xF0, //six
             0xF0, 0x10, 0x20, 0x40, 0x40, //seven
@@ -41,26 +48,51 @@ impl ROM {
             0xF0, 0x80, 0xF0, 0x80, 0xF0, //E
             0xF0, 0x80, 0xF0, 0x80, 0x80, //F
         ];
+        let big_fonts = [
+            0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, //zero
+            0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, //one
+            0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, //two
+            0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, //three
+            0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, //four
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, //five
+            0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, //six
+            0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, //seven
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, //eight
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, //nine
+            0x18, 0x3C, 0x66, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, //A
+            0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, //B
+            0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, //C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, //D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF, //E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0, //F
+        ];
         rom[..fonts.len()].copy_from_slice(&fonts);
+        let big_font_address = BIG_FONT_ADDRESS as usize;
+        rom[big_font_address..big_font_address + big_fonts.len()].copy_from_slice(&big_fonts);
         Self {
             rom,
         }
     }
 }
 
-impl RAM {
-    pub fn new() -> Self {
+impl Ram {
+    pub fn new(size: usize) -> Self {
         Self {
-            ram: [0; MEMORY_SIZE - RESERVED_MEMORY_SIZE],
+            ram: vec![0; size - RESERVED_MEMORY_SIZE],
         }
     }
 }
 
 impl Memory {
     pub fn new() -> Self {
-            let mut memory = [0; MEMORY_SIZE];
-            let rom = ROM::new().rom;
-            let ram = RAM::new().ram;
+        Memory::with_size(MEMORY_SIZE)
+    }
+
+    /// `size` bytes of memory, `MEMORY_SIZE` or XO-CHIP's `XO_MEMORY_SIZE`.
+    pub fn with_size(size: usize) -> Self {
+            let mut memory = vec![0; size];
+            let rom = Rom::new().rom;
+            let ram = Ram::new(size).ram;
     
             // Copy ROM contents into memory
             memory[..RESERVED_MEMORY_SIZE].copy_from_slice(&rom);
@@ -70,38 +102,57 @@ impl Memory {
             Self { memory }
     }
 
-    pub fn read(&self, address: u16) -> u8 {
-        self.memory[address as usize]
+    pub fn size(&self) -> usize {
+        self.memory.len()
     }
 
-    pub fn write(&mut self, address: u16, value: u8) {
+    pub fn read(&self, address: u16) -> Result<u8, EmulationError> {
+        self.memory
+            .get(address as usize)
+            .copied()
+            .ok_or(EmulationError::OutOfBounds { address })
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) -> Result<(), EmulationError> {
         if address < RESERVED_MEMORY_SIZE as u16 {
-            panic!("Attempted to write to reserved memory address: {:04X}", address);
+            return Err(EmulationError::WriteProtected { address });
         }
-        self.memory[address as usize] = value;
+        let byte = self.memory
+            .get_mut(address as usize)
+            .ok_or(EmulationError::OutOfBounds { address })?;
+        *byte = value;
+        Ok(())
     }
 
     pub fn dump(&self, start: u16, end: u16) {
-        for i in start..=end {
-            if i % 16 == 0 {
-                println!();
-            }
-            print!("{:02X} ", self.memory[i as usize]);
+        for line in self.dump_lines(start, end) {
+            println!("{}", line);
         }
-        println!();
     }
 
-    pub fn load_rom(&mut self, rom: Vec<u8>) {
-        for (i, byte) in rom.iter().enumerate() {
-            self.memory[i + RESERVED_MEMORY_SIZE] = *byte;
-        }
-        // panic!("ROM loaded into memory");
-        // The above copies the data in little endian format, which is not what we want??? 
-        //self.memory[RESERVED_MEMORY_SIZE..].copy_from_slice(&rom);
-        // use a for loop to copy the data into memory
-        // for (i, byte) in rom.iter().enumerate() {
-        //     print!("{:?} {:02X} ", i, byte);
-        // }
+    /// Rows of 16 bytes from the row holding `start` to the one holding `end`, each led by its address.
+    pub fn dump_lines(&self, start: u16, end: u16) -> Vec<String> {
+        let end = end.min((self.memory.len() - 1) as u16);
+        (start & !0xF..=end)
+            .step_by(16)
+            .map(|row| {
+                let bytes: Vec<String> = (row..=row + 0xF).map(|i| format!("{:02X}", self.memory[i as usize])).collect();
+                format!("{:03X}: {}", row, bytes.join(" "))
+            })
+            .collect()
+    }
+
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), EmulationError> {
+        self.load_rom_at(rom, RESERVED_MEMORY_SIZE as u16)
+    }
+
+    pub fn load_rom_at(&mut self, rom: Vec<u8>, address: u16) -> Result<(), EmulationError> {
+        let start = address as usize;
+        let target = self.memory
+            .get_mut(start..start + rom.len())
+            .ok_or(EmulationError::RomTooLarge { address, size: rom.len() })?;
+        target.copy_from_slice(&rom);
+        Ok(())
     }
 
     pub fn get_reserved_end(&self) -> u16 {
@@ -109,7 +160,17 @@ impl Memory {
     }
 
     pub fn reset(&mut self) {
-        self.memory = Memory::new().memory;
+        self.memory = Memory::with_size(self.memory.len()).memory;
+    }
+
+    pub fn write_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.memory);
+    }
+
+    pub fn read_state(&mut self, input: &mut StateReader) -> Result<(), String> {
+        let size = self.memory.len();
+        self.memory.copy_from_slice(input.bytes(size)?);
+        Ok(())
     }
 }
 
@@ -121,16 +182,38 @@ mod tests {
     #[test]
     fn test_memory_read_write() {
         let mut memory = Memory::new();
-        memory.write(0x0000, 0xFF);
-        assert_eq!(memory.read(0x0000), 0xFF);
+        memory.write(0x0200, 0xFF).unwrap();
+        assert_eq!(memory.read(0x0200), Ok(0xFF));
     }
 
     #[test]
-    #[should_panic]
     fn test_memory_write_reserved() {
         let mut memory = Memory::new();
-        memory.write(0x0000, 0xFF);
-        memory.write(0x01FF, 0xFF);
+        assert_eq!(memory.write(0x0000, 0xFF), Err(EmulationError::WriteProtected { address: 0x0000 }));
+        assert_eq!(memory.write(0x01FF, 0xFF), Err(EmulationError::WriteProtected { address: 0x01FF }));
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.read(0x1000), Err(EmulationError::OutOfBounds { address: 0x1000 }));
+        assert_eq!(memory.write(0x1000, 0xFF), Err(EmulationError::OutOfBounds { address: 0x1000 }));
+    }
+
+    #[test]
+    fn test_memory_dump_lines() {
+        let memory = Memory::new();
+        let lines = memory.dump_lines(0x005, 0x01F);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "000: F0 90 90 90 F0 20 60 20 20 70 F0 10 F0 80 F0 F0");
+        assert!(lines[1].starts_with("010: "));
+    }
+
+    #[test]
+    fn test_memory_font_four() {
+        let memory = Memory::new();
+        let glyph: Vec<u8> = (20..25).map(|address| memory.read(address).unwrap()).collect();
+        assert_eq!(glyph, [0x90, 0x90, 0xF0, 0x10, 0x10]);
     }
 
     #[test]
@@ -139,11 +222,29 @@ mod tests {
         assert_eq!(memory.get_reserved_end(), 0x200);
     }
 
+    #[test]
+    fn test_memory_load_rom_out_of_bounds() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.load_rom_at(vec![1, 2], 0xFFE), Ok(()));
+        assert_eq!(memory.read(0xFFF), Ok(2));
+        assert_eq!(memory.load_rom_at(vec![1, 2, 3], 0xFFE), Err(EmulationError::RomTooLarge { address: 0xFFE, size: 3 }));
+        assert_eq!(memory.load_rom(vec![0; 0xE01]), Err(EmulationError::RomTooLarge { address: 0x200, size: 0xE01 }));
+    }
+
     #[test]
     fn test_memory_reset() {
         let mut memory = Memory::new();
-        memory.write(0x0000, 0xFF);
+        memory.write(0x0200, 0xFF).unwrap();
+        memory.reset();
+        assert_eq!(memory.read(0x0200), Ok(0x00));
+    }
+
+    #[test]
+    fn test_memory_xo_size() {
+        let mut memory = Memory::with_size(XO_MEMORY_SIZE);
+        memory.write(0xFFFF, 0xAB).unwrap();
         memory.reset();
-        assert_eq!(memory.read(0x0000), 0x00);
+        assert_eq!((memory.size(), memory.read(0xFFFF)), (XO_MEMORY_SIZE, Ok(0)));
+        assert_eq!(memory.read(BIG_FONT_ADDRESS), Ok(0x3C));
     }
 }
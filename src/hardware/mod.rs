@@ -1,8 +1,15 @@
+pub mod bus;
 pub mod cartridge;
 pub mod clock;
 pub mod computer;
 pub mod display;
+pub mod error;
 pub mod keyboard;
 pub mod memory;
+pub mod rewind;
+pub mod savestate;
+pub mod sound;
+pub mod trace;
+pub mod watch;
 
 pub mod cpus;
\ No newline at end of file
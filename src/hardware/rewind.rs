@@ -0,0 +1,180 @@
+//! Rewind
+//! A history of save states taken at every frame, to run the machine backwards. Only the newest
+//! snapshot is kept whole, every older one is stored as the few bytes that differ from the snapshot
+//! after it, which is usually a handful of registers and the odd sprite. Going back a frame rebuilds
+//! the previous snapshot from the newest one and its delta, so the history can be walked back to the
+//! oldest frame it holds.
+
+use std::collections::VecDeque;
+
+pub struct Rewind {
+    capacity: usize,
+    // the newest snapshot and the instruction count it was taken at
+    newest: Option<(u128, Vec<u8>)>,
+    // older snapshots, oldest first, each as the delta that turns the snapshot after it back into it
+    older: VecDeque<(u128, Vec<u8>)>,
+}
+
+impl Rewind {
+    /// Keep up to `capacity` snapshots, one per frame.
+    pub fn new(capacity: usize) -> Self {
+        Rewind {
+            capacity: capacity.max(1),
+            newest: None,
+            older: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.older.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Bytes held by the snapshots.
+    pub fn size(&self) -> usize {
+        self.newest.as_ref().map_or(0, |(_, state)| state.len()) + self.older.iter().map(|(_, delta)| delta.len()).sum::<usize>()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.older.clear();
+    }
+
+    pub fn push(&mut self, cycles: u128, state: Vec<u8>) {
+        if let Some((previous_cycles, previous)) = self.newest.take() {
+            self.older.push_back((previous_cycles, delta(&state, &previous)));
+            if self.older.len() >= self.capacity {
+                self.older.pop_front();
+            }
+        }
+        self.newest = Some((cycles, state));
+    }
+
+    /// Forget every snapshot taken after instruction `cycles` and return the newest one left, if any.
+    pub fn rewind_to(&mut self, cycles: u128) -> Option<(u128, &[u8])> {
+        while self.newest.as_ref().is_some_and(|(newest, _)| *newest > cycles) {
+            let (_, newest) = self.newest.take()?;
+            self.newest = self.older.pop_back().map(|(cycles, delta)| (cycles, apply(&newest, &delta)));
+        }
+        self.newest.as_ref().map(|(cycles, state)| (*cycles, state.as_slice()))
+    }
+}
+
+const WHOLE: u8 = 0;
+const RUNS: u8 = 1;
+
+/// `to` as changes to `from`: runs of `skip count bytes...`, or the whole of `to` when the sizes differ.
+fn delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    if from.len() != to.len() {
+        let mut delta = vec![WHOLE];
+        delta.extend(to);
+        return delta;
+    }
+    let mut delta = vec![RUNS];
+    let mut position = 0;
+    while position < to.len() {
+        let start = match (position..to.len()).find(|&i| from[i] != to[i]) {
+            Some(start) => start,
+            None => break,
+        };
+        // a run ends at the first stretch of a few equal bytes, shorter gaps are cheaper to copy
+        let mut end = start;
+        while end < to.len() && (end..(end + 4).min(to.len())).any(|i| from[i] != to[i]) {
+            end += 1;
+        }
+        write_number(&mut delta, start - position);
+        write_number(&mut delta, end - start);
+        delta.extend(&to[start..end]);
+        position = end;
+    }
+    delta
+}
+
+fn apply(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let (kind, mut rest) = match delta.split_first() {
+        Some((kind, rest)) => (*kind, rest),
+        None => return from.to_vec(),
+    };
+    if kind == WHOLE {
+        return rest.to_vec();
+    }
+    let mut to = from.to_vec();
+    let mut position = 0;
+    while !rest.is_empty() {
+        position += read_number(&mut rest);
+        let count = read_number(&mut rest);
+        to[position..position + count].copy_from_slice(&rest[..count]);
+        rest = &rest[count..];
+        position += count;
+    }
+    to
+}
+
+/// LEB128, seven bits at a time with the high bit set on all but the last byte.
+fn write_number(out: &mut Vec<u8>, mut number: usize) {
+    while number >= 0x80 {
+        out.push(number as u8 | 0x80);
+        number >>= 7;
+    }
+    out.push(number as u8);
+}
+
+fn read_number(input: &mut &[u8]) -> usize {
+    let mut number = 0;
+    let mut shift = 0;
+    while let Some((&byte, rest)) = input.split_first() {
+        *input = rest;
+        number |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    number
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta() {
+        let from: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut to = from.clone();
+        to[3] = 0;
+        to[5] = 0;
+        to[700..900].fill(7);
+        let delta = delta(&from, &to);
+        assert!(delta.len() < 220, "{} bytes", delta.len());
+        assert_eq!(apply(&from, &delta), to);
+
+        assert_eq!(super::delta(&from, &from), vec![RUNS]);
+        assert_eq!(apply(&from, &super::delta(&from, &to[..10])), &to[..10]);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let state = |frame: u8| -> Vec<u8> {
+            let mut state = vec![0; 4096];
+            state[100] = frame;
+            state[2000..2000 + frame as usize].fill(frame);
+            state
+        };
+        let mut rewind = Rewind::new(4);
+        for frame in 0..6u8 {
+            rewind.push(frame as u128 * 10, state(frame));
+        }
+        assert_eq!(rewind.len(), 4);
+        assert!(rewind.size() < 4096 + 100);
+
+        assert_eq!(rewind.rewind_to(55), Some((50, &state(5)[..])));
+        assert_eq!(rewind.rewind_to(49), Some((40, &state(4)[..])));
+        assert_eq!(rewind.rewind_to(20), Some((20, &state(2)[..])));
+        assert_eq!(rewind.rewind_to(19), None);
+        assert!(rewind.is_empty());
+    }
+}
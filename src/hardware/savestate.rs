@@ -0,0 +1,231 @@
+//! Save states
+//! The whole machine in a file: registers and stack, memory, the screen, keypad, timers, the XO-CHIP
+//! audio pattern, the random number generator and the clock counters. A state is a header followed by
+//! tagged chunks that carry their length, so a build skips chunks it does not know and fields later
+//! builds append to a chunk.
+//! The header holds the format version that wrote the state and the oldest version able to read it,
+//! a change older builds would misread bumps the latter and they refuse the file instead.
+
+use std::path::PathBuf;
+
+use super::computer::Computer;
+use super::cpus::Cpu;
+
+const MAGIC: &[u8; 4] = b"C8SS";
+/// Format written by this build.
+const VERSION: u8 = 1;
+// oldest format version that can read what this build writes
+const COMPATIBLE_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 6;
+
+pub type Tag = [u8; 4];
+
+/// Appends big endian fields and length prefixed chunks.
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([VERSION, COMPATIBLE_VERSION]);
+        StateWriter { bytes }
+    }
+
+    pub fn chunk(&mut self, tag: &Tag, write: impl FnOnce(&mut StateWriter)) {
+        self.bytes.extend(tag);
+        let length_at = self.bytes.len();
+        self.bytes.extend([0; 4]);
+        write(self);
+        let length = (self.bytes.len() - length_at - 4) as u32;
+        self.bytes[length_at..length_at + 4].copy_from_slice(&length.to_be_bytes());
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend(value.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend(value.to_be_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads the fields of one chunk. Running out of fields is an error, fields left over are ignored.
+pub struct StateReader<'a> {
+    tag: Tag,
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_be_bytes(value))
+    }
+
+    /// True when every field has been read, fields a later format version appended are optional to older states.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < count {
+            return Err(format!("save state chunk '{}' is truncated", String::from_utf8_lossy(&self.tag)));
+        }
+        let (bytes, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+}
+
+/// The chunks of a state, after checking its header.
+pub struct StateChunks<'a> {
+    chunks: Vec<StateReader<'a>>,
+}
+
+impl<'a> StateChunks<'a> {
+    pub fn parse(state: &'a [u8]) -> Result<Self, String> {
+        if state.len() < HEADER_SIZE || &state[..4] != MAGIC {
+            return Err(String::from("not a chip8rs save state"));
+        }
+        let (version, compatible) = (state[4], state[5]);
+        if compatible > VERSION {
+            return Err(format!("save state format {} needs a newer version of chip8rs", version));
+        }
+        let mut chunks = Vec::new();
+        let mut rest = &state[HEADER_SIZE..];
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(String::from("save state is truncated"));
+            }
+            let tag = [rest[0], rest[1], rest[2], rest[3]];
+            let length = u32::from_be_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+            let bytes = rest[8..].get(..length).ok_or("save state is truncated")?;
+            chunks.push(StateReader { tag, bytes });
+            rest = &rest[8 + length..];
+        }
+        Ok(StateChunks { chunks })
+    }
+
+    pub fn get(&self, tag: &Tag) -> Option<StateReader<'a>> {
+        self.chunks.iter().find(|chunk| &chunk.tag == tag).map(|chunk| StateReader { tag: chunk.tag, bytes: chunk.bytes })
+    }
+
+    pub fn require(&self, tag: &Tag) -> Result<StateReader<'a>, String> {
+        self.get(tag).ok_or_else(|| format!("save state has no '{}' chunk", String::from_utf8_lossy(tag)))
+    }
+}
+
+/// Numbered save state files next to the ROM: `PONG.state0` to `PONG.state9`.
+pub struct SaveSlots {
+    base: PathBuf,
+    pub slot: u8,
+}
+
+impl SaveSlots {
+    pub const COUNT: u8 = 10;
+
+    pub fn new(rom_path: &str) -> Self {
+        SaveSlots {
+            base: PathBuf::from(rom_path),
+            slot: 0,
+        }
+    }
+
+    pub fn path(&self, slot: u8) -> PathBuf {
+        let mut name = self.base.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".state{}", slot));
+        self.base.with_file_name(name)
+    }
+
+    pub fn next(&mut self) {
+        self.slot = (self.slot + 1) % Self::COUNT;
+    }
+
+    pub fn previous(&mut self) {
+        self.slot = (self.slot + Self::COUNT - 1) % Self::COUNT;
+    }
+
+    pub fn save<C: Cpu>(&self, computer: &Computer<C>) -> Result<PathBuf, String> {
+        let path = self.path(self.slot);
+        std::fs::write(&path, computer.save_state()).map_err(|e| format!("unable to write '{}': {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    pub fn load<C: Cpu>(&self, computer: &mut Computer<C>) -> Result<PathBuf, String> {
+        let path = self.path(self.slot);
+        let state = std::fs::read(&path).map_err(|e| format!("unable to read '{}': {}", path.display(), e))?;
+        computer.load_state(&state).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(path)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks() {
+        let mut writer = StateWriter::new();
+        writer.chunk(b"ONE ", |out| {
+            out.u8(1);
+            out.u16(0x0203);
+        });
+        writer.chunk(b"TWO ", |out| out.u64(4));
+        let state = writer.finish();
+
+        let chunks = StateChunks::parse(&state).unwrap();
+        let mut one = chunks.require(b"ONE ").unwrap();
+        assert_eq!((one.u8(), one.u16()), (Ok(1), Ok(0x0203)));
+        assert!(one.u8().unwrap_err().contains("truncated"));
+        assert_eq!(chunks.require(b"TWO ").unwrap().u64(), Ok(4));
+        assert!(chunks.get(b"NONE").is_none());
+
+        assert!(StateChunks::parse(&state[..state.len() - 1]).is_err());
+        assert!(StateChunks::parse(b"C8SV\x01\x01").is_err());
+    }
+
+    #[test]
+    fn test_versions() {
+        // a later format older builds can still read, with a chunk they do not know
+        let mut state = StateWriter::new().finish();
+        state[4] = VERSION + 1;
+        state.extend(b"NEW \x00\x00\x00\x02ab");
+        assert!(StateChunks::parse(&state).is_ok());
+
+        state[5] = VERSION + 1;
+        assert!(StateChunks::parse(&state).is_err_and(|e| e.contains("newer version")));
+    }
+
+    #[test]
+    fn test_slot_paths() {
+        let mut slots = SaveSlots::new("data/PONG");
+        assert_eq!(slots.path(3), PathBuf::from("data/PONG.state3"));
+        slots.previous();
+        assert_eq!(slots.slot, 9);
+        slots.next();
+        assert_eq!(slots.slot, 0);
+    }
+}
@@ -0,0 +1,84 @@
+//! Sound
+//! XO-CHIP's audio registers: a pattern of 128 one bit samples, loaded from memory with F002 and
+//! played while the sound timer runs, and the pitch it plays at, set with Fx3A. A program that never
+//! loads a pattern gets the plain CHIP-8 beeper.
+
+use super::savestate::{StateReader, StateWriter};
+
+pub const PATTERN_SIZE: usize = 16;
+pub const PATTERN_BITS: usize = PATTERN_SIZE * 8;
+/// Plays the pattern at 4000 samples a second.
+pub const DEFAULT_PITCH: u8 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sound {
+    pub pattern: Option<[u8; PATTERN_SIZE]>,
+    pub pitch: u8,
+}
+
+impl Sound {
+    pub fn new() -> Self {
+        Sound {
+            pattern: None,
+            pitch: DEFAULT_PITCH,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Sound::new();
+    }
+
+    /// Pattern samples played per second, 4000 * 2 ^ ((pitch - 64) / 48).
+    pub fn playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    /// Whether sample `index` of the pattern is set, bits are played from the top bit of the first byte.
+    pub fn bit(pattern: &[u8; PATTERN_SIZE], index: usize) -> bool {
+        let index = index % PATTERN_BITS;
+        pattern[index / 8] & 0x80 >> (index % 8) != 0
+    }
+
+    pub fn write_state(&self, out: &mut StateWriter) {
+        out.u8(self.pattern.is_some() as u8);
+        out.bytes(&self.pattern.unwrap_or_default());
+        out.u8(self.pitch);
+    }
+
+    pub fn read_state(&mut self, input: &mut StateReader) -> Result<(), String> {
+        let loaded = input.u8()? != 0;
+        let mut pattern = [0; PATTERN_SIZE];
+        pattern.copy_from_slice(input.bytes(PATTERN_SIZE)?);
+        self.pattern = loaded.then_some(pattern);
+        self.pitch = input.u8()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playback_rate() {
+        let mut sound = Sound::new();
+        assert_eq!(sound.playback_rate(), 4000.0);
+        sound.pitch = 64 + 48;
+        assert_eq!(sound.playback_rate(), 8000.0);
+        sound.pitch = 16;
+        assert_eq!(sound.playback_rate(), 2000.0);
+    }
+
+    #[test]
+    fn test_pattern_bits() {
+        let mut pattern = [0; PATTERN_SIZE];
+        pattern[0] = 0x80;
+        pattern[15] = 0x01;
+        assert!(Sound::bit(&pattern, 0));
+        assert!(!Sound::bit(&pattern, 1));
+        assert!(Sound::bit(&pattern, 127));
+        // wraps around to the start
+        assert!(Sound::bit(&pattern, 128));
+    }
+}
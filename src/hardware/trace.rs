@@ -0,0 +1,513 @@
+//! Trace
+//! Instruction trace: the address, opcode and changed registers of every instruction that passes a
+//! filter on address range and opcode class. Records are kept in a ring buffer or written out, either
+//! as text or in a compact binary format that `read_trace` reads back for dumping as text.
+//! The `Computer` only looks at an instruction when a tracer is attached, without one tracing costs nothing.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use super::clock::Timers;
+use super::cpus::registers::Registers;
+use super::cpus::instruction::Instruction;
+
+const MAGIC: &[u8; 4] = b"C8TR";
+const VERSION: u8 = 1;
+const MNEMONIC_WIDTH: usize = 20;
+
+/// What an instruction is for, to trace only the kinds of interest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpcodeClass {
+    /// SYS, JP, CALL, RET, JP V0 and EXIT.
+    Flow,
+    /// SE and SNE.
+    Skip,
+    /// LD of a byte, a register, I, I LONG or the RPL flags.
+    Load,
+    /// ADD, SUB, SUBN, OR, AND, XOR, SHR, SHL and RND.
+    Alu,
+    /// Instructions that use memory at I: ADD I, LD F, LD HF, LD B and the loads and stores of registers.
+    Memory,
+    /// CLS, DRW, the scrolls, LOW, HIGH and PLANE.
+    Display,
+    /// SKP, SKNP and LD Vx, K.
+    Input,
+    /// The delay and sound timers.
+    Timer,
+}
+
+impl OpcodeClass {
+    pub fn of(instruction: Instruction) -> Self {
+        match instruction {
+            Instruction::Sys(_)
+            | Instruction::Jump(_)
+            | Instruction::Call(_)
+            | Instruction::Ret
+            | Instruction::JumpV0(_)
+            | Instruction::Exit => OpcodeClass::Flow,
+            Instruction::SkipEqualByte { .. }
+            | Instruction::SkipNotEqualByte { .. }
+            | Instruction::SkipEqual { .. }
+            | Instruction::SkipNotEqual { .. } => OpcodeClass::Skip,
+            Instruction::LoadByte { .. }
+            | Instruction::Load { .. }
+            | Instruction::LoadI(_)
+            | Instruction::LoadILong(_)
+            | Instruction::StoreFlags { .. }
+            | Instruction::LoadFlags { .. } => OpcodeClass::Load,
+            Instruction::AddByte { .. }
+            | Instruction::Or { .. }
+            | Instruction::And { .. }
+            | Instruction::Xor { .. }
+            | Instruction::Add { .. }
+            | Instruction::Sub { .. }
+            | Instruction::ShiftRight { .. }
+            | Instruction::SubN { .. }
+            | Instruction::ShiftLeft { .. }
+            | Instruction::Random { .. } => OpcodeClass::Alu,
+            Instruction::AddI { .. }
+            | Instruction::LoadFont { .. }
+            | Instruction::LoadBigFont { .. }
+            | Instruction::StoreBcd { .. }
+            | Instruction::StoreRegisters { .. }
+            | Instruction::LoadRegisters { .. }
+            | Instruction::StoreRange { .. }
+            | Instruction::LoadRange { .. }
+            | Instruction::LoadPattern => OpcodeClass::Memory,
+            Instruction::Cls
+            | Instruction::Draw { .. }
+            | Instruction::ScrollDown(_)
+            | Instruction::ScrollUp(_)
+            | Instruction::SelectPlanes(_)
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::LowRes
+            | Instruction::HighRes => OpcodeClass::Display,
+            Instruction::SkipKeyPressed { .. } | Instruction::SkipKeyNotPressed { .. } | Instruction::WaitKey { .. } => {
+                OpcodeClass::Input
+            },
+            Instruction::LoadDelay { .. }
+            | Instruction::SetDelay { .. }
+            | Instruction::SetSound { .. }
+            | Instruction::SetPitch { .. } => OpcodeClass::Timer,
+        }
+    }
+}
+
+impl FromStr for OpcodeClass {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "flow" => Ok(OpcodeClass::Flow),
+            "skip" => Ok(OpcodeClass::Skip),
+            "load" => Ok(OpcodeClass::Load),
+            "alu" => Ok(OpcodeClass::Alu),
+            "memory" => Ok(OpcodeClass::Memory),
+            "display" => Ok(OpcodeClass::Display),
+            "input" => Ok(OpcodeClass::Input),
+            "timer" => Ok(OpcodeClass::Timer),
+            _ => Err(format!(
+                "'{}' is not an opcode class, expected flow, skip, load, alu, memory, display, input or timer",
+                text
+            )),
+        }
+    }
+}
+
+/// Which instructions get traced, everything by default.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFilter {
+    pub range: Option<RangeInclusive<u16>>,
+    /// Empty for all classes.
+    pub classes: Vec<OpcodeClass>,
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        TraceFilter {
+            range: None,
+            classes: Vec::new(),
+        }
+    }
+
+    pub fn with_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    pub fn with_classes(mut self, classes: Vec<OpcodeClass>) -> Self {
+        self.classes = classes;
+        self
+    }
+
+    /// Opcodes that are not instructions always pass the class filter, they are about to halt the program.
+    pub fn accepts(&self, pc: u16, opcode: u16) -> bool {
+        self.range.as_ref().is_none_or(|range| range.contains(&pc))
+            && (self.classes.is_empty()
+                || Instruction::decode_long(opcode, 0).is_none_or(|instruction| self.classes.contains(&OpcodeClass::of(instruction))))
+    }
+}
+
+/// A register changed by an instruction. The program counter is left out, the next record has it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceRegister {
+    V(u8),
+    I,
+    Sp,
+    Dt,
+    St,
+}
+
+impl TraceRegister {
+    fn id(self) -> u8 {
+        match self {
+            TraceRegister::V(x) => x,
+            TraceRegister::I => 16,
+            TraceRegister::Sp => 17,
+            TraceRegister::Dt => 18,
+            TraceRegister::St => 19,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0..=15 => Some(TraceRegister::V(id)),
+            16 => Some(TraceRegister::I),
+            17 => Some(TraceRegister::Sp),
+            18 => Some(TraceRegister::Dt),
+            19 => Some(TraceRegister::St),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TraceRegister {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceRegister::V(x) => write!(f, "V{:X}", x),
+            TraceRegister::I => write!(f, "I"),
+            TraceRegister::Sp => write!(f, "SP"),
+            TraceRegister::Dt => write!(f, "DT"),
+            TraceRegister::St => write!(f, "ST"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub opcode: u16,
+    /// Registers the instruction changed, with their new values.
+    pub changes: Vec<(TraceRegister, u16)>,
+}
+
+impl TraceRecord {
+    pub fn new(opcode: u16, before: &Registers, after: &Registers) -> Self {
+        let mut changes: Vec<(TraceRegister, u16)> = (0..16u8)
+            .filter(|&x| before.v[x as usize] != after.v[x as usize])
+            .map(|x| (TraceRegister::V(x), after.v[x as usize] as u16))
+            .collect();
+        if before.i != after.i {
+            changes.push((TraceRegister::I, after.i));
+        }
+        if before.sp != after.sp {
+            changes.push((TraceRegister::Sp, after.sp as u16));
+        }
+        TraceRecord {
+            pc: before.pc,
+            opcode,
+            changes,
+        }
+    }
+
+    /// Adds the delay and sound timers the instruction set.
+    pub fn with_timers(mut self, before: &Timers, after: &Timers) -> Self {
+        if before.delay != after.delay {
+            self.changes.push((TraceRegister::Dt, after.delay as u16));
+        }
+        if before.sound != after.sound {
+            self.changes.push((TraceRegister::St, after.sound as u16));
+        }
+        self
+    }
+
+    /// `pc opcode count` followed by `id value` per change, I takes two bytes and the rest one, all big endian.
+    fn write_binary(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(5 + self.changes.len() * 3);
+        bytes.extend(self.pc.to_be_bytes());
+        bytes.extend(self.opcode.to_be_bytes());
+        bytes.push(self.changes.len() as u8);
+        for &(register, value) in &self.changes {
+            bytes.push(register.id());
+            match register {
+                TraceRegister::I => bytes.extend(value.to_be_bytes()),
+                _ => bytes.push(value as u8),
+            }
+        }
+        out.write_all(&bytes)
+    }
+
+    /// None at the end of the input.
+    fn read_binary(input: &mut dyn Read) -> io::Result<Option<Self>> {
+        let mut header = [0; 5];
+        match input.read_exact(&mut header) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let mut changes = Vec::with_capacity(header[4] as usize);
+        for _ in 0..header[4] {
+            let mut id = [0];
+            input.read_exact(&mut id)?;
+            let register = TraceRegister::from_id(id[0])
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("unknown register {} in trace", id[0])))?;
+            let mut value = [0; 2];
+            let value = match register {
+                TraceRegister::I => {
+                    input.read_exact(&mut value)?;
+                    u16::from_be_bytes(value)
+                },
+                _ => {
+                    input.read_exact(&mut value[..1])?;
+                    value[0] as u16
+                },
+            };
+            changes.push((register, value));
+        }
+        Ok(Some(TraceRecord {
+            pc: u16::from_be_bytes([header[0], header[1]]),
+            opcode: u16::from_be_bytes([header[2], header[3]]),
+            changes,
+        }))
+    }
+}
+
+/// `204: F055  LD [I], V0            I=0302`
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the address of F000 nnnn is not recorded, the change to I shows it
+        let mnemonic = match Instruction::decode(self.opcode) {
+            Some(instruction) => instruction.to_string(),
+            None if Instruction::is_long(self.opcode) => String::from("LD I, LONG"),
+            None => String::from("???"),
+        };
+        let changes: Vec<String> = self
+            .changes
+            .iter()
+            .map(|(register, value)| match register {
+                TraceRegister::I => format!("{}={:04X}", register, value),
+                _ => format!("{}={:02X}", register, value),
+            })
+            .collect();
+        let line = format!("{:03X}: {:04X}  {:width$} {}", self.pc, self.opcode, mnemonic, changes.join(" "), width = MNEMONIC_WIDTH);
+        f.write_str(line.trim_end())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    Binary,
+    Text,
+}
+
+enum Sink {
+    /// The last `capacity` records.
+    Ring { records: VecDeque<TraceRecord>, capacity: usize },
+    Writer { out: Box<dyn Write>, format: TraceFormat },
+}
+
+pub struct Tracer {
+    filter: TraceFilter,
+    sink: Sink,
+    // the first write error, tracing stops there and `finish` reports it
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    /// Keep the last `capacity` records in memory.
+    pub fn ring(capacity: usize) -> Self {
+        Tracer {
+            filter: TraceFilter::new(),
+            sink: Sink::Ring { records: VecDeque::with_capacity(capacity), capacity },
+            error: None,
+        }
+    }
+
+    pub fn writer(out: impl Write + 'static, format: TraceFormat) -> Self {
+        let mut out: Box<dyn Write> = Box::new(out);
+        let error = match format {
+            TraceFormat::Binary => out.write_all(MAGIC).and_then(|_| out.write_all(&[VERSION])).err(),
+            TraceFormat::Text => None,
+        };
+        Tracer {
+            filter: TraceFilter::new(),
+            sink: Sink::Writer { out, format },
+            error,
+        }
+    }
+
+    pub fn create(path: &str, format: TraceFormat) -> io::Result<Self> {
+        Ok(Tracer::writer(BufWriter::new(File::create(path)?), format))
+    }
+
+    pub fn with_filter(mut self, filter: TraceFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn filter(&self) -> &TraceFilter {
+        &self.filter
+    }
+
+    /// The records in the ring buffer, oldest first. Records that were written out are not kept.
+    pub fn records(&self) -> impl Iterator<Item = &TraceRecord> {
+        let records = match &self.sink {
+            Sink::Ring { records, .. } => Some(records.iter()),
+            Sink::Writer { .. } => None,
+        };
+        records.into_iter().flatten()
+    }
+
+    pub fn record(&mut self, record: TraceRecord) {
+        match &mut self.sink {
+            Sink::Ring { records, capacity } => {
+                if records.len() == *capacity {
+                    records.pop_front();
+                }
+                if *capacity > 0 {
+                    records.push_back(record);
+                }
+            },
+            Sink::Writer { out, format } if self.error.is_none() => {
+                let written = match format {
+                    TraceFormat::Binary => record.write_binary(out),
+                    TraceFormat::Text => writeln!(out, "{}", record),
+                };
+                self.error = written.err();
+            },
+            Sink::Writer { .. } => {},
+        }
+    }
+
+    /// Flush what was written and report the first error.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        match &mut self.sink {
+            Sink::Writer { out, .. } => out.flush(),
+            Sink::Ring { .. } => Ok(()),
+        }
+    }
+}
+
+/// Read a binary trace back.
+pub fn read_trace(mut input: impl Read) -> io::Result<Vec<TraceRecord>> {
+    let mut header = [0; 5];
+    input.read_exact(&mut header).map_err(|_| io::Error::new(ErrorKind::InvalidData, "not a chip8rs trace"))?;
+    if &header[..4] != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a chip8rs trace"));
+    }
+    if header[4] > VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("trace format version {} is newer than this build", header[4])));
+    }
+    let mut records = Vec::new();
+    while let Some(record) = TraceRecord::read_binary(&mut input)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A writer the test can still look into after handing it to a tracer.
+    #[derive(Clone)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record(opcode: u16, change: impl FnOnce(&mut Registers)) -> TraceRecord {
+        let before = Registers::new();
+        let mut after = before.clone();
+        change(&mut after);
+        TraceRecord::new(opcode, &before, &after)
+    }
+
+    #[test]
+    fn test_record_changes() {
+        let record = record(0xF265, |registers| {
+            registers.v[2] = 0x7F;
+            registers.i = 0x303;
+            registers.pc = 0x202;
+        });
+        assert_eq!(record.changes, vec![(TraceRegister::V(2), 0x7F), (TraceRegister::I, 0x303)]);
+        assert_eq!(record.to_string(), "200: F265  LD V2, [I]           V2=7F I=0303");
+        assert_eq!(format!("{}", TraceRecord { pc: 0x300, opcode: 0xFFFF, changes: vec![] }), "300: FFFF  ???");
+    }
+
+    #[test]
+    fn test_record_timers() {
+        let before = Timers { delay: 0, sound: 5 };
+        let after = Timers { delay: 0x3C, sound: 5 };
+        let record = record(0xF315, |_| {}).with_timers(&before, &after);
+        assert_eq!(record.changes, vec![(TraceRegister::Dt, 0x3C)]);
+        assert_eq!(record.to_string(), "200: F315  LD DT, V3            DT=3C");
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let buffer = Shared(Rc::new(RefCell::new(Vec::new())));
+        let mut tracer = Tracer::writer(buffer.clone(), TraceFormat::Binary);
+        let records = vec![
+            record(0x2300, |registers| registers.sp = 1),
+            record(0xA123, |registers| registers.i = 0x123),
+            record(0x00E0, |_| {}),
+            record(0xF218, |_| {}).with_timers(&Timers::new(), &Timers { delay: 0, sound: 9 }),
+        ];
+        for record in &records {
+            tracer.record(record.clone());
+        }
+        tracer.finish().unwrap();
+        assert_eq!(buffer.0.borrow().len(), 5 + (5 + 2) + (5 + 3) + 5 + (5 + 2));
+        assert_eq!(read_trace(&buffer.0.borrow()[..]).unwrap(), records);
+
+        assert!(read_trace(&b"C8TR\x02"[..]).is_err());
+        assert!(read_trace(&b"nope!"[..]).is_err());
+    }
+
+    #[test]
+    fn test_filter_and_ring() {
+        let filter = TraceFilter::new().with_range(0x200..=0x2FF).with_classes(vec![OpcodeClass::Flow, OpcodeClass::Display]);
+        assert!(filter.accepts(0x200, 0x2300));
+        assert!(filter.accepts(0x2FE, 0xD015));
+        assert!(filter.accepts(0x200, 0xFFFF));
+        assert!(!filter.accepts(0x200, 0x6001));
+        assert!(!filter.accepts(0x300, 0x2300));
+        assert_eq!(" Memory".parse::<OpcodeClass>(), Ok(OpcodeClass::Memory));
+        assert!("jumps".parse::<OpcodeClass>().is_err());
+
+        let mut tracer = Tracer::ring(2);
+        for opcode in [0x6001, 0x6102, 0x6203] {
+            tracer.record(record(opcode, |_| {}));
+        }
+        assert_eq!(tracer.records().map(|record| record.opcode).collect::<Vec<_>>(), vec![0x6102, 0x6203]);
+    }
+}
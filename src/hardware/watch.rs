@@ -0,0 +1,434 @@
+//! Watchpoints
+//! Data breakpoints for debugger frontends: memory reads and writes made by instructions (opcode fetches
+//! do not count), I pointing into a range and conditions on the registers and timers such as
+//! `V3 == 0x10 && DT == 0`. The `Computer` checks them after every instruction and keeps the hits
+//! for the frontend to collect with `take_watch_hits`.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use super::clock::Timers;
+use super::cpus::registers::Registers;
+
+pub type WatchpointId = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A data access to memory made by an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: u16,
+    pub value: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Watchpoint {
+    /// Reads and or writes of an address in the range.
+    Memory { range: RangeInclusive<u16>, read: bool, write: bool },
+    /// I moves into the range.
+    Index(RangeInclusive<u16>),
+    /// The condition goes from false to true.
+    Condition(Condition),
+}
+
+impl Watchpoint {
+    /// True while an edge triggered watchpoint is in its triggered state.
+    fn holds(&self, registers: &Registers, timers: &Timers) -> bool {
+        match self {
+            Watchpoint::Memory { .. } => false,
+            Watchpoint::Index(range) => range.contains(&registers.i),
+            Watchpoint::Condition(condition) => condition.evaluate(registers, timers),
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let range = |range: &RangeInclusive<u16>| match range.start() == range.end() {
+            true => format!("{:#05X}", range.start()),
+            false => format!("{:#05X}-{:#05X}", range.start(), range.end()),
+        };
+        match self {
+            Watchpoint::Memory { range: addresses, read: true, write: true } => write!(f, "access {}", range(addresses)),
+            Watchpoint::Memory { range: addresses, read: true, .. } => write!(f, "read {}", range(addresses)),
+            Watchpoint::Memory { range: addresses, .. } => write!(f, "write {}", range(addresses)),
+            Watchpoint::Index(addresses) => write!(f, "i {}", range(addresses)),
+            Watchpoint::Condition(condition) => write!(f, "{}", condition),
+        }
+    }
+}
+
+/// `read 0x300-0x30F`, `write 0xEA0`, `access 0x300` (reads and writes), `i 0x300-0x3FF`,
+/// anything else is a condition.
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (keyword, argument) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let watchpoint = match keyword.to_ascii_lowercase().as_str() {
+            "read" => Watchpoint::Memory { range: parse_range(argument)?, read: true, write: false },
+            "write" => Watchpoint::Memory { range: parse_range(argument)?, read: false, write: true },
+            "access" => Watchpoint::Memory { range: parse_range(argument)?, read: true, write: true },
+            "i" if !argument.is_empty() && !argument.starts_with(['=', '!', '<', '>']) => {
+                Watchpoint::Index(parse_range(argument)?)
+            },
+            _ => Watchpoint::Condition(text.parse()?),
+        };
+        Ok(watchpoint)
+    }
+}
+
+fn parse_range(text: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = text.split_once('-').unwrap_or((text, text));
+    match (parse_number(start.trim()), parse_number(end.trim())) {
+        (Some(start), Some(end)) if start <= end => Ok(start..=end),
+        _ => Err(format!("'{}' is not an address or a range like 0x300-0x30F", text.trim())),
+    }
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Why a watchpoint fired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Access(MemoryAccess),
+    Index(u16),
+    Condition,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: WatchpointId,
+    /// Address of the instruction that set it off.
+    pub pc: u16,
+    pub trigger: Trigger,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "watchpoint {}: ", self.id)?;
+        match self.trigger {
+            Trigger::Access(MemoryAccess { kind: AccessKind::Read, address, value }) => {
+                write!(f, "read {:#04X} from {:#05X}", value, address)?
+            },
+            Trigger::Access(MemoryAccess { kind: AccessKind::Write, address, value }) => {
+                write!(f, "wrote {:#04X} to {:#05X}", value, address)?
+            },
+            Trigger::Index(i) => write!(f, "I is {:#05X}", i)?,
+            Trigger::Condition => write!(f, "condition is true")?,
+        }
+        write!(f, " at {:#05X}", self.pc)
+    }
+}
+
+struct Entry {
+    id: WatchpointId,
+    watchpoint: Watchpoint,
+    // last state of an edge triggered watchpoint, it only fires on a change to true
+    held: bool,
+}
+
+pub struct Watchpoints {
+    entries: Vec<Entry>,
+    next_id: WatchpointId,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Watchpoints {
+            entries: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (WatchpointId, &Watchpoint)> {
+        self.entries.iter().map(|entry| (entry.id, &entry.watchpoint))
+    }
+
+    /// A condition that already holds fires the next time it becomes true, not straight away.
+    pub fn add(&mut self, watchpoint: Watchpoint, registers: &Registers, timers: &Timers) -> WatchpointId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let held = watchpoint.holds(registers, timers);
+        self.entries.push(Entry { id, watchpoint, held });
+        id
+    }
+
+    pub fn remove(&mut self, id: WatchpointId) -> bool {
+        let count = self.entries.len();
+        self.entries.retain(|entry| entry.id != id);
+        self.entries.len() != count
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The watchpoints set off by the instruction at `pc`, which made `accesses`.
+    pub fn check(&mut self, pc: u16, accesses: &[MemoryAccess], registers: &Registers, timers: &Timers) -> Vec<WatchHit> {
+        let mut hits = Vec::new();
+        for entry in &mut self.entries {
+            let trigger = match &entry.watchpoint {
+                Watchpoint::Memory { range, read, write } => accesses
+                    .iter()
+                    .find(|access| range.contains(&access.address) && match access.kind {
+                        AccessKind::Read => *read,
+                        AccessKind::Write => *write,
+                    })
+                    .map(|access| Trigger::Access(*access)),
+                watchpoint => {
+                    let held = std::mem::replace(&mut entry.held, watchpoint.holds(registers, timers));
+                    match watchpoint {
+                        Watchpoint::Index(_) if entry.held && !held => Some(Trigger::Index(registers.i)),
+                        Watchpoint::Condition(_) if entry.held && !held => Some(Trigger::Condition),
+                        _ => None,
+                    }
+                },
+            };
+            if let Some(trigger) = trigger {
+                hits.push(WatchHit { id: entry.id, pc, trigger });
+            }
+        }
+        hits
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value {
+    Register(u8),
+    I,
+    Pc,
+    Sp,
+    Delay,
+    Sound,
+    Number(u16),
+}
+
+impl Value {
+    fn get(&self, registers: &Registers, timers: &Timers) -> u16 {
+        match *self {
+            Value::Register(x) => registers.v[x as usize] as u16,
+            Value::I => registers.i,
+            Value::Pc => registers.pc,
+            Value::Sp => registers.sp as u16,
+            Value::Delay => timers.delay as u16,
+            Value::Sound => timers.sound as u16,
+            Value::Number(number) => number,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Compare(Value, String, Value),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+/// Comparisons (`==`, `!=`, `<`, `>`, `<=`, `>=`) of V0-VF, I, PC, SP, DT, ST and numbers, joined with
+/// `&&` and `||` and grouped with parentheses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    text: String,
+    expression: Expression,
+}
+
+impl Condition {
+    pub fn evaluate(&self, registers: &Registers, timers: &Timers) -> bool {
+        evaluate(&self.expression, registers, timers)
+    }
+}
+
+fn evaluate(expression: &Expression, registers: &Registers, timers: &Timers) -> bool {
+    match expression {
+        Expression::Compare(left, comparison, right) => {
+            let (left, right) = (left.get(registers, timers), right.get(registers, timers));
+            match comparison.as_str() {
+                "==" => left == right,
+                "!=" => left != right,
+                "<" => left < right,
+                ">" => left > right,
+                "<=" => left <= right,
+                _ => left >= right,
+            }
+        },
+        Expression::And(left, right) => evaluate(left, registers, timers) && evaluate(right, registers, timers),
+        Expression::Or(left, right) => evaluate(left, registers, timers) || evaluate(right, registers, timers),
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut position = 0;
+        let expression = parse_or(&tokens, &mut position)?;
+        if let Some(token) = tokens.get(position) {
+            return Err(format!("unexpected '{}' in condition", token));
+        }
+        Ok(Condition { text: text.trim().to_string(), expression })
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric()) {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else {
+            chars.next();
+            let token = match (c, chars.peek()) {
+                ('=' | '!' | '<' | '>', Some('=')) | ('&', Some('&')) | ('|', Some('|')) => {
+                    format!("{}{}", c, chars.next().unwrap_or_default())
+                },
+                ('<' | '>' | '(' | ')', _) => c.to_string(),
+                _ => return Err(format!("unexpected '{}' in condition", c)),
+            };
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_or(tokens: &[String], position: &mut usize) -> Result<Expression, String> {
+    let mut expression = parse_and(tokens, position)?;
+    while tokens.get(*position).map(String::as_str) == Some("||") {
+        *position += 1;
+        expression = Expression::Or(Box::new(expression), Box::new(parse_and(tokens, position)?));
+    }
+    Ok(expression)
+}
+
+fn parse_and(tokens: &[String], position: &mut usize) -> Result<Expression, String> {
+    let mut expression = parse_comparison(tokens, position)?;
+    while tokens.get(*position).map(String::as_str) == Some("&&") {
+        *position += 1;
+        expression = Expression::And(Box::new(expression), Box::new(parse_comparison(tokens, position)?));
+    }
+    Ok(expression)
+}
+
+fn parse_comparison(tokens: &[String], position: &mut usize) -> Result<Expression, String> {
+    if tokens.get(*position).map(String::as_str) == Some("(") {
+        *position += 1;
+        let expression = parse_or(tokens, position)?;
+        if tokens.get(*position).map(String::as_str) != Some(")") {
+            return Err(String::from("missing ) in condition"));
+        }
+        *position += 1;
+        return Ok(expression);
+    }
+    let left = parse_value(tokens, position)?;
+    let comparison = match tokens.get(*position) {
+        Some(token) if ["==", "!=", "<", ">", "<=", ">="].contains(&token.as_str()) => token.clone(),
+        Some(token) => return Err(format!("expected a comparison, got '{}'", token)),
+        None => return Err(String::from("condition ends without a comparison")),
+    };
+    *position += 1;
+    let right = parse_value(tokens, position)?;
+    Ok(Expression::Compare(left, comparison, right))
+}
+
+fn parse_value(tokens: &[String], position: &mut usize) -> Result<Value, String> {
+    let token = tokens.get(*position).ok_or("condition ends without a value")?;
+    *position += 1;
+    let upper = token.to_ascii_uppercase();
+    let value = match upper.as_str() {
+        "I" => Value::I,
+        "PC" => Value::Pc,
+        "SP" => Value::Sp,
+        "DT" => Value::Delay,
+        "ST" => Value::Sound,
+        _ => {
+            let register = upper.strip_prefix('V').filter(|x| x.len() == 1).and_then(|x| u8::from_str_radix(x, 16).ok());
+            match (register, parse_number(token)) {
+                (Some(x), _) => Value::Register(x),
+                (None, Some(number)) => Value::Number(number),
+                _ => return Err(format!("'{}' is not a register or a number", token)),
+            }
+        },
+    };
+    Ok(value)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_condition() {
+        let condition: Condition = "V3 == 0x10 && DT == 0 || (i >= 0x300 && pc != 512)".parse().unwrap();
+        let mut registers = Registers::new();
+        let mut timers = Timers::new();
+        registers.v[3] = 0x10;
+        assert!(condition.evaluate(&registers, &timers));
+        timers.delay = 1;
+        assert!(!condition.evaluate(&registers, &timers));
+        registers.i = 0x300;
+        registers.pc = 0x202;
+        assert!(condition.evaluate(&registers, &timers));
+    }
+
+    #[test]
+    fn test_condition_errors() {
+        assert!("V3 = 1".parse::<Condition>().is_err());
+        assert!("V3 == ".parse::<Condition>().is_err());
+        assert!("VG == 1".parse::<Condition>().is_err());
+        assert!("(V3 == 1".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn test_parse_watchpoint() {
+        assert_eq!("write 0x300-0x30F".parse(), Ok(Watchpoint::Memory { range: 0x300..=0x30F, read: false, write: true }));
+        assert_eq!("read 0xEA0".parse(), Ok(Watchpoint::Memory { range: 0xEA0..=0xEA0, read: true, write: false }));
+        assert_eq!("i 0x300-0x3FF".parse(), Ok(Watchpoint::Index(0x300..=0x3FF)));
+        assert!(matches!("I == 0x300".parse(), Ok(Watchpoint::Condition(_))));
+        assert_eq!("access 0x300-0x30F".parse::<Watchpoint>().unwrap().to_string(), "access 0x300-0x30F");
+    }
+
+    #[test]
+    fn test_conditions_fire_on_change() {
+        let mut watchpoints = Watchpoints::new();
+        let mut registers = Registers::new();
+        let timers = Timers::new();
+        let id = watchpoints.add("V0 == 1".parse().unwrap(), &registers, &timers);
+        assert!(watchpoints.check(0x200, &[], &registers, &timers).is_empty());
+        registers.v[0] = 1;
+        let hits = watchpoints.check(0x200, &[], &registers, &timers);
+        assert_eq!(hits, vec![WatchHit { id, pc: 0x200, trigger: Trigger::Condition }]);
+        // still true, no new hit
+        assert!(watchpoints.check(0x202, &[], &registers, &timers).is_empty());
+    }
+}
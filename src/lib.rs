@@ -0,0 +1,6 @@
+// the hardware follows a `new()` constructor convention, Default impls would only duplicate them
+#![allow(clippy::new_without_default)]
+
+pub mod frontend;
+pub mod hardware;
+pub mod tools;
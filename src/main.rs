@@ -1,68 +1,186 @@
-use hardware::{cartridge, computer::Computer};
-mod hardware;
-extern crate sdl2; 
+use chip8rs::tools::{assembler::Assembler, disassembler, octo::OctoCompiler};
+use chip8rs::hardware::{cartridge::Cartridge, clock::TIMER_FREQUENCY, computer::Computer, memory::{RESERVED_MEMORY_SIZE, XO_MEMORY_SIZE}};
+use chip8rs::hardware::cpus::quirks::Quirks;
+use chip8rs::hardware::rewind::Rewind;
+use chip8rs::hardware::trace::{self, TraceFormat, Tracer};
+#[cfg(feature = "gui")]
+use chip8rs::frontend::renderer::RenderConfig;
+use cli::Command;
+use std::io::Write;
 
-use sdl2::pixels::Color;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::EventPump;
+mod cli;
 
-fn main() -> Result<(), String> {
-    let sdl_context = sdl2::init()?;
-    let video_subsystem = sdl_context.video()?;
-
-    let window = video_subsystem.window("Chip8 Window", 64 * 15, 32 * 15)
-        .position_centered()
-        .build()
-        .map_err(|e| e.to_string())?;
+fn main() {
+    if let Err(e) = run(std::env::args().skip(1)) {
+        eprintln!("chip8rs: {}", e);
+        eprintln!("Try 'chip8rs --help' for more information.");
+        std::process::exit(1);
+    }
+}
 
-    let mut canvas = window.into_canvas().build()
-        .map_err(|e| e.to_string())?;
+fn run(args: impl IntoIterator<Item = String>) -> Result<(), String> {
+    let options = match cli::parse_args(args)? {
+        Command::Run(options) => options,
+        Command::Disassemble { rom, start_address } => {
+            let cartridge = read_cartridge(&rom, start_address)?;
+            check_cartridge(&rom, &cartridge, XO_MEMORY_SIZE)?;
+            let listing = disassembler::disassemble(&cartridge.rom, cartridge.start_address).to_string();
+            return write_stdout(&listing);
+        },
+        Command::Assemble { source, output, start_address } => {
+            let cartridge = compile_source(&source, start_address)?;
+            check_cartridge(&source, &cartridge, XO_MEMORY_SIZE)?;
+            let output = output.unwrap_or_else(|| {
+                std::path::Path::new(&source).with_extension("ch8").display().to_string()
+            });
+            return std::fs::write(&output, &cartridge.rom)
+                .map_err(|e| format!("unable to write '{}': {}", output, e));
+        },
+        Command::Trace { file } => {
+            let input = std::fs::File::open(&file).map_err(|e| format!("unable to read trace '{}': {}", file, e))?;
+            let records = trace::read_trace(std::io::BufReader::new(input)).map_err(|e| format!("trace '{}': {}", file, e))?;
+            let text: String = records.iter().map(|record| format!("{}\n", record)).collect();
+            return write_stdout(&text);
+        },
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        },
+    };
 
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
-    canvas.present();
+    let quirks = match options.quirks {
+        Some(quirks) => Some(quirks),
+        None => read_quirks(&options.rom)?,
+    };
+    let mut cartridge = read_cartridge(&options.rom, options.start_address)?;
+    if let Some(quirks) = quirks {
+        cartridge = cartridge.with_quirks(quirks);
+    }
+    check_cartridge(&options.rom, &cartridge, cartridge.quirks.memory_size)?;
+    let mut computer = Computer::new(cartridge);
+    if let Some(speed) = options.speed {
+        computer.clock.set_speed(speed);
+    }
+    computer.power_on().map_err(|e| e.to_string())?;
+    if options.rewind > 0 && !options.headless {
+        computer.set_rewind(Some(Rewind::new(options.rewind as usize * TIMER_FREQUENCY as usize)));
+    }
 
-    let file = std::fs::read("data/IBM_logo.ch8")
-    .expect("Unable to read file");
-    //let data = file.into_iter().map(|x| x as u8).collect::<Vec<u8>>();
+    let trace = match &options.trace {
+        Some(path) => {
+            let format = if path.ends_with(".txt") { TraceFormat::Text } else { TraceFormat::Binary };
+            let tracer = Tracer::create(path, format).map_err(|e| format!("unable to create trace '{}': {}", path, e))?;
+            computer.set_tracer(Some(tracer.with_filter(options.trace_filter.clone())));
+            Some(path)
+        },
+        None => None,
+    };
+    let result = run_frontend(&mut computer, &options);
+    // the trace is most useful when the program faulted, finish it either way
+    if let (Some(mut tracer), Some(path)) = (computer.set_tracer(None), trace) {
+        tracer.finish().map_err(|e| format!("unable to write trace '{}': {}", path, e))?;
+    }
+    result
+}
 
-    let cartridge = cartridge::Cartridge::new(file);
-    let mut computer = Computer::new(cartridge);
-    computer.power_on();
-    let mut counter = 0;
+fn run_frontend(computer: &mut Computer, options: &cli::Options) -> Result<(), String> {
+    if let Some(port) = options.gdb {
+        return chip8rs::frontend::gdb::run(computer, port);
+    }
+    if options.debug {
+        return chip8rs::frontend::debugger::run(computer);
+    }
+    if options.headless || cfg!(not(feature = "gui")) {
+        return chip8rs::frontend::headless::run(computer, options.frames, options.wav.as_deref(), options.audio);
+    }
 
-    let mut event_pump = sdl_context.event_pump()?;
-    'running: loop {
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                    break 'running;
-                },
-                Event::KeyDown { keycode: Some(Keycode::A), .. } => {
-                    // Handle 'A' key press
-                },
-                Event::KeyDown { keycode: Some(Keycode::Q), .. } => {
-                    // Handle 'Q' key press
-                    break 'running;
-                },
-                // Handle other keys or events
-                _ => {}
-            }
+    #[cfg(feature = "gui")]
+    {
+        let mut render_config = RenderConfig::new();
+        if let Some(scale) = options.scale {
+            render_config.scale = scale;
         }
-        computer.run();
-        counter += 1;
-        if counter > 20 {
-            break;
+        if let Some(palette) = &options.palette {
+            let colours = [
+                &mut render_config.foreground, &mut render_config.background,
+                &mut render_config.second, &mut render_config.both,
+            ];
+            for (colour, &(r, g, b)) in colours.into_iter().zip(palette) {
+                *colour = sdl2::pixels::Color::RGB(r, g, b);
+            }
         }
+        render_config.grid = options.grid;
+        render_config.vsync = options.vsync;
+        let saves = chip8rs::hardware::savestate::SaveSlots::new(&options.rom);
+        chip8rs::frontend::sdl::run(computer, render_config, options.audio, options.keymap.as_deref(), saves)?;
+    }
+    Ok(())
+}
+
+// listings are often piped into `head` or `less`, a reader that goes away early is not an error
+fn write_stdout(text: &str) -> Result<(), String> {
+    match std::io::stdout().lock().write_all(text.as_bytes()) {
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
 
-        // Your emulator's rendering and logic update code goes here
-        // Example: just clear the screen with a different color
-        //canvas.set_draw_color(Color::RGB(0, 0, 255));
-        canvas.clear();
-        canvas.present();
+fn read_cartridge(path: &str, start_address: Option<u16>) -> Result<Cartridge, String> {
+    if path.ends_with(".asm") || path.ends_with(".8o") {
+        return compile_source(path, start_address);
+    }
+    let rom = std::fs::read(path)
+        .map_err(|e| format!("unable to read ROM '{}': {}", path, e))?;
+    let mut cartridge = Cartridge::new(rom);
+    if let Some(start_address) = start_address {
+        cartridge = cartridge.with_start_address(start_address);
     }
-    computer.memory.dump(0x0000, 0x0FFF);
+    Ok(cartridge)
+}
 
+/// The quirk profile saved next to a ROM, `PONG.quirks` for `PONG`, holding what `--quirks` takes.
+fn read_quirks(rom: &str) -> Result<Option<Quirks>, String> {
+    let path = format!("{}.quirks", rom);
+    match std::fs::read_to_string(&path) {
+        Ok(text) => text.trim().parse().map(Some).map_err(|e| format!("{}: {}", path, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("unable to read '{}': {}", path, e)),
+    }
+}
+
+/// Octo source for `.8o` files, the built-in assembler's syntax for anything else.
+fn compile_source(path: &str, start_address: Option<u16>) -> Result<Cartridge, String> {
+    if path.ends_with(".8o") {
+        let mut compiler = OctoCompiler::new();
+        if let Some(start_address) = start_address {
+            compiler = compiler.with_origin(start_address);
+        }
+        return compiler.compile_file(path).map_err(|e| e.to_string());
+    }
+    let mut assembler = Assembler::new();
+    if let Some(start_address) = start_address {
+        assembler = assembler.with_origin(start_address);
+    }
+    assembler.assemble_file(path).map_err(|e| e.to_string())
+}
+
+/// Whether the cartridge fits in `memory_size` bytes, 4 KB unless the quirk profile asks for XO-CHIP's 64 KB.
+fn check_cartridge(path: &str, cartridge: &Cartridge, memory_size: usize) -> Result<(), String> {
+    let start = cartridge.start_address as usize;
+    if !(RESERVED_MEMORY_SIZE..memory_size).contains(&start) {
+        return Err(format!(
+            "start address {:#05X} is outside of program memory ({:#05X}-{:#05X})",
+            start, RESERVED_MEMORY_SIZE, memory_size - 1,
+        ));
+    }
+    if cartridge.rom.is_empty() {
+        return Err(format!("ROM '{}' is empty", path));
+    }
+    if cartridge.rom.len() > memory_size - start {
+        return Err(format!(
+            "ROM '{}' is {} bytes, only {} bytes fit in memory from {:#05X}",
+            path, cartridge.rom.len(), memory_size - start, start,
+        ));
+    }
     Ok(())
-}
\ No newline at end of file
+}
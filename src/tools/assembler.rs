@@ -0,0 +1,573 @@
+//! Assembler
+//! Builds a ROM from source in the mnemonics of Cowgod's reference, the same syntax the disassembler
+//! writes, so a listing can be edited and assembled again. One statement per line, `;` starts a comment:
+//!     start:                  label, can share a line with a statement
+//!     LD V1, SPEED            instruction
+//!     db 0xF0, 0x90, 144      bytes
+//!     dw 0x1234               16 bit big endian words
+//!     SPEED equ 4             constant
+//!     include "sprites.asm"   the lines of another file, relative to the including one
+//! Numbers are decimal, hexadecimal with 0x or binary with 0b, and values can be added to and
+//! subtracted from each other: `LD I, sprites + 5`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::hardware::cartridge::{Cartridge, DEFAULT_START_ADDRESS};
+use crate::hardware::cpus::instruction::Instruction;
+use crate::hardware::memory::XO_MEMORY_SIZE;
+
+// deeper than this, a file is most likely including itself
+const MAX_INCLUDE_DEPTH: usize = 16;
+// same for constants defined in terms of each other
+const MAX_CONSTANT_DEPTH: usize = 32;
+const RESERVED_NAMES: [&str; 9] = ["I", "DT", "ST", "K", "F", "HF", "B", "R", "PITCH"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssemblerError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+struct SourceLine {
+    file: Rc<str>,
+    number: usize,
+    /// Without the comment and surrounding whitespace.
+    text: String,
+}
+
+impl SourceLine {
+    fn error(&self, message: impl Into<String>) -> AssemblerError {
+        AssemblerError {
+            file: self.file.to_string(),
+            line: self.number,
+            message: message.into(),
+        }
+    }
+
+    fn location(&self) -> String {
+        format!("{}:{}", self.file, self.number)
+    }
+}
+
+enum Symbol {
+    Label(u16),
+    Constant(String),
+}
+
+enum Statement<'a> {
+    Instruction { mnemonic: &'a str, operands: Vec<&'a str> },
+    Bytes(Vec<&'a str>),
+    Words(Vec<&'a str>),
+}
+
+impl Statement<'_> {
+    fn size(&self) -> usize {
+        match self {
+            Statement::Instruction { operands, .. } => {
+                match operands.iter().any(|operand| matches!(Operand::parse(operand), Operand::Long(_))) {
+                    true => 4,
+                    false => 2,
+                }
+            },
+            Statement::Bytes(values) => values.len(),
+            Statement::Words(values) => values.len() * 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Operand<'a> {
+    Register(u8),
+    I,
+    /// `[I]`, memory at I.
+    IndirectI,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    /// `HF`, SUPER-CHIP's big font.
+    BigFont,
+    Bcd,
+    /// `R`, SUPER-CHIP's RPL user flags.
+    Flags,
+    /// `PITCH`, XO-CHIP's audio pattern playback pitch.
+    Pitch,
+    /// `Vx-Vy`, XO-CHIP's register ranges.
+    Range(u8, u8),
+    /// `LONG addr`, XO-CHIP's 16 bit address in the word after the opcode.
+    Long(&'a str),
+    Value(&'a str),
+}
+
+impl<'a> Operand<'a> {
+    fn parse(text: &'a str) -> Self {
+        match text.to_ascii_uppercase().as_str() {
+            "I" => Operand::I,
+            "[I]" => Operand::IndirectI,
+            "DT" => Operand::DelayTimer,
+            "ST" => Operand::SoundTimer,
+            "K" => Operand::Key,
+            "F" => Operand::Font,
+            "HF" => Operand::BigFont,
+            "B" => Operand::Bcd,
+            "R" => Operand::Flags,
+            "PITCH" => Operand::Pitch,
+            upper if upper.starts_with("LONG ") => Operand::Long(text[5..].trim()),
+            upper => match (register(upper), upper.split_once('-')) {
+                (Some(x), _) => Operand::Register(x),
+                (None, Some((x, y))) => match (register(x.trim()), register(y.trim())) {
+                    (Some(x), Some(y)) => Operand::Range(x, y),
+                    _ => Operand::Value(text),
+                },
+                (None, None) => Operand::Value(text),
+            },
+        }
+    }
+}
+
+/// Assembler
+/// Two passes: the first expands includes, collects labels and constants and lays out addresses,
+/// the second encodes, so labels can be used before they are defined.
+pub struct Assembler {
+    origin: u16,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler { origin: DEFAULT_START_ADDRESS }
+    }
+
+    /// Assemble for a program loaded somewhere else than 0x200, labels are relative to `origin`.
+    pub fn with_origin(mut self, origin: u16) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn assemble_file(&self, path: &str) -> Result<Cartridge, AssemblerError> {
+        let source = std::fs::read_to_string(path).map_err(|e| AssemblerError {
+            file: path.to_string(),
+            line: 0,
+            message: format!("unable to read source: {}", e),
+        })?;
+        let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+        self.assemble_lines(read_source(path, &source, directory, 0)?)
+    }
+
+    /// `name` is used in error messages, includes are relative to the working directory.
+    pub fn assemble(&self, name: &str, source: &str) -> Result<Cartridge, AssemblerError> {
+        self.assemble_lines(read_source(name, source, Path::new(""), 0)?)
+    }
+
+    fn assemble_lines(&self, lines: Vec<SourceLine>) -> Result<Cartridge, AssemblerError> {
+        let mut symbols = Symbols { table: HashMap::new() };
+        let mut statements = Vec::new();
+        let mut address = self.origin as usize;
+
+        for (index, line) in lines.iter().enumerate() {
+            let mut text = line.text.as_str();
+            while let Some((label, rest)) = split_label(text) {
+                symbols.define(label, Symbol::Label(address as u16), index, &lines)?;
+                text = rest;
+            }
+            if text.is_empty() {
+                continue;
+            }
+            let (first, rest) = split_word(text);
+            let (keyword, expression) = split_word(rest);
+            if keyword.eq_ignore_ascii_case("equ") {
+                symbols.define(first, Symbol::Constant(expression.to_string()), index, &lines)?;
+                continue;
+            }
+            let operands = split_operands(rest).map_err(|e| line.error(e))?;
+            let statement = match first.to_ascii_lowercase().as_str() {
+                "db" => Statement::Bytes(operands),
+                "dw" => Statement::Words(operands),
+                _ => Statement::Instruction { mnemonic: first, operands },
+            };
+            address += statement.size();
+            if address > XO_MEMORY_SIZE {
+                return Err(line.error(format!("program does not fit in memory, it ends at {:#06X}", address)));
+            }
+            statements.push((line, statement));
+        }
+
+        // report broken constants where they are defined rather than where they are used
+        let mut constants: Vec<(&String, usize)> = symbols.table.iter()
+            .filter(|(_, (symbol, _))| matches!(symbol, Symbol::Constant(_)))
+            .map(|(name, (_, index))| (name, *index))
+            .collect();
+        constants.sort_by_key(|(_, index)| *index);
+        for (name, index) in constants {
+            symbols.value(name, 0).map_err(|e| lines[index].error(e))?;
+        }
+
+        let mut rom = Vec::new();
+        for (line, statement) in statements {
+            match statement {
+                Statement::Instruction { mnemonic, operands } => {
+                    let instruction = symbols.instruction(mnemonic, &operands).map_err(|e| line.error(e))?;
+                    rom.extend(instruction.to_bytes());
+                },
+                Statement::Bytes(values) => {
+                    for value in values {
+                        rom.push(symbols.ranged(value, -0x80, 0xFF).map_err(|e| line.error(e))? as u8);
+                    }
+                },
+                Statement::Words(values) => {
+                    for value in values {
+                        let word = symbols.ranged(value, -0x8000, 0xFFFF).map_err(|e| line.error(e))? as u16;
+                        rom.extend_from_slice(&word.to_be_bytes());
+                    }
+                },
+            }
+        }
+        Ok(Cartridge::new(rom).with_start_address(self.origin))
+    }
+}
+
+/// The lines of `source`, with includes replaced by the lines of the included file.
+fn read_source(name: &str, source: &str, directory: &Path, depth: usize) -> Result<Vec<SourceLine>, AssemblerError> {
+    let file: Rc<str> = Rc::from(name);
+    let mut lines = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let line = SourceLine {
+            file: file.clone(),
+            number: index + 1,
+            text: strip_comment(text).trim().to_string(),
+        };
+        let (keyword, argument) = split_word(&line.text);
+        if !keyword.eq_ignore_ascii_case("include") {
+            lines.push(line);
+            continue;
+        }
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error("includes are nested too deeply, does a file include itself?"));
+        }
+        let path = argument.trim_matches('"');
+        if path.is_empty() {
+            return Err(line.error("include needs a file name"));
+        }
+        let path = directory.join(path);
+        let included = std::fs::read_to_string(&path)
+            .map_err(|e| line.error(format!("unable to read '{}': {}", path.display(), e)))?;
+        let included_directory = path.parent().unwrap_or(Path::new(""));
+        lines.extend(read_source(&path.display().to_string(), &included, included_directory, depth + 1)?);
+    }
+    Ok(lines)
+}
+
+struct Symbols {
+    /// The symbol and the index of the line that defines it.
+    table: HashMap<String, (Symbol, usize)>,
+}
+
+impl Symbols {
+    fn define(&mut self, name: &str, symbol: Symbol, index: usize, lines: &[SourceLine]) -> Result<(), AssemblerError> {
+        if !is_identifier(name) {
+            return Err(lines[index].error(format!("'{}' is not a valid name", name)));
+        }
+        if register(&name.to_ascii_uppercase()).is_some() || RESERVED_NAMES.contains(&name.to_ascii_uppercase().as_str()) {
+            return Err(lines[index].error(format!("'{}' is a reserved name", name)));
+        }
+        if let Some((_, defined)) = self.table.get(name) {
+            return Err(lines[index].error(format!("'{}' is already defined at {}", name, lines[*defined].location())));
+        }
+        self.table.insert(name.to_string(), (symbol, index));
+        Ok(())
+    }
+
+    fn value(&self, name: &str, depth: usize) -> Result<i64, String> {
+        match self.table.get(name) {
+            Some((Symbol::Label(address), _)) => Ok(*address as i64),
+            Some((Symbol::Constant(_), _)) if depth >= MAX_CONSTANT_DEPTH => {
+                Err(format!("'{}' is defined in terms of itself", name))
+            },
+            Some((Symbol::Constant(expression), _)) => self.evaluate(expression, depth + 1),
+            None => Err(format!("unknown symbol '{}'", name)),
+        }
+    }
+
+    /// Sum and difference of numbers and symbols.
+    fn evaluate(&self, expression: &str, depth: usize) -> Result<i64, String> {
+        let (mut sign, mut rest) = match expression.trim().strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, expression.trim()),
+        };
+        let mut total: i64 = 0;
+        loop {
+            let end = rest.find(['+', '-']).unwrap_or(rest.len());
+            let term = rest[..end].trim();
+            let value = match parse_number(term) {
+                Some(number) => number,
+                None if term.is_empty() => return Err(format!("missing value in '{}'", expression.trim())),
+                None if is_identifier(term) => self.value(term, depth)?,
+                None => return Err(format!("'{}' is not a number or a name", term)),
+            };
+            total = total.saturating_add(sign * value);
+            if end == rest.len() {
+                return Ok(total);
+            }
+            sign = if rest[end..].starts_with('+') { 1 } else { -1 };
+            rest = &rest[end + 1..];
+        }
+    }
+
+    fn ranged(&self, expression: &str, min: i64, max: i64) -> Result<i64, String> {
+        let value = self.evaluate(expression, 0)?;
+        if !(min..=max).contains(&value) {
+            return Err(format!("'{}' is {}, which is outside of {}..{}", expression, value, min, max));
+        }
+        Ok(value)
+    }
+
+    fn address(&self, expression: &str) -> Result<u16, String> {
+        Ok(self.ranged(expression, 0, 0xFFF)? as u16)
+    }
+
+    /// Negative bytes are allowed for their two's complement, `ADD V0, -1`.
+    fn byte(&self, expression: &str) -> Result<u8, String> {
+        Ok(self.ranged(expression, -0x80, 0xFF)? as u8)
+    }
+
+    fn nibble(&self, expression: &str) -> Result<u8, String> {
+        Ok(self.ranged(expression, 0, 0xF)? as u8)
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[&str]) -> Result<Instruction, String> {
+        use Operand::*;
+        let parsed: Vec<Operand> = operands.iter().map(|operand| Operand::parse(operand)).collect();
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        let instruction = match (mnemonic.as_str(), parsed.as_slice()) {
+            ("CLS", []) => Instruction::Cls,
+            ("RET", []) => Instruction::Ret,
+            ("SCD", [Value(n)]) => Instruction::ScrollDown(self.nibble(n)?),
+            ("SCR", []) => Instruction::ScrollRight,
+            ("SCL", []) => Instruction::ScrollLeft,
+            ("EXIT", []) => Instruction::Exit,
+            ("LOW", []) => Instruction::LowRes,
+            ("HIGH", []) => Instruction::HighRes,
+            ("SCU", [Value(n)]) => Instruction::ScrollUp(self.nibble(n)?),
+            ("AUDIO", []) => Instruction::LoadPattern,
+            ("PLANE", [Value(n)]) => Instruction::SelectPlanes(self.ranged(n, 0, 3)? as u8),
+            ("SYS", [Value(addr)]) => Instruction::Sys(self.address(addr)?),
+            ("JP", [Value(addr)]) => Instruction::Jump(self.address(addr)?),
+            ("JP", [Register(0), Value(addr)]) => Instruction::JumpV0(self.address(addr)?),
+            ("CALL", [Value(addr)]) => Instruction::Call(self.address(addr)?),
+            ("SE", [Register(x), Register(y)]) => Instruction::SkipEqual { x: *x, y: *y },
+            ("SE", [Register(x), Value(byte)]) => Instruction::SkipEqualByte { x: *x, byte: self.byte(byte)? },
+            ("SNE", [Register(x), Register(y)]) => Instruction::SkipNotEqual { x: *x, y: *y },
+            ("SNE", [Register(x), Value(byte)]) => Instruction::SkipNotEqualByte { x: *x, byte: self.byte(byte)? },
+            ("LD", [Register(x), Register(y)]) => Instruction::Load { x: *x, y: *y },
+            ("LD", [Register(x), Value(byte)]) => Instruction::LoadByte { x: *x, byte: self.byte(byte)? },
+            ("LD", [I, Value(addr)]) => Instruction::LoadI(self.address(addr)?),
+            ("LD", [I, Long(addr)]) => Instruction::LoadILong(self.ranged(addr, 0, 0xFFFF)? as u16),
+            ("LD", [Register(x), DelayTimer]) => Instruction::LoadDelay { x: *x },
+            ("LD", [Register(x), Key]) => Instruction::WaitKey { x: *x },
+            ("LD", [DelayTimer, Register(x)]) => Instruction::SetDelay { x: *x },
+            ("LD", [SoundTimer, Register(x)]) => Instruction::SetSound { x: *x },
+            ("LD", [Pitch, Register(x)]) => Instruction::SetPitch { x: *x },
+            ("LD", [Font, Register(x)]) => Instruction::LoadFont { x: *x },
+            ("LD", [BigFont, Register(x)]) => Instruction::LoadBigFont { x: *x },
+            ("LD", [Flags, Register(x)]) => Instruction::StoreFlags { x: *x },
+            ("LD", [Register(x), Flags]) => Instruction::LoadFlags { x: *x },
+            ("LD", [Bcd, Register(x)]) => Instruction::StoreBcd { x: *x },
+            ("LD", [IndirectI, Register(x)]) => Instruction::StoreRegisters { x: *x },
+            ("LD", [Register(x), IndirectI]) => Instruction::LoadRegisters { x: *x },
+            ("LD", [IndirectI, Range(x, y)]) => Instruction::StoreRange { x: *x, y: *y },
+            ("LD", [Range(x, y), IndirectI]) => Instruction::LoadRange { x: *x, y: *y },
+            ("ADD", [Register(x), Register(y)]) => Instruction::Add { x: *x, y: *y },
+            ("ADD", [Register(x), Value(byte)]) => Instruction::AddByte { x: *x, byte: self.byte(byte)? },
+            ("ADD", [I, Register(x)]) => Instruction::AddI { x: *x },
+            ("OR", [Register(x), Register(y)]) => Instruction::Or { x: *x, y: *y },
+            ("AND", [Register(x), Register(y)]) => Instruction::And { x: *x, y: *y },
+            ("XOR", [Register(x), Register(y)]) => Instruction::Xor { x: *x, y: *y },
+            ("SUB", [Register(x), Register(y)]) => Instruction::Sub { x: *x, y: *y },
+            ("SUBN", [Register(x), Register(y)]) => Instruction::SubN { x: *x, y: *y },
+            // without Vy the shift reads Vx, whichever way the interpreter treats the source register
+            ("SHR", [Register(x)]) => Instruction::ShiftRight { x: *x, y: *x },
+            ("SHR", [Register(x), Register(y)]) => Instruction::ShiftRight { x: *x, y: *y },
+            ("SHL", [Register(x)]) => Instruction::ShiftLeft { x: *x, y: *x },
+            ("SHL", [Register(x), Register(y)]) => Instruction::ShiftLeft { x: *x, y: *y },
+            ("RND", [Register(x), Value(byte)]) => Instruction::Random { x: *x, byte: self.byte(byte)? },
+            ("DRW", [Register(x), Register(y), Value(n)]) => Instruction::Draw { x: *x, y: *y, n: self.nibble(n)? },
+            ("SKP", [Register(x)]) => Instruction::SkipKeyPressed { x: *x },
+            ("SKNP", [Register(x)]) => Instruction::SkipKeyNotPressed { x: *x },
+            ("CLS" | "RET" | "SYS" | "JP" | "CALL" | "SE" | "SNE" | "LD" | "ADD" | "OR" | "AND" | "XOR"
+                | "SUB" | "SUBN" | "SHR" | "SHL" | "RND" | "DRW" | "SKP" | "SKNP"
+                | "SCD" | "SCR" | "SCL" | "EXIT" | "LOW" | "HIGH" | "SCU" | "PLANE" | "AUDIO", _) => {
+                return Err(format!("invalid operands for {}: '{}'", mnemonic, operands.join(", ")));
+            },
+            _ => return Err(format!("unknown instruction '{}'", mnemonic)),
+        };
+        Ok(instruction)
+    }
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..index],
+            _ => {},
+        }
+    }
+    text
+}
+
+/// `name: rest` into the label and the rest of the line.
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let (label, rest) = text.split_once(':')?;
+    if is_identifier(label) {
+        Some((label, rest.trim()))
+    } else {
+        None
+    }
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+fn split_operands(text: &str) -> Result<Vec<&str>, String> {
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    let operands: Vec<&str> = text.split(',').map(str::trim).collect();
+    if operands.iter().any(|operand| operand.is_empty()) {
+        return Err(format!("missing operand in '{}'", text));
+    }
+    Ok(operands)
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// `V0` to `VF`, in upper case.
+fn register(text: &str) -> Option<u8> {
+    match text.as_bytes() {
+        [b'V', digit] => (*digit as char).to_digit(16).map(|x| x as u8),
+        _ => None,
+    }
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+        lower.parse().ok()
+    } else {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::disassembler::disassemble;
+
+    fn assemble(source: &str) -> Result<Vec<u8>, AssemblerError> {
+        Assembler::new().assemble("test.asm", source).map(|cartridge| cartridge.rom)
+    }
+
+    #[test]
+    fn test_assemble() {
+        let source = "\
+            ROWS equ 5          ; sprite height
+            start:
+                LD I, sprite    ; forward reference
+                LD V0, 0x20
+                ADD V0, -1
+                DRW V0, V1, ROWS
+            loop: JP loop
+                SHR V3
+            sprite:
+                db 0xF0, 0x90, 0b11110000
+                dw 0x1234, start + 2
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![
+            0xA2, 0x0C, 0x60, 0x20, 0x70, 0xFF, 0xD0, 0x15, 0x12, 0x08, 0x83, 0x36,
+            0xF0, 0x90, 0xF0, 0x12, 0x34, 0x02, 0x02,
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_schip() {
+        let source = "HIGH\nSCD 3\nSCR\nLD HF, V2\nLD R, V7\nLD V7, R\nDRW V0, V1, 0\nEXIT";
+        assert_eq!(assemble(source).unwrap(), vec![
+            0x00, 0xFF, 0x00, 0xC3, 0x00, 0xFB, 0xF2, 0x30, 0xF7, 0x75, 0xF7, 0x85, 0xD0, 0x10, 0x00, 0xFD,
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_xochip() {
+        let source = "LD I, LONG data\nLD [I], V2-V5\nLD V5-V2, [I]\nPLANE 3\nSCU 2\nAUDIO\nLD PITCH, V1\ndata: db 1";
+        assert_eq!(assemble(source).unwrap(), vec![
+            0xF0, 0x00, 0x02, 0x10, 0x52, 0x52, 0x55, 0x23, 0xF3, 0x01, 0x00, 0xD2, 0xF0, 0x02, 0xF1, 0x3A, 0x01,
+        ]);
+        assert_eq!(assemble("PLANE 4").unwrap_err().message, "'4' is 4, which is outside of 0..3");
+    }
+
+    #[test]
+    fn test_origin() {
+        let cartridge = Assembler::new().with_origin(0x600).assemble("test.asm", "here: JP here").unwrap();
+        assert_eq!(cartridge.start_address, 0x600);
+        assert_eq!(cartridge.rom, vec![0x16, 0x00]);
+    }
+
+    #[test]
+    fn test_errors_point_at_the_line() {
+        let error = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(error("CLS\n\nFOO V1"), "test.asm:3: unknown instruction 'FOO'");
+        assert_eq!(error("LD V1, 0x100"), "test.asm:1: '0x100' is 256, which is outside of -128..255");
+        assert_eq!(error("JP nowhere"), "test.asm:1: unknown symbol 'nowhere'");
+        assert_eq!(error("a: CLS\na: RET"), "test.asm:2: 'a' is already defined at test.asm:1");
+        assert_eq!(error("LD DT, 5"), "test.asm:1: invalid operands for LD: 'DT, 5'");
+        assert_eq!(error("CLS\nX equ Y\nY equ X"), "test.asm:2: 'X' is defined in terms of itself");
+    }
+
+    #[test]
+    fn test_include() {
+        let directory = std::env::temp_dir().join(format!("chip8rs-asm-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("main.asm"), "CALL draw\ninclude \"draw.asm\"\n").unwrap();
+        std::fs::write(directory.join("draw.asm"), "draw:\n  RET\n  BAD\n").unwrap();
+        let error = Assembler::new().assemble_file(directory.join("main.asm").to_str().unwrap()).map(|cartridge| cartridge.rom).unwrap_err();
+        assert_eq!((error.file.ends_with("draw.asm"), error.line), (true, 3));
+
+        std::fs::write(directory.join("draw.asm"), "draw:\n  RET\n").unwrap();
+        let cartridge = Assembler::new().assemble_file(directory.join("main.asm").to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(cartridge.rom, vec![0x22, 0x02, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn test_disassembly_assembles_to_the_same_rom() {
+        for rom in [&include_bytes!("../../data/PONG")[..], include_bytes!("../../data/BRIX"), include_bytes!("../../data/IBM_logo.ch8")] {
+            let listing = disassemble(rom, 0x200).to_string();
+            assert_eq!(assemble(&listing).unwrap(), rom);
+        }
+    }
+}
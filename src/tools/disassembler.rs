@@ -0,0 +1,238 @@
+//! Disassembler
+//! Turns a ROM back into a listing of mnemonics. Code and data are told apart by following every
+//! path the program can take from its start address: whatever is never reached is listed as `db`
+//! bytes. Jump and call targets get labels so the listing reads like source and can be assembled again.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::hardware::cpus::instruction::Instruction;
+
+// data bytes per `db` line
+const DATA_BYTES_PER_LINE: usize = 8;
+const MNEMONIC_WIDTH: usize = 24;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// None for data.
+    pub instruction: Option<Instruction>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Disassembly {
+    pub start_address: u16,
+    pub lines: Vec<Line>,
+    labels: BTreeMap<u16, String>,
+}
+
+impl Disassembly {
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    pub fn labels(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.labels.iter().map(|(address, label)| (*address, label.as_str()))
+    }
+
+    fn format_line(&self, line: &Line) -> String {
+        match line.instruction {
+            Some(instruction) => instruction.format_with(|address| match self.label(address) {
+                Some(label) => label.to_string(),
+                None => format!("0x{:03X}", address),
+            }),
+            None => {
+                let bytes: Vec<String> = line.bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+                format!("db {}", bytes.join(", "))
+            },
+        }
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size: usize = self.lines.iter().map(|line| line.bytes.len()).sum();
+        writeln!(f, "; {} bytes at 0x{:03X}", size, self.start_address)?;
+        for line in &self.lines {
+            if let Some(label) = self.label(line.address) {
+                writeln!(f, "{}:", label)?;
+            }
+            let bytes: Vec<String> = line.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            writeln!(f, "    {:width$} ; {:03X}: {}", self.format_line(line), line.address, bytes.join(" "), width = MNEMONIC_WIDTH)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn disassemble(rom: &[u8], start_address: u16) -> Disassembly {
+    let code = find_code(rom, start_address);
+    let lines = split_lines(rom, start_address, &code, &jump_targets(rom, start_address, &code));
+
+    // only label addresses that start a line, a jump into the middle of an instruction keeps its number
+    let mut labels = BTreeMap::new();
+    for line in &lines {
+        if let Some(instruction) = line.instruction {
+            if let Some(target) = instruction.target() {
+                let label = match instruction {
+                    Instruction::Call(_) => format!("sub_{:03X}", target),
+                    _ => format!("loc_{:03X}", target),
+                };
+                // a subroutine name is more telling than a jump label
+                let entry = labels.entry(target).or_insert_with(|| label.clone());
+                if label.starts_with("sub_") {
+                    *entry = label;
+                }
+            }
+        }
+    }
+    labels.retain(|address, _| lines.iter().any(|line| line.address == *address));
+
+    Disassembly {
+        start_address,
+        lines,
+        labels,
+    }
+}
+
+/// Marks the bytes where a reachable instruction starts.
+fn find_code(rom: &[u8], start_address: u16) -> Vec<bool> {
+    let mut code = vec![false; rom.len()];
+    let mut pending = vec![start_address as usize];
+
+    while let Some(address) = pending.pop() {
+        let offset = match address.checked_sub(start_address as usize) {
+            Some(offset) if offset + 1 < rom.len() => offset,
+            _ => continue, // outside of the ROM
+        };
+        if code[offset] {
+            continue;
+        }
+        let instruction = match decode_at(rom, offset) {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        code[offset] = true;
+
+        let next = address + instruction.size() as usize;
+        // a skip jumps over all 4 bytes of an F000 nnnn
+        let skipped = next + decode_at(rom, next - start_address as usize).map_or(2, |instruction| instruction.size() as usize);
+        match instruction {
+            Instruction::Jump(target) | Instruction::JumpV0(target) => pending.push(target as usize),
+            Instruction::Call(target) => {
+                pending.push(target as usize);
+                pending.push(next);
+            },
+            Instruction::Ret | Instruction::Exit => {},
+            Instruction::SkipEqualByte { .. }
+            | Instruction::SkipNotEqualByte { .. }
+            | Instruction::SkipEqual { .. }
+            | Instruction::SkipNotEqual { .. }
+            | Instruction::SkipKeyPressed { .. }
+            | Instruction::SkipKeyNotPressed { .. } => {
+                pending.push(next);
+                pending.push(skipped);
+            },
+            _ => pending.push(next),
+        }
+    }
+    code
+}
+
+/// The instruction at `offset`, with the word after it for F000 nnnn.
+fn decode_at(rom: &[u8], offset: usize) -> Option<Instruction> {
+    let word = |offset: usize| Some((*rom.get(offset)? as u16) << 8 | *rom.get(offset + 1)? as u16);
+    let op_code = word(offset)?;
+    match Instruction::is_long(op_code) {
+        true => Instruction::decode_long(op_code, word(offset + 2)?),
+        false => Instruction::decode(op_code),
+    }
+}
+
+fn jump_targets(rom: &[u8], start_address: u16, code: &[bool]) -> Vec<u16> {
+    code.iter()
+        .enumerate()
+        .filter(|(_, is_code)| **is_code)
+        .filter_map(|(offset, _)| decode_at(rom, offset))
+        .filter_map(|instruction| instruction.target())
+        .filter(|target| (*target as usize) >= start_address as usize)
+        .collect()
+}
+
+fn split_lines(rom: &[u8], start_address: u16, code: &[bool], targets: &[u16]) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = start_address + offset as u16;
+        if code[offset] {
+            let instruction = decode_at(rom, offset);
+            let size = instruction.map_or(2, |instruction| instruction.size() as usize);
+            lines.push(Line {
+                address,
+                bytes: rom[offset..offset + size].to_vec(),
+                instruction,
+            });
+            offset += size;
+            continue;
+        }
+        // extend the previous data line unless it is full or a label has to go in between
+        match lines.last_mut() {
+            Some(line) if line.instruction.is_none()
+                && line.bytes.len() < DATA_BYTES_PER_LINE
+                && !targets.contains(&address) => line.bytes.push(rom[offset]),
+            _ => lines.push(Line {
+                address,
+                bytes: vec![rom[offset]],
+                instruction: None,
+            }),
+        }
+        offset += 1;
+    }
+    lines
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_follows_control_flow() {
+        // 200: CALL 0x204, 202: JP 0x202, 204: LD I, 0x208, 206: RET, 208: sprite data
+        let disassembly = disassemble(&[0x22, 0x04, 0x12, 0x02, 0xA2, 0x08, 0x00, 0xEE, 0xF0, 0x90], 0x200);
+        assert_eq!(disassembly.lines.len(), 5);
+        assert_eq!(disassembly.lines[0].instruction, Some(Instruction::Call(0x204)));
+        assert_eq!(disassembly.lines[2].instruction, Some(Instruction::LoadI(0x208)));
+        assert_eq!(disassembly.lines[4].instruction, None);
+        assert_eq!(disassembly.lines[4].bytes, vec![0xF0, 0x90]);
+        assert_eq!(disassembly.label(0x204), Some("sub_204"));
+        assert_eq!(disassembly.label(0x202), Some("loc_202"));
+    }
+
+    #[test]
+    fn test_listing() {
+        let disassembly = disassemble(&[0x22, 0x04, 0x12, 0x02, 0x63, 0x1F, 0x00, 0xEE, 0xAB], 0x200);
+        let listing = disassembly.to_string();
+        assert!(listing.contains("    CALL sub_204"));
+        assert!(listing.contains("loc_202:\n    JP loc_202"));
+        assert!(listing.contains("sub_204:\n    LD V3, 0x1F"));
+        assert!(listing.contains("    db 0xAB"));
+        assert!(listing.contains("; 208: AB"));
+    }
+
+    #[test]
+    fn test_disassemble_long_load() {
+        // 200: SE V0, 0, 202: LD I, LONG 0x1234, 206: JP 0x206
+        let disassembly = disassemble(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x12, 0x06], 0x200);
+        assert_eq!(disassembly.lines.len(), 3);
+        assert_eq!(disassembly.lines[1].instruction, Some(Instruction::LoadILong(0x1234)));
+        assert_eq!(disassembly.lines[1].bytes, vec![0xF0, 0x00, 0x12, 0x34]);
+        assert!(disassembly.to_string().contains("LD I, LONG 0x1234"));
+    }
+
+    #[test]
+    fn test_disassemble_unknown_opcode_is_data() {
+        let disassembly = disassemble(&[0xFF, 0xFF], 0x200);
+        assert_eq!(disassembly.lines, vec![Line { address: 0x200, bytes: vec![0xFF, 0xFF], instruction: None }]);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod assembler;
+pub mod disassembler;
+pub mod octo;
@@ -0,0 +1,875 @@
+//! Octo
+//! Compiles Octo source (http://johnearnest.github.io/Octo/docs/Manual.html), the assembly language most
+//! modern CHIP-8 programs are written in. Covers the CHIP-8 part of the language: `: label`, `:alias`,
+//! `:const`, `:calc`, `:macro`, `:byte`, `:call`, `loop` / `while` / `again` and
+//! `if ... then` / `if ... begin ... else ... end`, including the `<`, `>`, `<=` and `>=` comparisons,
+//! which go through VF, and the SUPER-CHIP statements: `hires`, `lores`, `scroll-down`, `scroll-left`,
+//! `scroll-right`, `exit`, `i := bighex`, `saveflags` and `loadflags`, and the XO-CHIP ones: `i := long`,
+//! `save vx - vy`, `load vx - vy`, `plane`, `scroll-up`, `audio` and `pitch := vx`. Execution starts at `main`, a jump to it is put
+//! in front when it is not the first label.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::hardware::cartridge::{Cartridge, DEFAULT_START_ADDRESS};
+use crate::hardware::cpus::instruction::Instruction;
+use crate::hardware::memory::XO_MEMORY_SIZE;
+use crate::tools::assembler::AssemblerError;
+
+// nested this deep a macro is taken to expand into itself forever
+const MAX_MACRO_DEPTH: usize = 256;
+const VF: u8 = 0xF;
+const KEYWORDS: [&str; 44] = [
+    "clear", "return", "jump", "jump0", "native", "bcd", "save", "load", "sprite", "i", "delay", "buzzer",
+    "if", "then", "begin", "else", "end", "loop", "again", "while", "key", "-key", "random", "hex",
+    "HERE", "PI", "E", ":=", "+=", "-=", "hires", "lores", "scroll-down", "scroll-left", "scroll-right",
+    "exit", "bighex", "saveflags", "loadflags", "long", "plane", "scroll-up",
+    "audio", "pitch",
+];
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    /// How many macro expansions the token came out of.
+    depth: usize,
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// An address operand that names a label defined further down.
+struct Fixup {
+    offset: usize,
+    label: String,
+    line: usize,
+}
+
+/// An open `loop`, with the jumps out of it that `while` leaves for `again` to fill in.
+struct Loop {
+    start: u16,
+    line: usize,
+    exits: Vec<usize>,
+}
+
+/// An open `if ... begin`, `jump` skips the block and is filled in by `else` or `end`.
+struct Branch {
+    jump: usize,
+    line: usize,
+    has_else: bool,
+}
+
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+struct Condition {
+    x: u8,
+    comparison: String,
+    operand: Option<Operand>,
+}
+
+pub struct OctoCompiler {
+    origin: u16,
+}
+
+impl OctoCompiler {
+    pub fn new() -> Self {
+        OctoCompiler { origin: DEFAULT_START_ADDRESS }
+    }
+
+    /// Compile for a program loaded somewhere else than 0x200.
+    pub fn with_origin(mut self, origin: u16) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn compile_file(&self, path: &str) -> Result<Cartridge, AssemblerError> {
+        let source = std::fs::read_to_string(path).map_err(|e| AssemblerError {
+            file: path.to_string(),
+            line: 0,
+            message: format!("unable to read source: {}", e),
+        })?;
+        self.compile(path, &source)
+    }
+
+    /// `name` is used in error messages.
+    pub fn compile(&self, name: &str, source: &str) -> Result<Cartridge, AssemblerError> {
+        let program = Program {
+            file: name,
+            origin: self.origin,
+            tokens: tokenize(source),
+            line: 1,
+            rom: Vec::new(),
+            jump_to_main: true,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            loops: Vec::new(),
+            branches: Vec::new(),
+            depth: 0,
+        };
+        let rom = program.compile()?;
+        Ok(Cartridge::new(rom).with_start_address(self.origin))
+    }
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    source
+        .lines()
+        .enumerate()
+        .flat_map(|(index, line)| {
+            let code = line.split('#').next().unwrap_or("");
+            code.split_whitespace().map(move |text| Token { text: text.to_string(), line: index + 1, depth: 0 })
+        })
+        .collect()
+}
+
+struct Program<'a> {
+    file: &'a str,
+    origin: u16,
+    tokens: VecDeque<Token>,
+    /// Line of the last token taken, for errors.
+    line: usize,
+    rom: Vec<u8>,
+    jump_to_main: bool,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    loops: Vec<Loop>,
+    branches: Vec<Branch>,
+    /// Macro depth of the statement being compiled.
+    depth: usize,
+}
+
+impl Program<'_> {
+    fn compile(mut self) -> Result<Vec<u8>, AssemblerError> {
+        // room for the jump to main, taken back when main turns out to be the first label
+        self.rom.extend([0, 0]);
+        while let Some(token) = self.tokens.pop_front() {
+            self.line = token.line;
+            self.depth = token.depth;
+            self.statement(&token.text)?;
+            if self.origin as usize + self.rom.len() > XO_MEMORY_SIZE {
+                return Err(self.error("program does not fit in memory"));
+            }
+        }
+
+        if let Some(open) = self.loops.last() {
+            return Err(self.error_at(open.line, "loop without again"));
+        }
+        if let Some(open) = self.branches.last() {
+            return Err(self.error_at(open.line, "begin without end"));
+        }
+        if self.jump_to_main {
+            let main = *self.labels.get("main").ok_or_else(|| self.error_at(1, "the program has no main label"))?;
+            self.patch(0, Instruction::Jump(main));
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            let address = *self.labels.get(&fixup.label)
+                .ok_or_else(|| self.error_at(fixup.line, format!("undefined label '{}'", fixup.label)))?;
+            let op_code = (self.rom[fixup.offset] as u16) << 8 | self.rom[fixup.offset + 1] as u16;
+            if Instruction::is_long(op_code) {
+                self.rom[fixup.offset + 2..fixup.offset + 4].copy_from_slice(&address.to_be_bytes());
+                continue;
+            }
+            if address > 0xFFF {
+                let message = format!("label '{}' is at {:#06X}, out of reach of a 12 bit address", fixup.label, address);
+                return Err(self.error_at(fixup.line, message));
+            }
+            self.rom[fixup.offset..fixup.offset + 2].copy_from_slice(&(op_code & 0xF000 | address).to_be_bytes());
+        }
+        Ok(self.rom)
+    }
+
+    fn statement(&mut self, text: &str) -> Result<(), AssemblerError> {
+        if let Some(x) = self.register_of(text) {
+            return self.register_statement(x);
+        }
+        match text {
+            ":" => {
+                let name = self.name()?;
+                if name == "main" && self.jump_to_main && self.labels.is_empty() && self.rom.len() == 2 {
+                    self.rom.clear();
+                    self.jump_to_main = false;
+                }
+                let address = self.address()?;
+                if self.labels.insert(name.clone(), address).is_some() {
+                    return Err(self.error(format!("label '{}' is already defined", name)));
+                }
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.next()?;
+                let value = self.value(&value)?;
+                self.constants.insert(name, value as f64);
+            },
+            ":calc" => {
+                let name = self.name()?;
+                let tokens = self.block()?;
+                let mut position = 0;
+                let value = self.calc(&tokens, &mut position)?;
+                if position < tokens.len() {
+                    return Err(self.error(format!("unexpected '{}' in :calc", tokens[position].text)));
+                }
+                self.constants.insert(name, value);
+            },
+            ":macro" => {
+                let name = self.name()?;
+                let mut parameters = Vec::new();
+                while self.tokens.front().map(|token| token.text.as_str()) != Some("{") {
+                    parameters.push(self.name()?);
+                }
+                let body = self.block()?;
+                self.macros.insert(name, Macro { parameters, body });
+            },
+            ":byte" => {
+                let byte = self.byte()?;
+                self.rom.push(byte);
+            },
+            ":call" => self.target(Instruction::Call)?,
+            "clear" => self.emit(Instruction::Cls),
+            "return" | ";" => self.emit(Instruction::Ret),
+            "jump" => self.target(Instruction::Jump)?,
+            "jump0" => self.target(Instruction::JumpV0)?,
+            "native" => self.target(Instruction::Sys)?,
+            "hires" => self.emit(Instruction::HighRes),
+            "lores" => self.emit(Instruction::LowRes),
+            "scroll-down" => {
+                let n = self.next()?;
+                let n = self.value(&n)?;
+                if !(0..=0xF).contains(&n) {
+                    return Err(self.error(format!("scroll-down {} is outside of 0..15", n)));
+                }
+                self.emit(Instruction::ScrollDown(n as u8));
+            },
+            "scroll-up" => {
+                let n = self.next()?;
+                let n = self.value(&n)?;
+                if !(0..=0xF).contains(&n) {
+                    return Err(self.error(format!("scroll-up {} is outside of 0..15", n)));
+                }
+                self.emit(Instruction::ScrollUp(n as u8));
+            },
+            "scroll-left" => self.emit(Instruction::ScrollLeft),
+            "scroll-right" => self.emit(Instruction::ScrollRight),
+            "exit" => self.emit(Instruction::Exit),
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::StoreFlags { x });
+            },
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LoadFlags { x });
+            },
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Instruction::StoreBcd { x });
+            },
+            "save" => {
+                let x = self.register()?;
+                match self.range_end()? {
+                    Some(y) => self.emit(Instruction::StoreRange { x, y }),
+                    None => self.emit(Instruction::StoreRegisters { x }),
+                }
+            },
+            "load" => {
+                let x = self.register()?;
+                match self.range_end()? {
+                    Some(y) => self.emit(Instruction::LoadRange { x, y }),
+                    None => self.emit(Instruction::LoadRegisters { x }),
+                }
+            },
+            "plane" => {
+                let n = self.next()?;
+                let n = self.value(&n)?;
+                if !(0..=3).contains(&n) {
+                    return Err(self.error(format!("plane {} is outside of 0..3", n)));
+                }
+                self.emit(Instruction::SelectPlanes(n as u8));
+            },
+            "sprite" => {
+                let (x, y) = (self.register()?, self.register()?);
+                let n = self.next()?;
+                let n = self.value(&n)?;
+                if !(0..=0xF).contains(&n) {
+                    return Err(self.error(format!("sprite height {} is outside of 0..15", n)));
+                }
+                self.emit(Instruction::Draw { x, y, n: n as u8 });
+            },
+            "i" => self.i_statement()?,
+            "audio" => self.emit(Instruction::LoadPattern),
+            "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(Instruction::SetPitch { x });
+            },
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(if text == "delay" { Instruction::SetDelay { x } } else { Instruction::SetSound { x } });
+            },
+            "if" => {
+                let condition = self.condition()?;
+                match self.next()?.as_str() {
+                    "then" => self.skip_unless(&condition, false),
+                    "begin" => {
+                        self.skip_unless(&condition, true);
+                        let jump = self.rom.len();
+                        self.emit(Instruction::Jump(0));
+                        self.branches.push(Branch { jump, line: self.line, has_else: false });
+                    },
+                    other => return Err(self.error(format!("expected then or begin, got '{}'", other))),
+                }
+            },
+            "else" => {
+                let jump = self.rom.len();
+                let branch = match self.branches.last_mut() {
+                    Some(branch) if !branch.has_else => branch,
+                    _ => return Err(self.error("else without begin")),
+                };
+                let skipped = std::mem::replace(&mut branch.jump, jump);
+                branch.has_else = true;
+                self.emit(Instruction::Jump(0));
+                // the block that is skipped to starts after the jump over it
+                let address = self.address()?;
+                self.patch(skipped, Instruction::Jump(address));
+            },
+            "end" => {
+                let branch = self.branches.pop().ok_or_else(|| self.error("end without begin"))?;
+                let address = self.address()?;
+                self.patch(branch.jump, Instruction::Jump(address));
+            },
+            "loop" => {
+                let start = self.address()?;
+                self.loops.push(Loop { start, line: self.line, exits: Vec::new() });
+            },
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(self.error("while outside of a loop"));
+                }
+                let condition = self.condition()?;
+                self.skip_unless(&condition, true);
+                let exit = self.rom.len();
+                self.emit(Instruction::Jump(0));
+                if let Some(open) = self.loops.last_mut() {
+                    open.exits.push(exit);
+                }
+            },
+            "again" => {
+                let open = self.loops.pop().ok_or_else(|| self.error("again without loop"))?;
+                self.emit(Instruction::Jump(open.start));
+                let address = self.address()?;
+                for exit in open.exits {
+                    self.patch(exit, Instruction::Jump(address));
+                }
+            },
+            _ if self.macros.contains_key(text) => self.expand(text)?,
+            _ if parse_number(text).is_some() => {
+                let byte = self.byte_value(text)?;
+                self.rom.push(byte);
+            },
+            _ if is_name(text) => {
+                // calling a subroutine is just naming it
+                let text = text.to_string();
+                self.target_of(&text, Instruction::Call)?;
+            },
+            _ => return Err(self.error(format!("unexpected '{}'", text))),
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), AssemblerError> {
+        let operator = self.next()?;
+        let operand = self.next()?;
+        let instruction = match (operator.as_str(), operand.as_str()) {
+            (":=", "random") => Instruction::Random { x, byte: self.byte()? },
+            (":=", "delay") => Instruction::LoadDelay { x },
+            (":=", "key") => Instruction::WaitKey { x },
+            (":=", _) => match self.register_of(&operand) {
+                Some(y) => Instruction::Load { x, y },
+                None => Instruction::LoadByte { x, byte: self.byte_value(&operand)? },
+            },
+            ("+=", _) => match self.register_of(&operand) {
+                Some(y) => Instruction::Add { x, y },
+                None => Instruction::AddByte { x, byte: self.byte_value(&operand)? },
+            },
+            // there is no subtract immediate, add the negative instead
+            ("-=", _) => match self.register_of(&operand) {
+                Some(y) => Instruction::Sub { x, y },
+                None => Instruction::AddByte { x, byte: self.byte_value(&operand)?.wrapping_neg() },
+            },
+            ("=-", _) => Instruction::SubN { x, y: self.register_value(&operand)? },
+            ("|=", _) => Instruction::Or { x, y: self.register_value(&operand)? },
+            ("&=", _) => Instruction::And { x, y: self.register_value(&operand)? },
+            ("^=", _) => Instruction::Xor { x, y: self.register_value(&operand)? },
+            (">>=", _) => Instruction::ShiftRight { x, y: self.register_value(&operand)? },
+            ("<<=", _) => Instruction::ShiftLeft { x, y: self.register_value(&operand)? },
+            _ => return Err(self.error(format!("unknown register operator '{}'", operator))),
+        };
+        self.emit(instruction);
+        Ok(())
+    }
+
+    fn i_statement(&mut self) -> Result<(), AssemblerError> {
+        let operator = self.next()?;
+        let operand = self.next()?;
+        match (operator.as_str(), operand.as_str()) {
+            (":=", "hex") => {
+                let x = self.register()?;
+                self.emit(Instruction::LoadFont { x });
+            },
+            (":=", "bighex") => {
+                let x = self.register()?;
+                self.emit(Instruction::LoadBigFont { x });
+            },
+            (":=", "long") => self.target(Instruction::LoadILong)?,
+            (":=", _) => self.target_of(&operand, Instruction::LoadI)?,
+            ("+=", _) => {
+                let x = self.register_value(&operand)?;
+                self.emit(Instruction::AddI { x });
+            },
+            _ => return Err(self.error(format!("unknown i operator '{}'", operator))),
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, AssemblerError> {
+        let x = self.register()?;
+        let comparison = self.next()?;
+        let operand = match comparison.as_str() {
+            "key" | "-key" => None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                let operand = self.next()?;
+                Some(match self.register_of(&operand) {
+                    Some(y) => Operand::Register(y),
+                    None => Operand::Byte(self.byte_value(&operand)?),
+                })
+            },
+            _ => return Err(self.error(format!("unknown comparison '{}'", comparison))),
+        };
+        Ok(Condition { x, comparison, operand })
+    }
+
+    /// Emits the instructions that skip the next one unless `condition` holds, or when `negated`
+    /// unless it does not. The ordering comparisons subtract into VF and test the borrow flag.
+    fn skip_unless(&mut self, condition: &Condition, negated: bool) {
+        let comparison = match (condition.comparison.as_str(), negated) {
+            (comparison, false) => comparison,
+            ("==", true) => "!=",
+            ("!=", true) => "==",
+            ("key", true) => "-key",
+            ("-key", true) => "key",
+            ("<", true) => ">=",
+            (">=", true) => "<",
+            (">", true) => "<=",
+            (_, true) => ">",
+        };
+        let x = condition.x;
+        match (comparison, &condition.operand) {
+            ("==", Some(Operand::Register(y))) => self.emit(Instruction::SkipNotEqual { x, y: *y }),
+            ("==", Some(Operand::Byte(byte))) => self.emit(Instruction::SkipNotEqualByte { x, byte: *byte }),
+            ("!=", Some(Operand::Register(y))) => self.emit(Instruction::SkipEqual { x, y: *y }),
+            ("!=", Some(Operand::Byte(byte))) => self.emit(Instruction::SkipEqualByte { x, byte: *byte }),
+            ("key", _) => self.emit(Instruction::SkipKeyNotPressed { x }),
+            ("-key", _) => self.emit(Instruction::SkipKeyPressed { x }),
+            (comparison, operand) => {
+                // VF ends up 1 when the left side of the subtraction is at least the right side
+                let vx_first = matches!(comparison, "<" | ">=");
+                match operand {
+                    Some(Operand::Register(y)) => {
+                        let (left, right) = if vx_first { (x, *y) } else { (*y, x) };
+                        self.emit(Instruction::Load { x: VF, y: left });
+                        self.emit(Instruction::Sub { x: VF, y: right });
+                    },
+                    Some(Operand::Byte(byte)) => {
+                        self.emit(Instruction::LoadByte { x: VF, byte: *byte });
+                        if vx_first {
+                            self.emit(Instruction::SubN { x: VF, y: x });
+                        } else {
+                            self.emit(Instruction::Sub { x: VF, y: x });
+                        }
+                    },
+                    None => {},
+                }
+                let byte = if matches!(comparison, ">=" | "<=") { 1 } else { 0 };
+                self.emit(Instruction::SkipNotEqualByte { x: VF, byte });
+            },
+        }
+    }
+
+    fn expand(&mut self, name: &str) -> Result<(), AssemblerError> {
+        let depth = self.depth + 1;
+        if depth > MAX_MACRO_DEPTH {
+            return Err(self.error(format!("macro '{}' keeps expanding", name)));
+        }
+        let parameters = self.macros[name].parameters.clone();
+        let mut arguments = HashMap::new();
+        for parameter in parameters {
+            let token = self.tokens.pop_front()
+                .ok_or_else(|| self.error(format!("macro '{}' is missing the argument '{}'", name, parameter)))?;
+            arguments.insert(parameter, token);
+        }
+        for token in self.macros[name].body.iter().rev() {
+            let token = arguments.get(&token.text).unwrap_or(token);
+            self.tokens.push_front(Token { depth, ..token.clone() });
+        }
+        Ok(())
+    }
+
+    /// `:calc` expression. Octo has no operator precedence, binary operators group to the right.
+    fn calc(&self, tokens: &[Token], position: &mut usize) -> Result<f64, AssemblerError> {
+        let left = self.calc_term(tokens, position)?;
+        let operator = match tokens.get(*position) {
+            Some(token) if token.text != ")" => token.text.as_str(),
+            _ => return Ok(left),
+        };
+        *position += 1;
+        let right = self.calc(tokens, position)?;
+        let (a, b) = (left as i64, right as i64);
+        let value = match operator {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" | "%" if right == 0.0 => return Err(self.error("division by zero in :calc")),
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+            ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as u8 as f64,
+            ">" => (left > right) as u8 as f64,
+            "<=" => (left <= right) as u8 as f64,
+            ">=" => (left >= right) as u8 as f64,
+            "==" => (left == right) as u8 as f64,
+            "!=" => (left != right) as u8 as f64,
+            _ => return Err(self.error(format!("unknown operator '{}' in :calc", operator))),
+        };
+        Ok(value)
+    }
+
+    fn calc_term(&self, tokens: &[Token], position: &mut usize) -> Result<f64, AssemblerError> {
+        let token = tokens.get(*position).ok_or_else(|| self.error("missing value in :calc"))?;
+        *position += 1;
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|value| -value),
+            "~" => Some(|value| !(value as i64) as f64),
+            "!" => Some(|value| (value == 0.0) as u8 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.calc_term(tokens, position)?));
+        }
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc(tokens, position)?;
+                match tokens.get(*position) {
+                    Some(token) if token.text == ")" => *position += 1,
+                    _ => return Err(self.error("missing ) in :calc")),
+                }
+                Ok(value)
+            },
+            "@" => {
+                let address = self.calc_term(tokens, position)? as i64;
+                let offset = address - self.origin as i64;
+                Ok(self.rom.get(offset.max(0) as usize).filter(|_| offset >= 0).copied().unwrap_or(0) as f64)
+            },
+            "HERE" => Ok(self.address()? as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            text => match (parse_number(text), text.parse::<f64>()) {
+                (Some(number), _) => Ok(number as f64),
+                (None, Ok(number)) => Ok(number),
+                _ => Ok(self.value(text)? as f64),
+            },
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.rom.extend(instruction.to_bytes());
+    }
+
+    fn patch(&mut self, offset: usize, instruction: Instruction) {
+        self.rom[offset..offset + 2].copy_from_slice(&instruction.encode().to_be_bytes());
+    }
+
+    fn target(&mut self, instruction: fn(u16) -> Instruction) -> Result<(), AssemblerError> {
+        let text = self.next()?;
+        self.target_of(&text, instruction)
+    }
+
+    /// Emits an instruction with an address operand, labels may be defined later.
+    fn target_of(&mut self, text: &str, instruction: fn(u16) -> Instruction) -> Result<(), AssemblerError> {
+        if self.labels.contains_key(text) || self.constants.contains_key(text) || !is_name(text) {
+            let address = self.value(text)?;
+            let limit = if instruction(0).size() == 4 { 0xFFFF } else { 0xFFF };
+            if !(0..=limit).contains(&address) {
+                return Err(self.error(format!("address {} is outside of 0..{:#X}", address, limit)));
+            }
+            self.emit(instruction(address as u16));
+        } else {
+            self.fixups.push(Fixup { offset: self.rom.len(), label: text.to_string(), line: self.line });
+            self.emit(instruction(0));
+        }
+        Ok(())
+    }
+
+    /// A number, constant or label that is already defined.
+    fn value(&self, text: &str) -> Result<i64, AssemblerError> {
+        if let Some(number) = parse_number(text) {
+            return Ok(number);
+        }
+        if let Some(constant) = self.constants.get(text) {
+            return Ok(constant.trunc() as i64);
+        }
+        if let Some(address) = self.labels.get(text) {
+            return Ok(*address as i64);
+        }
+        Err(self.error(format!("'{}' is not a number or a known name", text)))
+    }
+
+    fn byte(&mut self) -> Result<u8, AssemblerError> {
+        let text = self.next()?;
+        self.byte_value(&text)
+    }
+
+    /// Negative numbers are allowed for their two's complement.
+    fn byte_value(&self, text: &str) -> Result<u8, AssemblerError> {
+        let value = self.value(text)?;
+        if !(-0x80..=0xFF).contains(&value) {
+            return Err(self.error(format!("'{}' is {}, which does not fit in a byte", text, value)));
+        }
+        Ok(value as u8)
+    }
+
+    /// The `- vy` that makes a `save` or `load` work on a range of registers.
+    fn range_end(&mut self) -> Result<Option<u8>, AssemblerError> {
+        if self.tokens.front().map(|token| token.text.as_str()) != Some("-") {
+            return Ok(None);
+        }
+        self.next()?;
+        self.register().map(Some)
+    }
+
+    fn register(&mut self) -> Result<u8, AssemblerError> {
+        let text = self.next()?;
+        self.register_value(&text)
+    }
+
+    fn register_value(&self, text: &str) -> Result<u8, AssemblerError> {
+        self.register_of(text).ok_or_else(|| self.error(format!("'{}' is not a register", text)))
+    }
+
+    /// `v0` to `vf` or an alias.
+    fn register_of(&self, text: &str) -> Option<u8> {
+        if let Some(register) = self.aliases.get(text) {
+            return Some(*register);
+        }
+        match text.as_bytes() {
+            [b'v' | b'V', digit] => (*digit as char).to_digit(16).map(|x| x as u8),
+            _ => None,
+        }
+    }
+
+    /// The name for a new label, constant, alias or macro.
+    fn name(&mut self) -> Result<String, AssemblerError> {
+        let text = self.next()?;
+        if !is_name(&text) || KEYWORDS.contains(&text.as_str()) || self.register_of(&text).is_some() {
+            return Err(self.error(format!("'{}' can not be used as a name", text)));
+        }
+        Ok(text)
+    }
+
+    /// The tokens between `{` and the matching `}`.
+    fn block(&mut self) -> Result<Vec<Token>, AssemblerError> {
+        self.expect("{")?;
+        let start = self.line;
+        let mut depth = 1;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.tokens.pop_front().ok_or_else(|| self.error_at(start, "{ without }"))?;
+            self.line = token.line;
+            depth += match token.text.as_str() {
+                "{" => 1,
+                "}" => -1,
+                _ => 0,
+            };
+            if depth == 0 {
+                return Ok(tokens);
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), AssemblerError> {
+        let text = self.next()?;
+        if text != expected {
+            return Err(self.error(format!("expected '{}', got '{}'", expected, text)));
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<String, AssemblerError> {
+        let token = self.tokens.pop_front().ok_or_else(|| self.error("unexpected end of file"))?;
+        self.line = token.line;
+        Ok(token.text)
+    }
+
+    /// Where the next byte goes, an error once memory is full.
+    fn address(&self) -> Result<u16, AssemblerError> {
+        u16::try_from(self.origin as usize + self.rom.len()).map_err(|_| self.error("program does not fit in memory"))
+    }
+
+    fn error(&self, message: impl Into<String>) -> AssemblerError {
+        self.error_at(self.line, message)
+    }
+
+    fn error_at(&self, line: usize, message: impl Into<String>) -> AssemblerError {
+        AssemblerError {
+            file: self.file.to_string(),
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> Result<Vec<u8>, AssemblerError> {
+        OctoCompiler::new().compile("test.8o", source).map(|cartridge| cartridge.rom)
+    }
+
+    #[test]
+    fn test_compile() {
+        let source = "
+            :alias x v1
+            :const SPEED 3
+            :calc HALF { SPEED * 4 / 2 }
+            :macro bump register amount { register += amount }
+            : main
+                clear
+                x := HALF
+                bump x SPEED
+                i := dot          # forward reference
+                loop
+                    sprite x v2 1
+                    if x == 0x3F then x := 0
+                again
+            : dot
+                0x80
+        ";
+        assert_eq!(compile(source).unwrap(), vec![
+            0x00, 0xE0, 0x61, 0x06, 0x71, 0x03, 0xA2, 0x10,
+            0xD1, 0x21, 0x41, 0x3F, 0x61, 0x00, 0x12, 0x08,
+            0x80,
+        ]);
+    }
+
+    #[test]
+    fn test_jump_to_main() {
+        assert_eq!(compile(": sub return : main sub").unwrap(), vec![0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]);
+    }
+
+    #[test]
+    fn test_if_begin_else_end() {
+        let source = ": main if v0 key begin v1 := 1 else v1 := 2 end v2 += -1";
+        assert_eq!(compile(source).unwrap(), vec![
+            0xE0, 0x9E, 0x12, 0x08, 0x61, 0x01, 0x12, 0x0A, 0x61, 0x02, 0x72, 0xFF,
+        ]);
+    }
+
+    #[test]
+    fn test_ordering_comparisons() {
+        assert_eq!(compile(": main if v1 < v2 then return").unwrap(), vec![0x8F, 0x10, 0x8F, 0x25, 0x4F, 0x00, 0x00, 0xEE]);
+        assert_eq!(compile(": main if v1 > 5 then return").unwrap(), vec![0x6F, 0x05, 0x8F, 0x15, 0x4F, 0x00, 0x00, 0xEE]);
+        // while leaves the loop once the condition fails
+        assert_eq!(compile(": main loop while v0 != 3 again").unwrap(), vec![0x40, 0x03, 0x12, 0x06, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn test_schip_statements() {
+        let source = ": main hires scroll-down 4 scroll-left i := bighex v3 saveflags v7 loadflags v7 sprite v0 v1 0 exit";
+        assert_eq!(compile(source).unwrap(), vec![
+            0x00, 0xFF, 0x00, 0xC4, 0x00, 0xFC, 0xF3, 0x30, 0xF7, 0x75, 0xF7, 0x85, 0xD0, 0x10, 0x00, 0xFD,
+        ]);
+    }
+
+    #[test]
+    fn test_xochip_statements() {
+        let source = ": main i := long data save v1 - v3 load v2 plane 2 scroll-up 1 audio pitch := v4 : data 0x01";
+        assert_eq!(compile(source).unwrap(), vec![
+            0xF0, 0x00, 0x02, 0x10, 0x51, 0x32, 0xF2, 0x65, 0xF2, 0x01, 0x00, 0xD1, 0xF0, 0x02, 0xF4, 0x3A, 0x01,
+        ]);
+    }
+
+    #[test]
+    fn test_macro_used_many_times() {
+        let source = format!(":macro nop {{ v0 += 0 }}\n: main {}", "nop ".repeat(20_000));
+        assert_eq!(compile(&source).unwrap().len(), 40_000);
+    }
+
+    #[test]
+    fn test_errors_point_at_the_line() {
+        let error = |source| compile(source).unwrap_err().to_string();
+        assert_eq!(error(": main\n  v0 := 256"), "test.8o:2: '256' is 256, which does not fit in a byte");
+        assert_eq!(error(": main\n\n  jump nowhere"), "test.8o:3: undefined label 'nowhere'");
+        assert_eq!(error(": main\nloop\n  clear"), "test.8o:2: loop without again");
+        assert_eq!(error("clear"), "test.8o:1: the program has no main label");
+        assert_eq!(error(": main\n  v0 <> v1"), "test.8o:2: unknown register operator '<>'");
+        assert_eq!(error(":macro forever { forever }\n: main forever"), "test.8o:1: macro 'forever' keeps expanding");
+        let full = format!(": main\n{}\n: tail", "0 ".repeat(0xFE00));
+        assert_eq!(error(&full), "test.8o:3: program does not fit in memory");
+    }
+}